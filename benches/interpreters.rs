@@ -0,0 +1,55 @@
+//! Compares the pre-decoded interpreter with the byte-level one on a tight
+//! arithmetic loop. Run with `cargo bench`.
+use std::time::{Duration, Instant};
+
+use lil_vm::assembler::parser::Parser;
+use lil_vm::vm::{Vm, VmConfig};
+
+const ROUNDS: usize = 200;
+
+/// Counts `$0` down from 65535 to zero, adding `$0` into `$4` each time
+/// around the loop.
+const SUM_LOOP: &str = "
+    load $0 #65535
+    load $1 #1
+    load $3 @loop
+    load $4 #0
+    loop: add $4 $0 $4
+    sub $0 $1 $0
+    eq $0 $2
+    jmpne $3
+    halt
+";
+
+fn time(code: &[u8], run: fn(&mut Vm)) -> Duration {
+    let start = Instant::now();
+    for _ in 0..ROUNDS {
+        let mut vm = Vm::with_config(VmConfig {
+            trace_len: 0,
+            ..Default::default()
+        });
+        vm.set_code(code.to_vec());
+        run(&mut vm);
+        assert!(vm.trap().is_none());
+    }
+    start.elapsed()
+}
+
+fn main() {
+    let program = Parser::new(SUM_LOOP).program().unwrap();
+    assert!(program.errors.is_empty());
+    let code = program.bytes();
+    let bytes = time(&code, Vm::run_bytes);
+    let decoded = time(&code, Vm::run);
+    let steps = (ROUNDS * (4 * u16::MAX as usize + 5)) as f64;
+    println!(
+        "byte-level:  {:?} ({:.2} ns/instr)",
+        bytes,
+        bytes.as_nanos() as f64 / steps
+    );
+    println!(
+        "pre-decoded: {:?} ({:.2} ns/instr)",
+        decoded,
+        decoded.as_nanos() as f64 / steps
+    );
+}
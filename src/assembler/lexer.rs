@@ -1,23 +1,32 @@
 use std::{
     iter::Peekable,
+    num::ParseIntError,
     str::{Chars, FromStr},
 };
 
 use crate::bytecode::OpCode;
 
-use crate::data::{Int, Reg};
+use crate::data::{FReg, Int, Reg};
 
 /// Lexical syntax
 ///
 /// ```txt
-/// Program := { Instruction }
+/// Program := { [ LabelDecl ] Instruction }
 ///
 /// Instruction := OpCode Register Integer "\n"
 ///
 /// OpCode := [Letter] " "
 ///
+/// LabelDecl := Ident ":"
+///
+/// LabelRef := "@" Ident
+///
+/// Ident := Letter { Letter | Number | "_" }
+///
 /// Register := "$" Number " "
 ///
+/// FloatRegister := "%f" Number " "
+///
 /// Int := "#" Number
 ///
 /// Number := "0" | ... | "9"
@@ -27,8 +36,12 @@ pub enum Lexeme {
     Newline,
     Op(OpCode),
     Reg(Reg),
+    FReg(FReg),
     Int(Int),
-    Label(&'static str),
+    /// a label declaration `name:`, as the span of `name`
+    Label(usize, usize),
+    /// a label used as an operand, `@name`, as the span of `name`
+    LabelRef(usize, usize),
     InvalidInt(usize, usize),
     InvalidReg(usize, usize),
     Unknown(usize, usize),
@@ -51,8 +64,10 @@ impl std::fmt::Display for Lexeme {
             Lexeme::Newline => write!(f, "\n"),
             Lexeme::Op(op) => write!(f, "{}", op),
             Lexeme::Reg(r) => write!(f, "{}", r),
+            Lexeme::FReg(r) => write!(f, "%f{}", r),
             Lexeme::Int(n) => write!(f, "{}", n),
-            Lexeme::Label(s) => write!(f, "{}", s),
+            Lexeme::Label(a, b) => write!(f, "<LABEL@{}:{}>", a, b),
+            Lexeme::LabelRef(a, b) => write!(f, "<LABEL_REF@{}:{}>", a, b),
             Lexeme::InvalidInt(a, b) => write!(f, "<INVALID_INT@{}:{}>", a, b),
             Lexeme::InvalidReg(a, b) => write!(f, "<INVALID_REG@{}:{}>", a, b),
             Lexeme::Unknown(a, b) => write!(f, "<UNKNOWN_TOK@{}:{}>", a, b),
@@ -96,7 +111,7 @@ impl<'t> Lexer<'t> {
         }
     }
 
-    pub fn source(&self) -> &str {
+    pub fn source(&self) -> &'t str {
         self.input
     }
 
@@ -206,10 +221,29 @@ impl<'t> Lexer<'t> {
                     },
                 }
             }
-            // integer
+            // float register
+            Some('%') => {
+                let start = self.byte;
+                self.next_char();
+                if self.peek_char() != Some(&'f') {
+                    return Token {
+                        lexeme: Lexeme::Unknown(start, self.byte),
+                    };
+                }
+                self.next_char();
+                match self.number::<u8, 10>() {
+                    Ok(byte) => Token {
+                        lexeme: Lexeme::FReg(FReg(byte)),
+                    },
+                    Err((_err, (_, end))) => Token {
+                        lexeme: Lexeme::InvalidReg(start, end),
+                    },
+                }
+            }
+            // integer, optionally negative
             Some('#') => {
                 self.next_char();
-                match self.number::<i32, 10>() {
+                match self.signed_number() {
                     Ok(int) => Token {
                         lexeme: Lexeme::Int(Int(int)),
                     },
@@ -218,6 +252,21 @@ impl<'t> Lexer<'t> {
                     },
                 }
             }
+            // label reference
+            Some('@') => {
+                let start = self.byte;
+                self.next_char();
+                let (name, end) = self.eat_while(is_ident_char);
+                if name == end {
+                    Token {
+                        lexeme: Lexeme::Unknown(start, end),
+                    }
+                } else {
+                    Token {
+                        lexeme: Lexeme::LabelRef(name, end),
+                    }
+                }
+            }
             // letter, beginning of identifier
             Some(c) if c.is_ascii_alphabetic() => self.ident(),
             Some(c) if c.is_digit(10) => match self.number::<i32, 10>() {
@@ -240,8 +289,26 @@ impl<'t> Lexer<'t> {
         }
     }
 
+    fn signed_number(&mut self) -> Result<i32, (ParseIntError, (usize, usize))> {
+        let start = self.byte;
+        if self.peek_char() == Some(&'-') {
+            self.next_char();
+        }
+        let (_, end) = self.eat_while(|c| c.is_ascii_digit());
+        match i32::from_str(&self.input[start..end]) {
+            Ok(n) => Ok(n),
+            Err(err) => Err((err, (start, end))),
+        }
+    }
+
     fn ident(&mut self) -> Token {
-        let (start, end) = self.eat_while(char::is_ascii_alphabetic);
+        let (start, end) = self.eat_while(is_ident_char);
+        if self.peek_char() == Some(&':') {
+            self.next_char();
+            return Token {
+                lexeme: Lexeme::Label(start, end),
+            };
+        }
         match OpCode::from_str(&self.input[start..end]) {
             Some(op) => Token {
                 lexeme: Lexeme::Op(op),
@@ -253,6 +320,10 @@ impl<'t> Lexer<'t> {
     }
 }
 
+fn is_ident_char(c: &char) -> bool {
+    c.is_ascii_alphanumeric() || *c == '_'
+}
+
 impl<'t> Iterator for Lexer<'t> {
     type Item = Token;
 
@@ -274,6 +345,56 @@ impl<'t> Iterator for Lexer<'t> {
 mod test {
     use super::*;
 
+    #[test]
+    fn test_float_registers() {
+        let lexemes = Lexer::new("%f0 %f31 %g %f")
+            .map(|tok| tok.lexeme)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            lexemes,
+            vec![
+                Lexeme::FReg(FReg(0)),
+                Lexeme::FReg(FReg(31)),
+                Lexeme::Unknown(9, 10),
+                Lexeme::Unknown(10, 11),
+                Lexeme::InvalidReg(12, 14),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_labels() {
+        let lexemes = Lexer::new("loop_2: bne $0 $1 @loop_2 @")
+            .map(|tok| tok.lexeme)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            lexemes,
+            vec![
+                Lexeme::Label(0, 6),
+                Lexeme::Op(OpCode::BranchNeq),
+                Lexeme::Reg(Reg(0)),
+                Lexeme::Reg(Reg(1)),
+                Lexeme::LabelRef(19, 25),
+                Lexeme::Unknown(26, 27),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_integers() {
+        let lexemes = Lexer::new("#12 #-34 #-")
+            .map(|tok| tok.lexeme)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            lexemes,
+            vec![
+                Lexeme::Int(Int(12)),
+                Lexeme::Int(Int(-34)),
+                Lexeme::InvalidInt(10, 11)
+            ]
+        );
+    }
+
     #[test]
     fn test_registers() {
         Lexer::new(" $1 $2").enumerate().for_each(|(i, tok)| {
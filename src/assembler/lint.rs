@@ -0,0 +1,342 @@
+//! Dataflow checks over an assembled program's control-flow graph.
+//!
+//! `lint` warns about code that runs but almost certainly doesn't do what
+//! was meant:
+//!
+//! * reads of registers that nothing writes to on any path leading there, so
+//!   that they silently read `0` (or whatever the host left in them)
+//! * stores whose value is overwritten on every path before anything reads it
+//! * comparisons whose result is overwritten by another comparison on every
+//!   path before a `JMPE`, `JMPNE` or `MOVCMP` gets to use it
+//!
+//! The checks are conservative, so as not to cry wolf: everything is assumed
+//! to be read after a `HALT` or `RET` (by the host or the caller), or after a
+//! jump whose target isn't known, and code that an unknown jump might land on
+//! is assumed to see whatever such jumps might leave in the registers.
+//! Interrupt handlers and spawned processes are assumed to start with every
+//! register written, and code that is never reached isn't checked at all.
+use crate::bytecode::OpCode;
+use crate::cfg::{Cfg, Edge};
+use crate::data::Reg;
+use crate::decode::DecodedInstr;
+
+use super::parser::Program;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Warning {
+    UninitializedRead(usize, Reg),
+    DeadStore(usize, Reg),
+    UnusedComparison(usize),
+}
+
+impl Warning {
+    pub fn line(&self) -> usize {
+        match self {
+            Warning::UninitializedRead(line, _)
+            | Warning::DeadStore(line, _)
+            | Warning::UnusedComparison(line) => *line,
+        }
+    }
+}
+
+impl std::fmt::Display for Warning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Warning::UninitializedRead(line, reg) => write!(
+                f,
+                "line {}: warning: `${}` is read before anything is written to it",
+                line, reg
+            ),
+            Warning::DeadStore(line, reg) => write!(
+                f,
+                "line {}: warning: the value stored in `${}` is always overwritten before being read",
+                line, reg
+            ),
+            Warning::UnusedComparison(line) => write!(
+                f,
+                "line {}: warning: the result of this comparison is always overwritten before being used",
+                line
+            ),
+        }
+    }
+}
+
+/// A set of registers, as a bitmask
+type Regs = u32;
+
+const ALL: Regs = !0;
+
+fn mask(regs: &[u8]) -> Regs {
+    regs.iter().fold(0, |set, r| set | 1 << r)
+}
+
+fn written(instr: &DecodedInstr) -> Regs {
+    instr.written().map_or(0, |r| 1 << r)
+}
+
+fn sets_cmp(op: OpCode) -> bool {
+    matches!(
+        op,
+        OpCode::Eq
+            | OpCode::NotEq
+            | OpCode::Greater
+            | OpCode::Less
+            | OpCode::GreaterEq
+            | OpCode::LessEq
+            | OpCode::CmpI
+            | OpCode::FCmp
+            | OpCode::FLess
+    )
+}
+
+fn reads_cmp(op: OpCode) -> bool {
+    matches!(op, OpCode::JumpEq | OpCode::JumpNeq | OpCode::MovCmp)
+}
+
+/// Whether storing a result is all the instruction does, so that the store
+/// being dead makes the instruction pointless. Instructions that also take
+/// something from somewhere, like `POP` or `RECV`, are still worth running.
+fn only_stores(op: OpCode) -> bool {
+    !matches!(
+        op,
+        OpCode::Pop | OpCode::Recv | OpCode::Wait | OpCode::Spawn | OpCode::New | OpCode::LoadWord
+    )
+}
+
+/// How control leaves a block, other than along its edges
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Exit {
+    /// only along its edges
+    Edges,
+    /// to wherever the last `CALL` was made from
+    Return,
+    /// to somewhere unknown, e.g., through an unresolved jump or `IRET`
+    Unknown,
+    /// nowhere; the program halts or traps
+    Stop,
+}
+
+fn exit(cfg: &Cfg, b: usize) -> Exit {
+    let block = &cfg.blocks[b];
+    if block.unresolved {
+        return Exit::Unknown;
+    }
+    match cfg.decoded.instrs[block.instrs.end - 1] {
+        Ok(instr) => match instr.op {
+            OpCode::Return => Exit::Return,
+            OpCode::IntReturn => Exit::Unknown,
+            OpCode::Halt => Exit::Stop,
+            _ => Exit::Edges,
+        },
+        Err(_) => Exit::Stop,
+    }
+}
+
+/// Checks `program`, returning its warnings in order of their offset in the
+/// code. Programs that failed to assemble aren't checked.
+pub fn lint(program: &Program) -> Vec<Warning> {
+    if !program.errors.is_empty() {
+        return vec![];
+    }
+    let cfg = Cfg::new(&program.bytes());
+    let map = program.source_map();
+    let n = cfg.blocks.len();
+    let instrs = |b: usize| {
+        cfg.blocks[b]
+            .instrs
+            .clone()
+            .filter_map(|i| cfg.decoded.instrs[i].ok())
+    };
+    let exits = (0..n).map(|b| exit(&cfg, b)).collect::<Vec<_>>();
+    // blocks control returns to from a call
+    let mut return_sites = vec![false; n];
+    for block in &cfg.blocks {
+        if block.succs.iter().any(|(_, edge)| *edge == Edge::Call) {
+            for (succ, edge) in &block.succs {
+                return_sites[*succ] |= *edge == Edge::Next;
+            }
+        }
+    }
+
+    // registers that may have been written on some path to the start of each
+    // block, or `None` if no path reaches it
+    let mut written_in: Vec<Option<Regs>> = vec![None; n];
+    loop {
+        let out = (0..n)
+            .map(|b| written_in[b].map(|set| instrs(b).fold(set, |set, i| set | written(&i))))
+            .collect::<Vec<_>>();
+        let mut unknown = None;
+        let mut returned = None;
+        for (exit, out) in exits.iter().zip(&out) {
+            match exit {
+                Exit::Unknown => unknown = join(unknown, *out),
+                Exit::Return => returned = join(returned, *out),
+                _ => {}
+            }
+        }
+        let mut next = vec![unknown; n];
+        for (e, entry) in cfg.entries.iter().enumerate() {
+            next[*entry] = join(next[*entry], Some(if e == 0 { 0 } else { ALL }));
+        }
+        for b in 0..n {
+            for (succ, _) in &cfg.blocks[b].succs {
+                next[*succ] = join(next[*succ], out[b]);
+            }
+            if return_sites[b] {
+                next[b] = join(next[b], returned);
+            }
+        }
+        if next == written_in {
+            break;
+        }
+        written_in = next;
+    }
+
+    // registers, and whether the comparison register, may be read before
+    // being written after the end of each block
+    let mut live_in: Vec<(Regs, bool)> = vec![(0, false); n];
+    let live_out = |live_in: &[(Regs, bool)], b: usize| {
+        if exits[b] != Exit::Edges {
+            return (ALL, true);
+        }
+        cfg.blocks[b]
+            .succs
+            .iter()
+            .fold((0, false), |(regs, cmp), (succ, _)| {
+                (regs | live_in[*succ].0, cmp || live_in[*succ].1)
+            })
+    };
+    loop {
+        let mut changed = false;
+        for b in (0..n).rev() {
+            let (mut regs, mut cmp) = live_out(&live_in, b);
+            for instr in instrs(b).collect::<Vec<_>>().into_iter().rev() {
+                regs = (regs & !written(&instr)) | mask(instr.read());
+                cmp = (cmp && !sets_cmp(instr.op)) || reads_cmp(instr.op);
+            }
+            if live_in[b] != (regs, cmp) {
+                live_in[b] = (regs, cmp);
+                changed = true;
+            }
+        }
+        if !changed {
+            break;
+        }
+    }
+
+    let mut found = vec![];
+    for (b, set) in written_in.iter().enumerate() {
+        let mut set = match set {
+            Some(set) => *set,
+            None => continue,
+        };
+        for instr in instrs(b) {
+            let mut reported = 0;
+            for r in instr.read() {
+                if set & 1 << r == 0 && reported & 1 << r == 0 {
+                    reported |= 1 << r;
+                    found.push((instr.pc, Warning::UninitializedRead(0, Reg(*r))));
+                }
+            }
+            set |= written(&instr);
+        }
+        let (mut regs, mut cmp) = live_out(&live_in, b);
+        for instr in instrs(b).collect::<Vec<_>>().into_iter().rev() {
+            if let Some(r) = instr.written() {
+                if regs & 1 << r == 0 && only_stores(instr.op) {
+                    found.push((instr.pc, Warning::DeadStore(0, Reg(r as u8))));
+                }
+            }
+            if sets_cmp(instr.op) && !cmp {
+                found.push((instr.pc, Warning::UnusedComparison(0)));
+            }
+            regs = (regs & !written(&instr)) | mask(instr.read());
+            cmp = (cmp && !sets_cmp(instr.op)) || reads_cmp(instr.op);
+        }
+    }
+    // stable, so that an instruction's reads come before its store
+    found.sort_by_key(|(pc, _)| *pc);
+    found
+        .into_iter()
+        .map(|(pc, warning)| {
+            let line = map.line(pc).unwrap_or(0);
+            match warning {
+                Warning::UninitializedRead(_, reg) => Warning::UninitializedRead(line, reg),
+                Warning::DeadStore(_, reg) => Warning::DeadStore(line, reg),
+                Warning::UnusedComparison(_) => Warning::UnusedComparison(line),
+            }
+        })
+        .collect()
+}
+
+fn join(a: Option<Regs>, b: Option<Regs>) -> Option<Regs> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a | b),
+        (a, None) => a,
+        (None, b) => b,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::parser::Parser;
+
+    fn check(src: &str) -> Vec<Warning> {
+        lint(&Parser::new(src).program().unwrap())
+    }
+
+    #[test]
+    fn test_uninitialized_reads() {
+        let src = "load $0 #1\nadd $0 $1 $2\nload $3 @skip\njmp $3\nload $4 #1\nskip: inc $4\nhalt";
+        assert_eq!(
+            check(src),
+            vec![
+                Warning::UninitializedRead(2, Reg(1)),
+                Warning::UninitializedRead(6, Reg(4)),
+            ]
+        );
+        // written on one of the paths is enough
+        let src = "load $1 #0\nbeq $1 $1 @skip\nload $0 #1\nskip: inc $0\nhalt";
+        assert_eq!(check(src), vec![]);
+    }
+
+    #[test]
+    fn test_dead_stores() {
+        let src = "load $0 #1\nload $0 #2\nload $1 #0\nloop: inc $1\nload $2 #5\nblt $1 $0 @loop\nload $2 #6\nhalt";
+        assert_eq!(
+            check(src),
+            vec![Warning::DeadStore(1, Reg(0)), Warning::DeadStore(5, Reg(2))]
+        );
+        // results are left for the host once the program halts, and popping
+        // into a register that is never read still pops
+        assert_eq!(check("load $0 #1\npush $0\npop $1\npop $1\nhalt"), vec![]);
+    }
+
+    #[test]
+    fn test_unused_comparisons() {
+        let src = "load $0 #1\neq $0 $0\nlt $0 $0\nload $1 @end\njmpe $1\ngt $0 $0\nend: halt";
+        assert_eq!(check(src), vec![Warning::UnusedComparison(2)]);
+    }
+
+    #[test]
+    fn test_calls() {
+        // the subroutine's writes reach the caller once it returns, and the
+        // caller's reads keep the subroutine's stores alive
+        let src = "load $0 @sub\ncall $0\ninc $1\nhalt\nsub: load $1 #4\nload $2 #1\nret";
+        assert_eq!(check(src), vec![]);
+        // an unresolved jump might land anywhere, taking what it wrote along
+        let src = "load $5 #2\nload $0 #1\npush $0\npop $6\nlt $0 $0\njmp $6\nhalt\ninc $5\nhalt";
+        assert_eq!(check(src), vec![]);
+        let src = "load $0 #1\npush $0\npop $6\njmp $6\nhalt\ninc $5\nhalt";
+        assert_eq!(check(src), vec![Warning::UninitializedRead(6, Reg(5))]);
+    }
+
+    #[test]
+    fn test_display() {
+        assert_eq!(
+            Warning::UninitializedRead(3, Reg(1)).to_string(),
+            "line 3: warning: `$1` is read before anything is written to it"
+        );
+    }
+}
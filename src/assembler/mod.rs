@@ -1,2 +1,4 @@
 pub mod lexer;
+pub mod lint;
+pub mod optimize;
 pub mod parser;
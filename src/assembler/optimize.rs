@@ -0,0 +1,814 @@
+//! Peephole optimization of assembled programs.
+//!
+//! An `Optimizer` rewrites a `Program`'s instructions before they're encoded,
+//! repeating its rewrites until none of them apply any more:
+//!
+//! * `redundant_loads` drops `LOAD`s whose value is overwritten before it is
+//!   read, as well as those that reload the value already in the register
+//! * `fold_constants` turns a `LOAD`, `LOAD`, `ADD` of two constants into a
+//!   single `LOAD` of their sum, dropping whichever of the first two `LOAD`s
+//!   are no longer needed
+//! * `jumps_to_next` drops jumps and branches that only ever land on the
+//!   instruction after them, along with a `LOAD` of the jump address
+//! * `unreachable` deletes code following a `HALT` that nothing jumps to
+//! * `direct_jumps` turns `JMP`s (and `JMPR`s, etc.) whose target
+//!   `constprop` can work out into a `BEQ` of the register with itself, so
+//!   that the target is right there in the code, for other tools as well as
+//!   the rewrites above. This is done once, before anything else, and is off
+//!   by default, as each jump grows by three bytes.
+//!
+//! Rewritten instructions keep the source line they came from, so that source
+//! maps, profiles and coverage point at the same lines they would have.
+//!
+//! Removing code moves everything after it, so every code address is
+//! relocated afterwards. This relies on knowing which operands are addresses:
+//! branch offsets always are, and other integers only if they were given as
+//! labels (see `Instruction::is_address`). Programs that come up with
+//! addresses some other way, e.g., a `JMP` to a `LOAD $0 #12`, or a `JMPR` by
+//! a hand-counted number of bytes, aren't safe to optimize, so they are left
+//! alone.
+use crate::bytecode::OpCode;
+use crate::constprop::Constants;
+use crate::data::{Int, Reg};
+use crate::decode::{decode_at, DecodedInstr};
+
+use super::parser::{Instruction, Operand, Program};
+
+/// Which rewrites to apply; all but `direct_jumps` by default.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Optimizer {
+    pub redundant_loads: bool,
+    pub fold_constants: bool,
+    pub jumps_to_next: bool,
+    pub unreachable: bool,
+    pub direct_jumps: bool,
+}
+
+impl Default for Optimizer {
+    fn default() -> Self {
+        Self {
+            redundant_loads: true,
+            fold_constants: true,
+            jumps_to_next: true,
+            unreachable: true,
+            direct_jumps: false,
+        }
+    }
+}
+
+impl Optimizer {
+    /// An optimizer that doesn't rewrite anything, for turning rewrites on
+    /// one at a time
+    pub fn none() -> Self {
+        Self {
+            redundant_loads: false,
+            fold_constants: false,
+            jumps_to_next: false,
+            unreachable: false,
+            direct_jumps: false,
+        }
+    }
+
+    /// Optimizes `program` in place, returning the number of instructions
+    /// removed. Programs with errors, with an address that doesn't land on
+    /// an instruction, or that might jump to an address that wasn't given as
+    /// a label, are left alone.
+    pub fn optimize(&self, program: &mut Program) -> usize {
+        let mut code = match Code::new(program) {
+            Some(code) => code,
+            None => return 0,
+        };
+        if self.direct_jumps {
+            code.direct_jumps(program);
+        }
+        loop {
+            let mut changed = false;
+            if self.unreachable {
+                changed |= code.unreachable();
+            }
+            if self.jumps_to_next {
+                changed |= code.jumps_to_next();
+            }
+            if self.fold_constants {
+                changed |= code.fold_constants();
+            }
+            if self.redundant_loads {
+                changed |= code.redundant_loads();
+            }
+            if !changed {
+                break;
+            }
+        }
+        let before = program.instrs.len();
+        program.instrs = code.finish();
+        before - program.instrs.len()
+    }
+}
+
+/// Where the value in a register came from, as far as relocating it goes
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Origin {
+    /// a `LOAD` of a label, whose address is relocated with the code
+    Address,
+    /// a `LOAD` of `#0`
+    Zero,
+    /// anything else, including values that depend on the path taken
+    Other,
+}
+
+#[derive(Clone, Debug)]
+struct Slot {
+    instr: Instruction,
+    /// the index of the instruction its address operand refers to, where the
+    /// end of the code is one past the last instruction
+    target: Option<usize>,
+}
+
+/// A program being optimized. Removed instructions leave an empty slot
+/// behind, so that targets keep their indices until the end, and a target
+/// whose instruction was removed refers to the next one that wasn't.
+#[derive(Clone, Debug)]
+struct Code {
+    slots: Vec<Option<Slot>>,
+    /// whether something jumps to each slot
+    targets: Vec<bool>,
+}
+
+/// Decodes an instruction on its own, to get at the registers it uses
+fn decode(instr: &Instruction) -> Option<DecodedInstr> {
+    decode_at(&instr.bytes(), 0).ok()
+}
+
+fn int(instr: &Instruction) -> Option<i32> {
+    instr.operands().find_map(|operand| match operand {
+        Operand::Int(Int(n)) => Some(n),
+        _ => None,
+    })
+}
+
+impl Code {
+    fn new(program: &Program) -> Option<Self> {
+        if !program.errors.is_empty() {
+            return None;
+        }
+        let mut starts = vec![0];
+        for instr in &program.instrs {
+            starts.push(starts.last().unwrap() + instr.bytes().len());
+        }
+        let mut slots = vec![];
+        for (i, instr) in program.instrs.iter().enumerate() {
+            let target = if instr.is_address() {
+                let n = int(instr)?;
+                let dest = if instr.opcode().is_branch() {
+                    starts[i + 1] as i64 + n as u16 as i16 as i64
+                } else {
+                    n as u16 as i64
+                };
+                if dest < 0 {
+                    return None;
+                }
+                Some(starts.binary_search(&(dest as usize)).ok()?)
+            } else {
+                None
+            };
+            slots.push(Some(Slot {
+                instr: *instr,
+                target,
+            }));
+        }
+        let targets = vec![false; slots.len() + 1];
+        let code = Self { slots, targets };
+        if code.relocatable() {
+            Some(code)
+        } else {
+            None
+        }
+    }
+
+    /// Whether every jump, call, etc. through a register is certain to go to
+    /// an address that was `LOAD`ed as a label, and so will be relocated
+    /// along with the code. Relative jumps may only go by `0`, which is the
+    /// one offset that doesn't depend on where anything is.
+    ///
+    /// Nothing is assumed about the registers at the start, after a `CALL`,
+    /// or wherever a jump through a register might land, i.e., at any
+    /// instruction whose address is `LOAD`ed; so straight-line code is
+    /// followed, along with the branches out of it.
+    fn relocatable(&self) -> bool {
+        let n = self.slots.len();
+        let mut states: Vec<Option<[Origin; 32]>> = vec![None; n];
+        let mut work = vec![];
+        for slot in self.slots.iter().flatten() {
+            match slot.target {
+                Some(t) if !slot.instr.opcode().is_branch() && t < n => {
+                    states[t] = Some([Origin::Other; 32]);
+                    work.push(t);
+                }
+                _ => {}
+            }
+        }
+        if n > 0 {
+            states[0] = Some([Origin::Other; 32]);
+            work.push(0);
+        }
+        while let Some(i) = work.pop() {
+            let slot = match &self.slots[i] {
+                Some(slot) => slot,
+                None => continue,
+            };
+            let instr = match decode(&slot.instr) {
+                Some(instr) => instr,
+                None => return false,
+            };
+            let mut state = states[i].unwrap();
+            let reg = state[instr.regs[0] as usize];
+            match instr.op {
+                OpCode::Jump
+                | OpCode::JumpEq
+                | OpCode::JumpNeq
+                | OpCode::Call
+                | OpCode::SetVector
+                | OpCode::Spawn
+                    if reg != Origin::Address =>
+                {
+                    return false
+                }
+                OpCode::JumpR | OpCode::JumpF | OpCode::JumpB if reg != Origin::Zero => {
+                    return false
+                }
+                _ => {}
+            }
+            if let Some(r) = instr.written() {
+                state[r] = match instr.op {
+                    OpCode::Load if slot.target.is_some() => Origin::Address,
+                    OpCode::Load if instr.imm == 0 => Origin::Zero,
+                    OpCode::Mov => reg,
+                    _ => Origin::Other,
+                };
+            }
+            let mut succs = vec![];
+            if instr.op.is_branch() {
+                succs.extend(slot.target.map(|t| (t, state)));
+            }
+            match instr.op {
+                OpCode::Call => succs.push((i + 1, [Origin::Other; 32])),
+                OpCode::JumpEq | OpCode::JumpNeq => succs.push((i + 1, state)),
+                op if !op.ends_block() || op.is_branch() => succs.push((i + 1, state)),
+                _ => {}
+            }
+            for (j, incoming) in succs {
+                if j >= n {
+                    continue;
+                }
+                let merged = match states[j] {
+                    Some(mut merged) => {
+                        for (m, o) in merged.iter_mut().zip(&incoming) {
+                            if m != o {
+                                *m = Origin::Other;
+                            }
+                        }
+                        merged
+                    }
+                    None => incoming,
+                };
+                if states[j] != Some(merged) {
+                    states[j] = Some(merged);
+                    work.push(j);
+                }
+            }
+        }
+        true
+    }
+
+    /// The index of the first instruction at or after `i` that hasn't been
+    /// removed
+    fn live_from(&self, i: usize) -> usize {
+        (i..self.slots.len())
+            .find(|i| self.slots[*i].is_some())
+            .unwrap_or(self.slots.len())
+    }
+
+    fn next(&self, i: usize) -> usize {
+        self.live_from(i + 1)
+    }
+
+    fn prev(&self, i: usize) -> Option<usize> {
+        (0..i).rev().find(|i| self.slots[*i].is_some())
+    }
+
+    fn mark_targets(&mut self) {
+        let mut targets = vec![false; self.slots.len() + 1];
+        for slot in self.slots.iter().flatten() {
+            if let Some(target) = slot.target {
+                targets[self.live_from(target)] = true;
+            }
+        }
+        self.targets = targets;
+    }
+
+    fn remove(&mut self, i: usize) {
+        self.slots[i] = None;
+        // anything that jumped here now lands on the next instruction
+        if self.targets[i] {
+            let next = self.live_from(i);
+            self.targets[next] = true;
+        }
+    }
+
+    fn decoded(&self, i: usize) -> Option<DecodedInstr> {
+        decode(&self.slots.get(i)?.as_ref()?.instr)
+    }
+
+    /// Whether the value in register `r` after instruction `i` is certain to
+    /// be overwritten before it is read. Only the straight-line code that
+    /// follows is considered, so this gives up at the first jump.
+    fn dead_after(&self, i: usize, r: usize) -> bool {
+        let mut j = i;
+        loop {
+            j = self.next(j);
+            let instr = match self.decoded(j) {
+                Some(instr) => instr,
+                None => return false,
+            };
+            if instr.read().contains(&(r as u8)) {
+                return false;
+            }
+            if instr.written() == Some(r) {
+                return true;
+            }
+            if instr.op.ends_block() {
+                return false;
+            }
+        }
+    }
+
+    fn unreachable(&mut self) -> bool {
+        self.mark_targets();
+        let mut changed = false;
+        let mut reachable = true;
+        for i in 0..self.slots.len() {
+            let op = match &self.slots[i] {
+                Some(slot) => slot.instr.opcode(),
+                None => continue,
+            };
+            reachable |= self.targets[i];
+            if !reachable {
+                self.remove(i);
+                changed = true;
+            } else if op == OpCode::Halt {
+                reachable = false;
+            }
+        }
+        changed
+    }
+
+    fn jumps_to_next(&mut self) -> bool {
+        self.mark_targets();
+        let mut changed = false;
+        for i in 0..self.slots.len() {
+            let (instr, target) = match &self.slots[i] {
+                Some(slot) => (slot.instr, slot.target),
+                None => continue,
+            };
+            let next = self.next(i);
+            if instr.opcode().is_branch() {
+                if target.map(|t| self.live_from(t)) == Some(next) {
+                    self.remove(i);
+                    changed = true;
+                }
+                continue;
+            }
+            let jump = match self.decoded(i) {
+                Some(jump) => jump,
+                None => continue,
+            };
+            let absolute = match jump.op {
+                OpCode::Jump | OpCode::JumpEq | OpCode::JumpNeq => true,
+                OpCode::JumpR | OpCode::JumpF | OpCode::JumpB => false,
+                _ => continue,
+            };
+            // whatever jumps straight here might have loaded another address
+            let load = match self.prev(i) {
+                Some(load) if !self.targets[i] => load,
+                _ => continue,
+            };
+            let (load_instr, load_target) = match &self.slots[load] {
+                Some(slot) => (slot.instr, slot.target),
+                None => continue,
+            };
+            let reg = jump.regs[0];
+            if load_instr.opcode() != OpCode::Load
+                || load_instr.operands().next() != Some(Operand::Reg(Reg(reg)))
+            {
+                continue;
+            }
+            let lands_next = if absolute {
+                load_target.map(|t| self.live_from(t)) == Some(next)
+            } else {
+                load_target.is_none() && int(&load_instr) == Some(0)
+            };
+            if lands_next {
+                self.remove(i);
+                if self.dead_after(load, reg as usize) {
+                    self.remove(load);
+                }
+                changed = true;
+            }
+        }
+        changed
+    }
+
+    fn fold_constants(&mut self) -> bool {
+        self.mark_targets();
+        let mut changed = false;
+        for i in 0..self.slots.len() {
+            let add = match self.decoded(i) {
+                Some(add) if add.op == OpCode::Add && !self.targets[i] => add,
+                _ => continue,
+            };
+            let second = match self.prev(i) {
+                Some(second) if !self.targets[second] => second,
+                _ => continue,
+            };
+            let first = match self.prev(second) {
+                Some(first) => first,
+                None => continue,
+            };
+            let constant = |at: usize| {
+                let slot = self.slots[at].as_ref()?;
+                let load = decode(&slot.instr)?;
+                match load.op {
+                    OpCode::Load if slot.target.is_none() => Some((load.regs[0], load.imm)),
+                    _ => None,
+                }
+            };
+            let (x, y) = match (constant(first), constant(second)) {
+                (Some(x), Some(y)) => (x, y),
+                _ => continue,
+            };
+            let [a, b, dst] = add.regs;
+            if x.0 == y.0 || !((x.0, y.0) == (a, b) || (x.0, y.0) == (b, a)) {
+                continue;
+            }
+            let sum = x.1 as i32 + y.1 as i32;
+            if sum > u16::MAX as i32 {
+                continue;
+            }
+            let line = self.slots[i].as_ref().unwrap().instr.line();
+            let load = Instruction::new(
+                line,
+                OpCode::Load,
+                &[Operand::Reg(Reg(dst)), Operand::Int(Int(sum))],
+            );
+            self.slots[i] = Some(Slot {
+                instr: load,
+                target: None,
+            });
+            for &(at, (reg, _)) in &[(first, x), (second, y)] {
+                if reg == dst || self.dead_after(i, reg as usize) {
+                    self.remove(at);
+                }
+            }
+            changed = true;
+        }
+        changed
+    }
+
+    fn redundant_loads(&mut self) -> bool {
+        self.mark_targets();
+        let mut changed = false;
+        // the constant (and whether it is an address) known to be in each
+        // register, within the current run of straight-line code
+        let mut known: [Option<(u16, bool)>; 32] = [None; 32];
+        for i in 0..self.slots.len() {
+            let is_address = match &self.slots[i] {
+                Some(slot) => slot.instr.is_address(),
+                None => continue,
+            };
+            if self.targets[i] {
+                known = [None; 32];
+            }
+            let instr = match self.decoded(i) {
+                Some(instr) => instr,
+                None => {
+                    known = [None; 32];
+                    continue;
+                }
+            };
+            if instr.op == OpCode::Load {
+                let r = instr.regs[0] as usize;
+                let val = Some((instr.imm, is_address));
+                if known[r] == val || self.dead_after(i, r) {
+                    self.remove(i);
+                    changed = true;
+                } else {
+                    known[r] = val;
+                }
+                continue;
+            }
+            if let Some(r) = instr.written() {
+                known[r] = None;
+            }
+            if instr.op.ends_block() {
+                known = [None; 32];
+            }
+        }
+        changed
+    }
+
+    fn direct_jumps(&mut self, program: &Program) {
+        let mut starts = vec![0];
+        for slot in self.slots.iter().flatten() {
+            starts.push(starts.last().unwrap() + slot.instr.bytes().len());
+        }
+        let jumps = self.slots.iter().flatten().filter(|slot| {
+            matches!(
+                slot.instr.opcode(),
+                OpCode::Jump | OpCode::JumpR | OpCode::JumpF | OpCode::JumpB
+            )
+        });
+        // each `BEQ` is three bytes longer than the jump it replaces, and every
+        // offset has to fit in its immediate
+        let grown = starts.last().unwrap() + 3 * jumps.count();
+        if grown > i16::MAX as usize {
+            return;
+        }
+        let consts = Constants::new(&program.bytes());
+        for i in 0..self.slots.len() {
+            let jump = match self.decoded(i) {
+                Some(jump) => jump,
+                None => continue,
+            };
+            if !matches!(
+                jump.op,
+                OpCode::Jump | OpCode::JumpR | OpCode::JumpF | OpCode::JumpB
+            ) {
+                continue;
+            }
+            let target = match consts.target(starts[i]) {
+                Some(dest) if dest >= 0 => starts.binary_search(&(dest as usize)).ok(),
+                _ => None,
+            };
+            if let Some(target) = target {
+                let reg = Operand::Reg(Reg(jump.regs[0]));
+                let line = self.slots[i].as_ref().unwrap().instr.line();
+                let branch =
+                    Instruction::new(line, OpCode::BranchEq, &[reg, reg, Operand::Int(Int(0))]);
+                self.slots[i] = Some(Slot {
+                    instr: branch,
+                    target: Some(target),
+                });
+            }
+        }
+    }
+
+    /// The remaining instructions, with their addresses relocated
+    fn finish(self) -> Vec<Instruction> {
+        // the new index of each slot, or of the next remaining instruction
+        // for removed ones
+        let mut index = vec![0; self.slots.len() + 1];
+        let mut count = 0;
+        for (i, slot) in self.slots.iter().enumerate() {
+            index[i] = count;
+            count += slot.is_some() as usize;
+        }
+        index[self.slots.len()] = count;
+        let slots = self.slots.into_iter().flatten().collect::<Vec<_>>();
+        let mut starts = vec![0];
+        for slot in &slots {
+            starts.push(starts.last().unwrap() + slot.instr.bytes().len());
+        }
+        slots
+            .iter()
+            .enumerate()
+            .map(|(i, slot)| match slot.target {
+                Some(target) => {
+                    let dest = starts[index[target]] as i32;
+                    if slot.instr.opcode().is_branch() {
+                        slot.instr.with_int(Int(dest - starts[i + 1] as i32))
+                    } else {
+                        slot.instr.with_int(Int(dest))
+                    }
+                }
+                None => slot.instr,
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::parser::Parser;
+    use crate::vm::Vm;
+
+    fn optimized(src: &str, optimizer: Optimizer) -> Program {
+        let mut program = Parser::new(src).program().unwrap();
+        assert!(program.errors.is_empty());
+        optimizer.optimize(&mut program);
+        program
+    }
+
+    /// The line and opcode of every instruction
+    fn listing(program: &Program) -> Vec<(usize, OpCode)> {
+        program
+            .instrs
+            .iter()
+            .map(|instr| (instr.line(), instr.opcode()))
+            .collect()
+    }
+
+    fn run(program: &Program) -> [i32; 32] {
+        let mut vm = Vm::new();
+        vm.load(program.bytes()).unwrap();
+        vm.run();
+        assert_eq!(vm.trap(), None);
+        vm.regs
+    }
+
+    #[test]
+    fn test_redundant_loads() {
+        let src = "load $0 #1\nload $0 #2\nload $1 #3\nload $1 #3\nadd $0 $1 $2\nload $1 #3\nhalt";
+        let program = optimized(
+            src,
+            Optimizer {
+                redundant_loads: true,
+                ..Optimizer::none()
+            },
+        );
+        assert_eq!(
+            listing(&program),
+            vec![
+                (2, OpCode::Load),
+                (4, OpCode::Load),
+                (5, OpCode::Add),
+                (7, OpCode::Halt)
+            ]
+        );
+        assert_eq!(run(&program)[..3], [2, 3, 5]);
+        // nothing is known about registers at a jump target
+        let src = "load $0 #1\nloop: load $0 #1\ninc $0\nblt $0 $0 @loop\nhalt";
+        let program = optimized(src, Optimizer::default());
+        assert_eq!(program.instrs.len(), 4);
+    }
+
+    #[test]
+    fn test_fold_constants() {
+        let src = "load $0 #2\nload $1 #3\nadd $0 $1 $0\nadd $0 $1 $2\nhalt";
+        let program = optimized(
+            src,
+            Optimizer {
+                fold_constants: true,
+                ..Optimizer::none()
+            },
+        );
+        assert_eq!(
+            listing(&program),
+            vec![
+                (2, OpCode::Load),
+                (3, OpCode::Load),
+                (4, OpCode::Load),
+                (5, OpCode::Halt)
+            ]
+        );
+        assert_eq!(
+            program.bytes(),
+            Parser::new("load $1 #3\nload $0 #5\nload $2 #8\nhalt")
+                .program()
+                .unwrap()
+                .bytes()
+        );
+        assert_eq!(run(&program)[..3], [5, 3, 8]);
+        // sums that don't fit in a `LOAD` are left alone
+        let src = "load $0 #60000\nload $1 #60000\nadd $0 $1 $2\nhalt";
+        let program = optimized(src, Optimizer::default());
+        assert_eq!(program.instrs.len(), 4);
+    }
+
+    #[test]
+    fn test_jumps_to_next() {
+        let src = "load $0 @next\njmp $0\nnext: load $0 #1\nbeq $1 $1 @done\ndone: halt";
+        let program = optimized(
+            src,
+            Optimizer {
+                jumps_to_next: true,
+                ..Optimizer::none()
+            },
+        );
+        assert_eq!(
+            listing(&program),
+            vec![(3, OpCode::Load), (5, OpCode::Halt)]
+        );
+        // a jump elsewhere stays
+        let src = "load $0 @end\njmp $0\ninc $1\nend: halt";
+        let program = optimized(src, Optimizer::default());
+        assert_eq!(program.instrs.len(), 4);
+    }
+
+    #[test]
+    fn test_unreachable() {
+        let src = "load $0 @skip\njmp $0\nhalt\ninc $1\nskip: inc $2\nhalt\ninc $3";
+        let program = optimized(
+            src,
+            Optimizer {
+                unreachable: true,
+                ..Optimizer::none()
+            },
+        );
+        assert_eq!(
+            listing(&program),
+            vec![
+                (1, OpCode::Load),
+                (2, OpCode::Jump),
+                (3, OpCode::Halt),
+                (5, OpCode::Inc),
+                (6, OpCode::Halt)
+            ]
+        );
+        assert_eq!(
+            program.bytes(),
+            Parser::new("load $0 @skip\njmp $0\nhalt\nskip: inc $2\nhalt")
+                .program()
+                .unwrap()
+                .bytes()
+        );
+        assert_eq!(run(&program)[..3], [7, 0, 1]);
+    }
+
+    #[test]
+    fn test_relocation() {
+        let src = "
+            load $0 #0
+            load $1 #30
+            load $1 #30
+            loop: load $2 #1
+            load $3 #2
+            add $2 $3 $4
+            add $0 $4 $0
+            blt $0 $1 @loop
+            load $5 @end
+            jmp $5
+            end: halt
+            inc $0
+        ";
+        let original = Parser::new(src).program().unwrap();
+        let mut program = original.clone();
+        assert_eq!(Optimizer::default().optimize(&mut program), 3);
+        // all but the jump address, which has moved
+        assert_eq!(run(&program)[..5], run(&original)[..5]);
+        let lines = listing(&program).into_iter().map(|(line, _)| line);
+        assert_eq!(lines.collect::<Vec<_>>(), vec![2, 4, 5, 6, 7, 8, 9, 10, 12]);
+    }
+
+    #[test]
+    fn test_direct_jumps() {
+        let src = "load $0 @skip\nmov $0 $1\njmp $1\ninc $2\nskip: inc $3\nhalt";
+        let original = Parser::new(src).program().unwrap();
+        let program = optimized(
+            src,
+            Optimizer {
+                direct_jumps: true,
+                ..Optimizer::none()
+            },
+        );
+        assert_eq!(program.instrs[2].opcode(), OpCode::BranchEq);
+        assert_eq!(
+            program.bytes(),
+            Parser::new("load $0 @skip\nmov $0 $1\nbeq $1 $1 @skip\ninc $2\nskip: inc $3\nhalt")
+                .program()
+                .unwrap()
+                .bytes()
+        );
+        // all but the addresses, which have moved
+        assert_eq!(run(&program)[2..4], run(&original)[2..4]);
+        // a jump whose target isn't known stays
+        let src = "load $0 @end\npush $0\npop $1\njmp $1\nend: halt";
+        let program = optimized(
+            src,
+            Optimizer {
+                direct_jumps: true,
+                ..Optimizer::none()
+            },
+        );
+        assert_eq!(program.instrs[3].opcode(), OpCode::Jump);
+    }
+
+    #[test]
+    fn test_left_alone() {
+        let left_alone = |src: &str| {
+            let mut program = Parser::new(src).program().unwrap();
+            let original = program.clone();
+            assert_eq!(Optimizer::default().optimize(&mut program), 0, "{}", src);
+            assert_eq!(program, original);
+        };
+        // an address in the middle of an instruction
+        left_alone("beq $0 $0 #1\nload $0 #1\nhalt\ninc $0");
+        // addresses that aren't labels, which wouldn't be relocated
+        left_alone("load $0 #10\njmp $0\nload $1 #1\nload $1 #2\nhalt");
+        left_alone("load $0 #4\njmpr $0\nload $1 #1\nload $1 #2\nhalt");
+        left_alone("load $0 @end\naddi $0 #2\ncall $0\nload $1 #1\nend: load $1 #2\nhalt");
+        // nor does a jump on one path get to use a label loaded on another
+        let src = "load $0 @end\nbeq $1 $2 @go\nload $0 #19\ngo: jmp $0\nload $1 #1\nload $1 #2\nend: halt";
+        left_alone(src);
+    }
+}
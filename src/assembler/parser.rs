@@ -1,5 +1,7 @@
-use crate::bytecode::{Arity, OpCode};
-use crate::data::{Int, Reg};
+use std::collections::HashMap;
+
+use crate::bytecode::{Arity, OpCode, OperandKind};
+use crate::data::{FReg, Int, Reg};
 
 use super::lexer::{Lexeme, Lexer, Token};
 
@@ -11,6 +13,21 @@ pub enum Error {
     ExpectedOperand(Token),
     ExpectedInteger(Token),
     ExpectedRegister(Token),
+    /// an operation with an integer literal operand that can't be encoded in
+    /// its immediate form, found on the given line
+    InvalidImmediate(usize, OpCode),
+    /// an integer literal operand that doesn't fit in its opcode's 16-bit
+    /// immediate: signed for the immediate arithmetic forms and branches, and
+    /// unsigned otherwise
+    ImmediateRange(usize, OpCode, Int),
+    /// the operand at the given index was of the wrong kind for its opcode
+    OperandKind(usize, OpCode, usize, OperandKind),
+    /// a label used on the given line that is never declared
+    UndefinedLabel(usize, String),
+    /// a label declared more than once, the second time on the given line
+    DuplicateLabel(usize, String),
+    /// a label too far away from the branch on the given line to be reached
+    LabelRange(usize, String),
     UnexpectedEof,
 }
 impl std::fmt::Display for Error {
@@ -34,6 +51,42 @@ impl std::fmt::Display for Error {
             Error::ExpectedRegister(t) => {
                 write!(f, "expected a register token, but found `{}` instead", t)
             }
+            Error::InvalidImmediate(line, op) => write!(
+                f,
+                "line {}: `{}` with an integer operand must store its result in its first register",
+                line, op
+            ),
+            Error::ImmediateRange(line, op, n) => {
+                let sign = if op.has_signed_imm() {
+                    "signed"
+                } else {
+                    "unsigned"
+                };
+                write!(
+                    f,
+                    "line {}: the integer `{}` doesn't fit in the {} 16-bit immediate of `{}`",
+                    line, n, sign, op
+                )
+            }
+            Error::OperandKind(line, op, i, kind) => write!(
+                f,
+                "line {}: operand {} of `{}` should be a {}",
+                line,
+                i + 1,
+                op,
+                kind
+            ),
+            Error::UndefinedLabel(line, name) => {
+                write!(f, "line {}: label `{}` is never declared", line, name)
+            }
+            Error::DuplicateLabel(line, name) => {
+                write!(f, "line {}: label `{}` is already declared", line, name)
+            }
+            Error::LabelRange(line, name) => write!(
+                f,
+                "line {}: label `{}` is out of range of a 16-bit offset",
+                line, name
+            ),
             Error::UnexpectedEof => write!(f, "unexpected end of input"),
         }
     }
@@ -43,6 +96,10 @@ impl std::fmt::Display for Error {
 pub enum Operand {
     Int(Int),
     Reg(Reg),
+    FReg(FReg),
+    /// a reference to a label, as the span of its name in the source; these
+    /// are replaced by integers once the whole program has been parsed
+    Label(usize, usize),
 }
 
 impl Operand {
@@ -54,9 +111,18 @@ impl Operand {
                 // todo: confirm endianness
                 vec![b2 as u8, b1 as u8]
             }
-            Operand::Reg(Reg(r)) => {
+            Operand::Reg(Reg(r)) | Operand::FReg(FReg(r)) => {
                 vec![*r]
             }
+            Operand::Label(..) => vec![0, 0],
+        }
+    }
+
+    pub fn kind(&self) -> OperandKind {
+        match self {
+            Operand::Int(_) | Operand::Label(..) => OperandKind::Int,
+            Operand::Reg(_) => OperandKind::Reg,
+            Operand::FReg(_) => OperandKind::FReg,
         }
     }
 }
@@ -67,9 +133,30 @@ pub struct Instruction {
     label: Option<Token>,
     opcode: OpCode,
     operands: [Option<Operand>; Arity::MAX],
+    /// whether the integer operand is the offset of a code address, i.e.,
+    /// was given as a label, and so must be adjusted if the code moves
+    address: bool,
 }
 
 impl Instruction {
+    /// An instruction built directly rather than parsed, e.g., by a compiler
+    /// targeting the assembler's `Program`. Operands must already be of the
+    /// kinds the opcode expects, with any labels resolved to integers.
+    pub fn new(line: usize, opcode: OpCode, operands: &[Operand]) -> Self {
+        let mut instr = Instruction {
+            line,
+            label: None,
+            opcode,
+            operands: [None; Arity::MAX],
+            address: false,
+        };
+        for (slot, operand) in instr.operands.iter_mut().zip(operands) {
+            *slot = Some(*operand);
+        }
+        debug_assert_eq!(instr.check_operands(), Ok(()));
+        instr
+    }
+
     pub fn bytes(&self) -> Vec<u8> {
         let mut bytes = vec![];
         bytes.push(self.opcode as u8);
@@ -78,8 +165,107 @@ impl Instruction {
                 bytes.extend(arg.bytes())
             }
         }
+        // pad out to the width the VM expects to consume
+        if bytes.len() < self.opcode.width() {
+            bytes.resize(self.opcode.width(), 0);
+        }
         bytes
     }
+
+    /// The source line this instruction was parsed from
+    pub fn line(&self) -> usize {
+        self.line
+    }
+
+    pub fn opcode(&self) -> OpCode {
+        self.opcode
+    }
+
+    pub fn operands(&self) -> impl Iterator<Item = Operand> + '_ {
+        self.operands.iter().flatten().copied()
+    }
+
+    /// Whether the integer operand holds a code address: always the case for
+    /// branches, whose offset is relative to the next instruction, and
+    /// otherwise only if it was given as a label, when it is an offset from
+    /// the start of the code.
+    pub fn is_address(&self) -> bool {
+        self.address || self.opcode.is_branch()
+    }
+
+    /// Replaces the integer operand, e.g., to relocate an address.
+    pub fn with_int(mut self, n: Int) -> Self {
+        for operand in self.operands.iter_mut().flatten() {
+            if let Operand::Int(_) = operand {
+                *operand = Operand::Int(n);
+            }
+        }
+        self
+    }
+
+    /// Marks the integer operand as a code address, as if it was given as a
+    /// label, e.g., for compilers building instructions with `new`.
+    pub fn as_address(self) -> Self {
+        Instruction {
+            address: true,
+            ..self
+        }
+    }
+
+    /// Checks that each operand is of the kind its opcode expects, e.g., that
+    /// float operations are only given float registers.
+    fn check_operands(&self) -> Result<(), Error> {
+        for (i, (kind, operand)) in self.opcode.operands().iter().zip(self.operands).enumerate() {
+            if operand.map(|o| o.kind()) != Some(*kind) {
+                return Err(Error::OperandKind(self.line, self.opcode, i, *kind));
+            }
+        }
+        Ok(())
+    }
+
+    /// Swaps a register-register operation whose second operand is an
+    /// integer literal for its immediate form, e.g., `ADD $0 #1 $0` becomes
+    /// `ADDI $0 #1`. Since immediate forms work in place, the result must be
+    /// stored back into the first register.
+    fn select_form(self) -> Result<Self, Error> {
+        let form = match self.opcode.immediate_form() {
+            Some(form) => form,
+            None => return Ok(self),
+        };
+        match self.operands {
+            [Some(Operand::Reg(r)), Some(Operand::Int(n)), dst] => match dst {
+                Some(Operand::Reg(d)) if d != r => {
+                    Err(Error::InvalidImmediate(self.line, self.opcode))
+                }
+                Some(Operand::Int(_)) => Err(Error::InvalidImmediate(self.line, self.opcode)),
+                _ => Ok(Instruction {
+                    opcode: form,
+                    operands: [Some(Operand::Reg(r)), Some(Operand::Int(n)), None],
+                    ..self
+                }),
+            },
+            _ => Ok(self),
+        }
+    }
+
+    /// Checks that an integer literal operand fits in the immediate it is
+    /// encoded as, so that e.g. `LOAD $0 #-1` isn't silently read back as
+    /// `65535`.
+    fn check_immediate(&self) -> Result<(), Error> {
+        let range = if self.opcode.has_signed_imm() {
+            i16::MIN as i32..=i16::MAX as i32
+        } else {
+            0..=u16::MAX as i32
+        };
+        for operand in self.operands.iter().flatten() {
+            if let Operand::Int(n) = operand {
+                if !range.contains(&n.0) {
+                    return Err(Error::ImmediateRange(self.line, self.opcode, *n));
+                }
+            }
+        }
+        Ok(())
+    }
 }
 
 #[derive(Clone, Debug, PartialEq, Eq)]
@@ -92,6 +278,39 @@ impl Program {
     pub fn bytes(&self) -> Vec<u8> {
         self.instrs.iter().flat_map(|instr| instr.bytes()).collect()
     }
+
+    /// Maps the byte offset of each encoded instruction back to the source
+    /// line it came from.
+    pub fn source_map(&self) -> SourceMap {
+        let mut offset = 0;
+        let mut entries = Vec::with_capacity(self.instrs.len());
+        for instr in &self.instrs {
+            entries.push((offset, instr.line));
+            offset += instr.bytes().len();
+        }
+        SourceMap { entries }
+    }
+}
+
+/// Pairs of `(byte offset, source line)`, one per instruction, in ascending
+/// order of offset.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SourceMap {
+    entries: Vec<(usize, usize)>,
+}
+
+impl SourceMap {
+    /// The source line of the instruction starting at `pc`, if any
+    pub fn line(&self, pc: usize) -> Option<usize> {
+        self.entries
+            .binary_search_by_key(&pc, |(offset, _)| *offset)
+            .ok()
+            .map(|i| self.entries[i].1)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (usize, usize)> + '_ {
+        self.entries.iter().copied()
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -185,9 +404,32 @@ impl<'t> Parser<'t> {
             instrs: vec![],
             errors: vec![],
         };
+        // label name => byte offset of the instruction following it
+        let mut labels = HashMap::new();
+        let mut offset = 0;
+        let mut label = None;
         while !self.is_done() {
+            if let Some(Token {
+                lexeme: Lexeme::Label(start, end),
+            }) = self.peek().copied()
+            {
+                let name = &self.lexer.source()[start..end];
+                label = Some(self.label()?);
+                if labels.insert(name, offset).is_some() {
+                    let line = self.lexer.coord().0 as usize;
+                    program
+                        .errors
+                        .push(Error::DuplicateLabel(line, name.to_string()));
+                }
+                self.skip_newlines();
+                continue;
+            }
             match self.instruction() {
-                Ok(instr) => program.instrs.push(instr),
+                Ok(mut instr) => {
+                    instr.label = label.take();
+                    offset += instr.bytes().len();
+                    program.instrs.push(instr)
+                }
                 Err(err) => {
                     program.errors.push(err);
                     let _ = self.many_while(
@@ -202,9 +444,47 @@ impl<'t> Parser<'t> {
             }
             self.skip_newlines();
         }
+        self.resolve_labels(&mut program, &labels);
         Ok(program)
     }
 
+    /// Replaces every label operand with the offset of its label: relative to
+    /// the end of the instruction for branches, and from the start of the
+    /// code otherwise, e.g., so that `LOAD $0 @end` can be used with `JMP`.
+    fn resolve_labels(&self, program: &mut Program, labels: &HashMap<&str, usize>) {
+        let mut offset = 0;
+        for instr in &mut program.instrs {
+            let next = offset + instr.bytes().len();
+            for operand in instr.operands.iter_mut() {
+                if let Some(Operand::Label(start, end)) = *operand {
+                    let name = &self.lexer.source()[start..end];
+                    let target = match labels.get(name) {
+                        Some(target) => *target as i64,
+                        None => {
+                            program
+                                .errors
+                                .push(Error::UndefinedLabel(instr.line, name.to_string()));
+                            0
+                        }
+                    };
+                    let (val, range) = if instr.opcode.is_branch() {
+                        (target - next as i64, i16::MIN as i64..=i16::MAX as i64)
+                    } else {
+                        (target, 0..=u16::MAX as i64)
+                    };
+                    if !range.contains(&val) {
+                        program
+                            .errors
+                            .push(Error::LabelRange(instr.line, name.to_string()));
+                    }
+                    *operand = Some(Operand::Int(Int(val as i32)));
+                    instr.address = true;
+                }
+            }
+            offset = next;
+        }
+    }
+
     pub fn instruction(&mut self) -> Result<Instruction, Error> {
         let opcode = self.expect_opcode()?;
         let line = self.lexer.coord().0 as usize;
@@ -213,6 +493,7 @@ impl<'t> Parser<'t> {
             label: None,
             opcode,
             operands: [None; Arity::MAX],
+            address: false,
         };
 
         // we just have to make sure this never exceeds `Arity::MAX`, but
@@ -222,6 +503,9 @@ impl<'t> Parser<'t> {
             instr.operands[i] = self.operand().map(Some)?
         }
 
+        let instr = instr.select_form()?;
+        instr.check_operands()?;
+        instr.check_immediate()?;
         Ok(instr)
     }
 
@@ -243,6 +527,22 @@ impl<'t> Parser<'t> {
                 self.bump();
                 Ok(reg)
             }
+            Some(Token {
+                lexeme: Lexeme::FReg(r),
+                ..
+            }) => {
+                let reg = Operand::FReg(*r);
+                self.bump();
+                Ok(reg)
+            }
+            Some(Token {
+                lexeme: Lexeme::LabelRef(start, end),
+                ..
+            }) => {
+                let label = Operand::Label(*start, *end);
+                self.bump();
+                Ok(label)
+            }
             _ => Err(Error::ExpectedOperand(self.bump())),
         }
     }
@@ -279,7 +579,7 @@ impl<'t> Parser<'t> {
     pub fn label(&mut self) -> Result<Token, Error> {
         match self.peek() {
             Some(Token {
-                lexeme: Lexeme::Label(_),
+                lexeme: Lexeme::Label(..),
                 ..
             }) => Ok(self.bump()),
             Some(_) => Err(Error::ExpectedLabel(self.bump())),
@@ -305,10 +605,214 @@ mod test {
                     Some(Operand::Int(Int(100))),
                     None,
                 ],
+                address: false,
             }],
         };
         let program = Parser::new("load $0 #100").program();
         assert_eq!(program.as_ref().map(|prog| prog.bytes().len()), Ok(4));
         assert_eq!(program, Ok(expected))
     }
+
+    #[test]
+    fn test_bitwise_instrs() {
+        let program = Parser::new("and $0 $1 $2\nnot $2 $3\nSAR $3 $4 $5")
+            .program()
+            .unwrap();
+        assert!(program.errors.is_empty());
+        assert_eq!(
+            program.bytes(),
+            vec![
+                OpCode::And as u8,
+                0,
+                1,
+                2,
+                OpCode::Not as u8,
+                2,
+                3,
+                0,
+                OpCode::Sar as u8,
+                3,
+                4,
+                5
+            ]
+        );
+    }
+
+    #[test]
+    fn test_immediate_forms() {
+        let program = Parser::new("add $0 #1 $0\nSUB $1 #-2 $1\neq $2 #7\nmuli $3 #3")
+            .program()
+            .unwrap();
+        assert!(program.errors.is_empty());
+        assert_eq!(
+            program.bytes(),
+            vec![
+                OpCode::AddI as u8,
+                0,
+                0,
+                1,
+                OpCode::SubI as u8,
+                1,
+                255,
+                254,
+                OpCode::CmpI as u8,
+                2,
+                0,
+                7,
+                OpCode::MulI as u8,
+                3,
+                0,
+                3
+            ]
+        );
+        // register forms are left alone
+        let program = Parser::new("add $0 $1 $0").program().unwrap();
+        assert_eq!(program.instrs[0].opcode(), OpCode::Add);
+    }
+
+    #[test]
+    fn test_immediate_form_errors() {
+        let program = Parser::new("add $0 #1 $1\nmul $0 #40000 $0").program();
+        assert_eq!(
+            program.map(|p| p.errors),
+            Ok(vec![
+                Error::InvalidImmediate(1, OpCode::Add),
+                Error::ImmediateRange(2, OpCode::MulI, Int(40000))
+            ])
+        );
+    }
+
+    #[test]
+    fn test_immediate_ranges() {
+        let src = "load $0 #0\nload $0 #65535\naddi $0 #-32768\ncmpi $0 #32767\nbeq $0 $1 #-32768";
+        assert_eq!(Parser::new(src).program().map(|p| p.errors), Ok(vec![]));
+        let src = "load $0 #-1\nload $0 #65536\naddi $0 #-32769\ncmpi $0 #32768\nsub $0 #40000 $0";
+        assert_eq!(
+            Parser::new(src).program().map(|p| p.errors),
+            Ok(vec![
+                Error::ImmediateRange(1, OpCode::Load, Int(-1)),
+                Error::ImmediateRange(2, OpCode::Load, Int(65536)),
+                Error::ImmediateRange(3, OpCode::AddI, Int(-32769)),
+                Error::ImmediateRange(4, OpCode::CmpI, Int(32768)),
+                Error::ImmediateRange(5, OpCode::SubI, Int(40000)),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_float_instrs() {
+        let program = Parser::new("fload %f1 #-3\nfadd %f0 %f1 %f2\nftoi %f2 $4")
+            .program()
+            .unwrap();
+        assert!(program.errors.is_empty());
+        assert_eq!(
+            program.bytes(),
+            vec![
+                OpCode::FLoad as u8,
+                1,
+                255,
+                253,
+                OpCode::FAdd as u8,
+                0,
+                1,
+                2,
+                OpCode::FloatToInt as u8,
+                2,
+                4,
+                0
+            ]
+        );
+    }
+
+    #[test]
+    fn test_move_instrs() {
+        let program = Parser::new("mov $1 $2\ninc $3\nmovcmp $4")
+            .program()
+            .unwrap();
+        assert!(program.errors.is_empty());
+        assert_eq!(
+            program.bytes(),
+            vec![
+                OpCode::Mov as u8,
+                1,
+                2,
+                0,
+                OpCode::Inc as u8,
+                3,
+                OpCode::MovCmp as u8,
+                4
+            ]
+        );
+    }
+
+    #[test]
+    fn test_labels() {
+        let src = "load $1 #3\nloop: inc $0\nblt $0 $1 @loop\nload $2 @end\njmp $2\nend:";
+        let program = Parser::new(src).program().unwrap();
+        assert!(program.errors.is_empty());
+        assert!(program.instrs[1].label.is_some());
+        assert_eq!(
+            program.bytes(),
+            vec![
+                OpCode::Load as u8,
+                1,
+                0,
+                3,
+                OpCode::Inc as u8,
+                0,
+                OpCode::BranchLess as u8,
+                0,
+                1,
+                255,
+                249,
+                OpCode::Load as u8,
+                2,
+                0,
+                17,
+                OpCode::Jump as u8,
+                2
+            ]
+        );
+    }
+
+    #[test]
+    fn test_label_errors() {
+        let src = "a: halt\na: beq $0 $0 @b\neq $0 @a";
+        let program = Parser::new(src).program().unwrap();
+        assert_eq!(
+            program.errors,
+            vec![
+                Error::DuplicateLabel(2, "a".to_string()),
+                Error::OperandKind(3, OpCode::Eq, 1, OperandKind::Reg),
+                Error::UndefinedLabel(2, "b".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_operand_kinds() {
+        let program = Parser::new("fadd %f0 $1 %f2\nload %f0 #1\nadd $0 $1 #2").program();
+        assert_eq!(
+            program.map(|p| p.errors),
+            Ok(vec![
+                Error::OperandKind(1, OpCode::FAdd, 1, OperandKind::FReg),
+                Error::OperandKind(2, OpCode::Load, 0, OperandKind::Reg),
+                Error::OperandKind(3, OpCode::Add, 2, OperandKind::Reg),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_source_map() {
+        let src = "load $0 #1\nload $1 #2\n\nadd $0 $1 $2\neq $0 $2\nhalt";
+        let program = Parser::new(src).program().unwrap();
+        assert!(program.errors.is_empty());
+        let map = program.source_map();
+        assert_eq!(
+            map.iter().collect::<Vec<_>>(),
+            vec![(0, 1), (4, 2), (8, 4), (12, 5), (16, 6)]
+        );
+        assert_eq!(map.line(8), Some(4));
+        assert_eq!(map.line(9), None);
+    }
 }
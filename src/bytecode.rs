@@ -55,6 +55,12 @@ stringy::stringy! {
     /// 2. op_code (`8` bits), operand (`24` bits)
     /// 3. op_code (`8` bits), operand (`8` bits), operand (`16` bits)
     /// 4. op_code (`8` bits), operand (`8` bits) x 3 (= `24` bits)
+    ///
+    /// with the exception of the fused compare-and-branch instructions (and
+    /// `NEW`), which need a full 16 bits for their immediate on top of their
+    /// two registers and are therefore 40 bits long:
+    ///
+    /// 5. op_code (`8` bits), operand (`8` bits) x 2, operand (`16` bits)
     OpCode { arity: Arity }
         =
         /// Load into register R the value X
@@ -73,13 +79,14 @@ stringy::stringy! {
         /// ADD $0 $1 $2
         /// ```
         /* ARITHMETIC */
-        Add "add" | "ADD" { Arity(2) }
-        Sub "sub" | "SUB" { Arity(2) }
-        Mul "mul" | "MUL" { Arity(2) }
+        Add "add" | "ADD" { Arity(3) }
+        Sub "sub" | "SUB" { Arity(3) }
+        Mul "mul" | "MUL" { Arity(3) }
         /// Unlike `ADD`, `SUB`, or `MUL`, this operation is not algebraically
         /// closed over the integers (which is the type of values stored in
         /// registers), so it will need special care
-        Div "div" | "DIV" { Arity(2) }
+        Div "div" | "DIV" { Arity(3) }
+        /* CONTROL FLOW */
         /// Absolute jump; will modify the program counter to point to the
         /// INSTRUCTION AT THE GIVEN BYTE INDEX
         ///
@@ -91,14 +98,12 @@ stringy::stringy! {
         /// LOAD $0 #0
         /// JMP $0
         /// ```
-        /* CONTROL FLOW */
         Jump "jmp" | "JMP" { Arity(1) }
-        /// Relative jump in the FORWARD direction. The argument is the register
-        /// number in which the number of bytes to move forward is stored.
+        /// Relative jump in the FORWARD direction; the same as `JMPR`.
         JumpF "jmpf" | "JMPF" { Arity(1) }
-        /// Relative jump in the BACKWARD direction. The argument is the
-        /// register index in which the number of bytes to move backward is
-        /// stored.
+        /// Relative jump in the BACKWARD direction; the same as `JMPR` with
+        /// the offset negated, so the register holds the number of bytes to
+        /// move backward by.
         JumpB "jmpb" | "JMPB" { Arity(1) }
 
         /* COMPARISONS */
@@ -130,13 +135,512 @@ stringy::stringy! {
         /* IDK LOL */
         /// Halts the program
         Halt "halt" | "HALT" { Arity(0) }
+        // an opcode's byte is its position in this list, so everything added
+        // since goes below, just before `Bad`, to keep existing bytecode
+        // meaning the same thing
+        /* IMMEDIATE ARITHMETIC */
+        /// Adds the signed 16-bit integer X to the value in register R, in
+        /// place. The assembler picks this form automatically for an `ADD`
+        /// whose second operand is an integer literal, e.g. `ADD $0 #1 $0`.
+        ///
+        /// __syntax:__ `ADDI $R #X`
+        AddI "addi" | "ADDI" { Arity(2) }
+        /// Subtracts the signed 16-bit integer X from the value in register R,
+        /// in place
+        ///
+        /// __syntax:__ `SUBI $R #X`
+        SubI "subi" | "SUBI" { Arity(2) }
+        /// Multiplies the value in register R by the signed 16-bit integer X,
+        /// in place
+        ///
+        /// __syntax:__ `MULI $R #X`
+        MulI "muli" | "MULI" { Arity(2) }
+        /// Equality comparison between the value in register R and the signed
+        /// 16-bit integer X, stored in the comparison register like `EQ`. The
+        /// assembler picks this form for an `EQ` whose second operand is an
+        /// integer literal.
+        ///
+        /// __syntax:__ `CMPI $R #X`
+        CmpI "cmpi" | "CMPI" { Arity(2) }
+        /* BITWISE */
+        /// Bitwise AND of the values found in the first two registers, stored
+        /// in the third register
+        ///
+        /// __syntax:__ `AND $REG $REG $REG`
+        And "and" | "AND" { Arity(3) }
+        Or "or" | "OR" { Arity(3) }
+        Xor "xor" | "XOR" { Arity(3) }
+        /// Bitwise complement of the value found in the first register, stored
+        /// in the second register
+        ///
+        /// __syntax:__ `NOT $REG $REG`
+        Not "not" | "NOT" { Arity(2) }
+        /// Shifts the value found in the first register left by the amount
+        /// found in the second register, storing the result in the third
+        /// register
+        ///
+        /// __syntax:__ `SHL $REG $REG $REG`
+        ///
+        /// Shift amounts are read as *unsigned*, so a negative amount is just a
+        /// very large one. Shifting by 32 or more shifts every bit out, leaving
+        /// `0` for `SHL` and `SHR`, and a register full of sign bits (`0` or
+        /// `-1`) for `SAR`.
+        Shl "shl" | "SHL" { Arity(3) }
+        /// Logical shift right; vacated bits are filled with zeroes
+        Shr "shr" | "SHR" { Arity(3) }
+        /// Arithmetic shift right; vacated bits are filled with the sign bit
+        Sar "sar" | "SAR" { Arity(3) }
+        /* FLOATING POINT */
+        /// Load into float register F the (signed, 16-bit) integer X, converted
+        /// to a float. Other values can be built up from these with the float
+        /// arithmetic instructions below.
+        ///
+        /// __syntax:__ `FLOAD %fF #X`
+        FLoad "fload" | "FLOAD" { Arity(2) }
+        /// Adds the values found in the first two float registers and stores
+        /// it in the third float register. Like the rest of the float
+        /// arithmetic, this follows IEEE 754 semantics, so overflow gives an
+        /// infinity and dividing by zero gives an infinity or NaN rather than
+        /// trapping.
+        ///
+        /// __syntax:__ `FADD %fF %fF %fF`
+        FAdd "fadd" | "FADD" { Arity(3) }
+        FSub "fsub" | "FSUB" { Arity(3) }
+        FMul "fmul" | "FMUL" { Arity(3) }
+        FDiv "fdiv" | "FDIV" { Arity(3) }
+        /// Equality comparison between two float registers, stored in the
+        /// comparison register. NaN isn't equal to anything, itself included.
+        ///
+        /// __syntax:__ `FCMP %fF %fF`
+        FCmp "fcmp" | "FCMP" { Arity(2) }
+        /// Less-than comparison between two float registers, stored in the
+        /// comparison register
+        ///
+        /// __syntax:__ `FLT %fF %fF`
+        FLess "flt" | "FLT" { Arity(2) }
+        /// Converts the integer in register R to a float, stored in float
+        /// register F
+        ///
+        /// __syntax:__ `ITOF $R %fF`
+        IntToFloat "itof" | "ITOF" { Arity(2) }
+        /// Converts the float in float register F to an integer (rounding
+        /// towards zero), stored in register R. Values out of range saturate
+        /// to `i32::MIN`/`i32::MAX`, and NaN converts to `0`.
+        ///
+        /// __syntax:__ `FTOI %fF $R`
+        FloatToInt "ftoi" | "FTOI" { Arity(2) }
+        /* MOVES */
+        /// Copies the value found in the first register into the second
+        /// register
+        ///
+        /// __syntax:__ `MOV $SRC $DST`
+        Mov "mov" | "MOV" { Arity(2) }
+        /// Increments the value in register R by one, in place. Overflow is
+        /// handled the same way as for `ADD`.
+        ///
+        /// __syntax:__ `INC $R`
+        Inc "inc" | "INC" { Arity(1) }
+        /// Decrements the value in register R by one, in place
+        ///
+        /// __syntax:__ `DEC $R`
+        Dec "dec" | "DEC" { Arity(1) }
+        /// Copies the remainder left behind by the last `DIV` into register R
+        ///
+        /// __syntax:__ `MOVREM $R`
+        MovRem "movrem" | "MOVREM" { Arity(1) }
+        /// Stores the comparison register into register R, as `1` if it is set
+        /// and `0` otherwise
+        ///
+        /// __syntax:__ `MOVCMP $R`
+        MovCmp "movcmp" | "MOVCMP" { Arity(1) }
+        /* RELATIVE JUMPS */
+        /// Relative jump. The argument is the register holding the *signed*
+        /// number of bytes to move by, measured from the END of the jump
+        /// instruction (i.e., from the instruction that would otherwise run
+        /// next), so an offset of `0` does nothing. This is the same base the
+        /// compare-and-branch instructions use.
+        ///
+        /// __syntax:__ `JMPR $REG`
+        ///
+        /// Like every other jump, landing outside of the code or in the middle
+        /// of an instruction raises a trap.
+        JumpR "jmpr" | "JMPR" { Arity(1) }
+        /* BRANCHES */
+        /// Compares the values in the first two registers and, if they are
+        /// equal, jumps by the signed 16-bit offset X. The offset is relative
+        /// to the *end* of the branch instruction, so `#0` just falls through.
+        /// Unlike `EQ`, this leaves the comparison register alone.
+        ///
+        /// In assembly the offset is usually given as a label, which the
+        /// assembler resolves to the right offset:
+        ///
+        /// ```txt
+        /// loop:
+        ///     INC $0
+        ///     BNE $0 $1 @loop
+        /// ```
+        ///
+        /// __syntax:__ `BEQ $REG $REG #X`
+        BranchEq "beq" | "BEQ" { Arity(3) }
+        BranchNeq "bne" | "BNE" { Arity(3) }
+        BranchLess "blt" | "BLT" { Arity(3) }
+        BranchGreaterEq "bge" | "BGE" { Arity(3) }
+        BranchGreater "bgt" | "BGT" { Arity(3) }
+        BranchLessEq "ble" | "BLE" { Arity(3) }
+        /* MEMORY */
+        /// Loads the word (32 bits, little endian) found at the address held
+        /// in the first register into the second register. Addresses past the
+        /// end of RAM that aren't mapped to a device raise a trap.
+        ///
+        /// __syntax:__ `LDW $ADDR $DST`
+        LoadWord "ldw" | "LDW" { Arity(2) }
+        /// Stores the value found in the first register as a word at the
+        /// address held in the second register
+        ///
+        /// __syntax:__ `STW $SRC $ADDR`
+        StoreWord "stw" | "STW" { Arity(2) }
+        /* INTERRUPTS */
+        /// Installs the address found in register R as the handler for
+        /// interrupt line N (see the `interrupt` module)
+        ///
+        /// __syntax:__ `VEC $R #N`
+        SetVector "vec" | "VEC" { Arity(2) }
+        /// Enables interrupts
+        ///
+        /// __syntax:__ `EI`
+        EnableInt "ei" | "EI" { Arity(0) }
+        /// Disables interrupts; any raised in the meantime are left pending
+        ///
+        /// __syntax:__ `DI`
+        DisableInt "di" | "DI" { Arity(0) }
+        /// Returns from an interrupt handler, restoring the pc and flags saved
+        /// when the interrupt was taken and re-enabling interrupts
+        ///
+        /// __syntax:__ `IRET`
+        IntReturn "iret" | "IRET" { Arity(0) }
+        /* PROCESSES */
+        /// Gives up the rest of the time slice, letting the `Scheduler` run
+        /// another process. Outside of a scheduler this does nothing.
+        ///
+        /// __syntax:__ `YIELD`
+        Yield "yield" | "YIELD" { Arity(0) }
+        /// Sends the value found in the second register to the mailbox of the
+        /// process whose pid is found in the first register. Messages are
+        /// delivered by the scheduler at the end of the sender's time slice.
+        ///
+        /// __syntax:__ `SEND $PID $VAL`
+        Send "send" | "SEND" { Arity(2) }
+        /// Takes the oldest message from the process's mailbox into register
+        /// R, blocking until one arrives if the mailbox is empty
+        ///
+        /// __syntax:__ `RECV $R`
+        Recv "recv" | "RECV" { Arity(1) }
+        /// Starts a new process running the same code, from the code offset
+        /// found in the first register, and stores its pid in the second
+        /// register. The child starts out with fresh registers and memory.
+        /// Outside of a scheduler, this fails and stores `-1`.
+        ///
+        /// __syntax:__ `SPAWN $ENTRY $DST`
+        Spawn "spawn" | "SPAWN" { Arity(2) }
+        /// Waits for the process whose pid is found in register R to stop,
+        /// then replaces R with its exit value: the value of its `$0` when it
+        /// halted, or `i32::MIN` if it trapped (or doesn't exist)
+        ///
+        /// __syntax:__ `WAIT $R`
+        Wait "wait" | "WAIT" { Arity(1) }
+        /* STACK */
+        /// Pushes the value found in register R onto the stack. References
+        /// stay references, so objects on the stack are kept alive.
+        ///
+        /// __syntax:__ `PUSH $R`
+        Push "push" | "PUSH" { Arity(1) }
+        /// Pops the value on top of the stack into register R
+        ///
+        /// __syntax:__ `POP $R`
+        Pop "pop" | "POP" { Arity(1) }
+        /// Copies the value N entries below the top of the stack into
+        /// register R, so `PEEK $R #0` reads the top of the stack
+        ///
+        /// __syntax:__ `PEEK $R #N`
+        Peek "peek" | "PEEK" { Arity(2) }
+        /// Overwrites the value N entries below the top of the stack with the
+        /// value found in register R
+        ///
+        /// __syntax:__ `POKE $R #N`
+        Poke "poke" | "POKE" { Arity(2) }
+        /// Pushes the address of the next instruction onto the stack and
+        /// jumps to the address found in register R
+        ///
+        /// __syntax:__ `CALL $R`
+        Call "call" | "CALL" { Arity(1) }
+        /// Pops an address off the stack and jumps to it, returning from a
+        /// `CALL`
+        ///
+        /// __syntax:__ `RET`
+        Return "ret" | "RET" { Arity(0) }
+        /* HEAP */
+        /// Allocates an object with as many fields as the value found in the
+        /// first register, all `0`, and stores a reference to it in the second
+        /// register. K is the kind of object: `0` for an array, `1` for a
+        /// string (whose fields are bytes) and `2` for a record (see the
+        /// `heap` module).
+        ///
+        /// __syntax:__ `NEW $LEN $DST #K`
+        New "new" | "NEW" { Arity(3) }
+        /// Reads the field numbered by the second register out of the object
+        /// referenced by the first, into the third register
+        ///
+        /// __syntax:__ `GETF $OBJ $FIELD $DST`
+        GetField "getf" | "GETF" { Arity(3) }
+        /// Writes the value found in the third register into the field
+        /// numbered by the second register of the object referenced by the
+        /// first
+        ///
+        /// __syntax:__ `SETF $OBJ $FIELD $SRC`
+        SetField "setf" | "SETF" { Arity(3) }
+        /// Stores the number of fields of the object referenced by the first
+        /// register in the second
+        ///
+        /// __syntax:__ `LEN $OBJ $DST`
+        Len "len" | "LEN" { Arity(2) }
+        /* VALUES */
+        /// Stores `nil` in register R
+        ///
+        /// __syntax:__ `NIL $R`
+        Nil "nil" | "NIL" { Arity(1) }
+        /// Stores the type of the value found in the first register in the
+        /// second, as a number: `0` for nil, `1` for an integer, `2` for a
+        /// boolean and `3` for a reference (see the `value` module)
+        ///
+        /// __syntax:__ `TYPEOF $R $DST`
+        TypeOf "typeof" | "TYPEOF" { Arity(2) }
         /// INVALID opcode; stops VM with an error
         Bad "bad" | "BAD" { Arity(1) }
 }
 
 impl From<u8> for OpCode {
+    /// Bytes that don't correspond to any opcode decode as `OpCode::Bad`
     fn from(byte: u8) -> Self {
-        OpCode::VARIANTS[byte as usize]
+        OpCode::VARIANTS
+            .get(byte as usize)
+            .copied()
+            .unwrap_or(OpCode::Bad)
+    }
+}
+
+/// The operand layouts an instruction may be encoded with, following the
+/// forms listed in the `OpCode` docs. Registers take up a single byte and
+/// immediates two (big endian); layouts with fewer than 3 operand bytes are
+/// padded out to 32 bits, except for the single-register and opcode-only forms
+/// which are left unpadded.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Layout {
+    /// `OP`
+    Op,
+    /// `OP $R`
+    Reg,
+    /// `OP $R #X`
+    RegImm,
+    /// `OP $R $R` (+ 1 byte of padding)
+    RegReg,
+    /// `OP $R $R $R`
+    RegRegReg,
+    /// `OP $R $R #X`, the only layout wider than 32 bits
+    RegRegImm,
+}
+
+impl Layout {
+    /// Number of bytes an instruction with this layout occupies, counting the
+    /// opcode itself as well as any padding.
+    pub fn width(&self) -> usize {
+        match self {
+            Layout::Op => 1,
+            Layout::Reg => 2,
+            Layout::RegImm | Layout::RegReg | Layout::RegRegReg => 4,
+            Layout::RegRegImm => 5,
+        }
+    }
+
+    /// Number of register operands
+    pub fn regs(&self) -> usize {
+        match self {
+            Layout::Op => 0,
+            Layout::Reg | Layout::RegImm => 1,
+            Layout::RegReg | Layout::RegRegImm => 2,
+            Layout::RegRegReg => 3,
+        }
+    }
+
+    /// Whether a 16-bit immediate follows the register operands
+    pub fn has_imm(&self) -> bool {
+        matches!(self, Layout::RegImm | Layout::RegRegImm)
+    }
+}
+
+/// The kinds of operands an instruction can take, as written in assembly.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum OperandKind {
+    /// an integer register, `$R`
+    Reg,
+    /// a float register, `%fF`
+    FReg,
+    /// an integer literal, `#X`
+    Int,
+}
+
+impl std::fmt::Display for OperandKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OperandKind::Reg => write!(f, "register"),
+            OperandKind::FReg => write!(f, "float register"),
+            OperandKind::Int => write!(f, "integer"),
+        }
+    }
+}
+
+impl OpCode {
+    /// The immediate form of a register-register operation, i.e., the opcode
+    /// to use instead when its second operand is an integer literal
+    pub fn immediate_form(&self) -> Option<OpCode> {
+        match self {
+            OpCode::Add => Some(OpCode::AddI),
+            OpCode::Sub => Some(OpCode::SubI),
+            OpCode::Mul => Some(OpCode::MulI),
+            OpCode::Eq => Some(OpCode::CmpI),
+            _ => None,
+        }
+    }
+
+    /// The operands this opcode takes, in order. `BAD` takes none, since it
+    /// never decodes into a valid instruction.
+    pub fn operands(&self) -> &'static [OperandKind] {
+        use OperandKind::{FReg, Int, Reg};
+        match self {
+            OpCode::Halt
+            | OpCode::Bad
+            | OpCode::EnableInt
+            | OpCode::DisableInt
+            | OpCode::IntReturn
+            | OpCode::Yield
+            | OpCode::Return => &[],
+            OpCode::Jump
+            | OpCode::JumpR
+            | OpCode::JumpF
+            | OpCode::JumpB
+            | OpCode::JumpEq
+            | OpCode::JumpNeq
+            | OpCode::Inc
+            | OpCode::Dec
+            | OpCode::MovRem
+            | OpCode::MovCmp
+            | OpCode::Recv
+            | OpCode::Wait
+            | OpCode::Push
+            | OpCode::Pop
+            | OpCode::Nil
+            | OpCode::Call => &[Reg],
+            OpCode::Load | OpCode::AddI | OpCode::SubI | OpCode::MulI | OpCode::CmpI => &[Reg, Int],
+            OpCode::FLoad => &[FReg, Int],
+            OpCode::SetVector | OpCode::Peek | OpCode::Poke => &[Reg, Int],
+            OpCode::Eq
+            | OpCode::NotEq
+            | OpCode::Greater
+            | OpCode::Less
+            | OpCode::GreaterEq
+            | OpCode::LessEq
+            | OpCode::Not
+            | OpCode::Mov
+            | OpCode::LoadWord
+            | OpCode::StoreWord
+            | OpCode::Send
+            | OpCode::Spawn
+            | OpCode::Len
+            | OpCode::TypeOf => &[Reg, Reg],
+            OpCode::Add
+            | OpCode::Sub
+            | OpCode::Mul
+            | OpCode::Div
+            | OpCode::And
+            | OpCode::Or
+            | OpCode::Xor
+            | OpCode::Shl
+            | OpCode::Shr
+            | OpCode::Sar
+            | OpCode::GetField
+            | OpCode::SetField => &[Reg, Reg, Reg],
+            OpCode::FAdd | OpCode::FSub | OpCode::FMul | OpCode::FDiv => &[FReg, FReg, FReg],
+            OpCode::FCmp | OpCode::FLess => &[FReg, FReg],
+            OpCode::IntToFloat => &[Reg, FReg],
+            OpCode::FloatToInt => &[FReg, Reg],
+            OpCode::BranchEq
+            | OpCode::BranchNeq
+            | OpCode::BranchLess
+            | OpCode::BranchGreaterEq
+            | OpCode::BranchGreater
+            | OpCode::BranchLessEq
+            | OpCode::New => &[Reg, Reg, Int],
+        }
+    }
+
+    /// Whether the immediate operand is read as a signed 16-bit integer,
+    /// rather than an unsigned one
+    pub fn has_signed_imm(&self) -> bool {
+        matches!(
+            self,
+            OpCode::AddI | OpCode::SubI | OpCode::MulI | OpCode::CmpI | OpCode::FLoad
+        ) || self.is_branch()
+    }
+
+    /// Whether this is one of the fused compare-and-branch instructions,
+    /// whose immediate is an offset relative to the next instruction
+    pub fn is_branch(&self) -> bool {
+        matches!(
+            self,
+            OpCode::BranchEq
+                | OpCode::BranchNeq
+                | OpCode::BranchLess
+                | OpCode::BranchGreaterEq
+                | OpCode::BranchGreater
+                | OpCode::BranchLessEq
+        )
+    }
+
+    /// Whether control may continue anywhere other than the next instruction
+    /// after an instruction with this opcode.
+    pub fn ends_block(&self) -> bool {
+        matches!(
+            self,
+            OpCode::Jump
+                | OpCode::JumpR
+                | OpCode::JumpF
+                | OpCode::JumpB
+                | OpCode::JumpEq
+                | OpCode::JumpNeq
+                | OpCode::Halt
+                | OpCode::IntReturn
+                | OpCode::Call
+                | OpCode::Return
+        ) || self.is_branch()
+    }
+
+    /// How the operands are laid out in the bytecode; both register banks
+    /// are addressed with a single byte, so this only depends on how many
+    /// operands there are and whether the last is an immediate.
+    pub fn layout(&self) -> Layout {
+        match self.operands() {
+            [] => Layout::Op,
+            [_] => Layout::Reg,
+            [_, OperandKind::Int] => Layout::RegImm,
+            [_, _] => Layout::RegReg,
+            [_, _, OperandKind::Int] => Layout::RegRegImm,
+            _ => Layout::RegRegReg,
+        }
+    }
+
+    /// Number of bytes an encoded instruction with this opcode occupies in
+    /// the bytecode.
+    pub fn width(&self) -> usize {
+        self.layout().width()
     }
 }
 
@@ -146,3 +650,38 @@ fn stringything() {
     let u = OpCode::VARIANTS[byte as usize];
     assert_eq!(u.as_usize(), byte as usize)
 }
+
+#[test]
+fn operands_match_arity() {
+    for op in OpCode::VARIANTS.iter().filter(|op| **op != OpCode::Bad) {
+        assert!(op.arity().unwrap() == op.operands().len(), "{:?}", op)
+    }
+}
+
+#[test]
+fn baseline_opcodes_keep_their_bytes() {
+    let baseline = [
+        OpCode::Load,
+        OpCode::Add,
+        OpCode::Sub,
+        OpCode::Mul,
+        OpCode::Div,
+        OpCode::Jump,
+        OpCode::JumpF,
+        OpCode::JumpB,
+        OpCode::Eq,
+        OpCode::NotEq,
+        OpCode::Greater,
+        OpCode::Less,
+        OpCode::GreaterEq,
+        OpCode::LessEq,
+        OpCode::JumpEq,
+        OpCode::JumpNeq,
+        OpCode::Halt,
+    ];
+    for (byte, op) in baseline.iter().enumerate() {
+        assert_eq!(OpCode::from(byte as u8), *op);
+        assert_eq!(op.as_usize(), byte);
+    }
+    assert_eq!(OpCode::Bad.as_usize(), OpCode::VARIANTS.len() - 1);
+}
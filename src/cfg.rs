@@ -0,0 +1,338 @@
+//! Control-flow graphs of bytecode.
+//!
+//! A program is split into basic blocks, i.e., runs of instructions that are
+//! always executed from the top, one after the other. A block ends at any
+//! instruction after which control may go somewhere other than the next
+//! instruction (a jump, branch, `CALL`, `RET` or `HALT`), as well as just
+//! before any instruction something jumps to.
+//!
+//! Since every jump other than the compare-and-branch instructions goes
+//! through a register, its target is only known if that register was `LOAD`ed
+//! with a constant (or `MOV`ed from one that was) earlier in the same block,
+//! which is how the assembler lays out jumps to labels. Blocks ending in a jump
+//! that can't be resolved this way are marked as such, since they may have
+//! successors that aren't in the graph.
+//!
+//! `Cfg::to_dot` exports the graph to Graphviz, e.g., with `lil-vm cfg`:
+//!
+//! ```txt
+//! lil-vm cfg routine.lvm | dot -Tsvg > routine.svg
+//! ```
+use std::fmt::Write;
+use std::ops::Range;
+
+use crate::assembler::parser::SourceMap;
+use crate::bytecode::OpCode;
+use crate::decode::Decoded;
+use crate::verify::leaders;
+
+/// How control gets from a block to one of its successors
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Edge {
+    /// falling through to the next instruction, including returning from a
+    /// call and not taking a conditional jump
+    Next,
+    /// an unconditional jump
+    Jump,
+    /// a conditional jump or branch that was taken
+    Taken,
+    /// a call to a subroutine
+    Call,
+}
+
+impl std::fmt::Display for Edge {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Edge::Next => write!(f, "next"),
+            Edge::Jump => write!(f, "jump"),
+            Edge::Taken => write!(f, "taken"),
+            Edge::Call => write!(f, "call"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Block {
+    /// indices into `Cfg::decoded` of the instructions in the block
+    pub instrs: Range<usize>,
+    /// byte offset of the first instruction
+    pub start: usize,
+    /// byte offset just past the last instruction
+    pub end: usize,
+    /// indices of the blocks control may continue to, in the order of
+    /// their edges
+    pub succs: Vec<(usize, Edge)>,
+    pub preds: Vec<usize>,
+    /// whether the block ends in a jump or call whose target isn't known, so
+    /// that it may have successors that aren't listed
+    pub unresolved: bool,
+}
+
+#[derive(Clone, Debug)]
+pub struct Cfg {
+    pub decoded: Decoded,
+    pub blocks: Vec<Block>,
+    /// indices of the blocks execution may start at: the first one, as well as
+    /// interrupt handlers installed with `VEC` and processes started with
+    /// `SPAWN`, where their addresses are known
+    pub entries: Vec<usize>,
+    /// for every instruction, the index of the block containing it
+    block_of: Vec<usize>,
+}
+
+impl Cfg {
+    pub fn new(code: &[u8]) -> Self {
+        let decoded = Decoded::new(code);
+        let n = decoded.instrs.len();
+        let (leaders, targets) = leaders(&decoded);
+
+        let mut blocks = vec![];
+        let mut block_of = vec![0; n];
+        for i in 0..n {
+            if leaders[i] {
+                let start = pc(&decoded, i);
+                blocks.push(Block {
+                    instrs: i..i,
+                    start,
+                    end: start,
+                    succs: vec![],
+                    preds: vec![],
+                    unresolved: false,
+                });
+            }
+            let block = blocks.last_mut().unwrap();
+            block.instrs.end = i + 1;
+            block.end = if i + 1 < n {
+                pc(&decoded, i + 1)
+            } else {
+                code.len()
+            };
+            block_of[i] = blocks.len() - 1;
+        }
+
+        let mut entries = if n > 0 { vec![0] } else { vec![] };
+        for i in 0..n {
+            let target = targets[i]
+                .and_then(|dest| decoded.jump_position(dest))
+                .filter(|at| *at < n)
+                .map(|at| block_of[at]);
+            let instr = match decoded.instrs[i] {
+                Ok(instr) => instr,
+                Err(_) => continue,
+            };
+            if let OpCode::SetVector | OpCode::Spawn = instr.op {
+                if let Some(target) = target {
+                    if !entries.contains(&target) {
+                        entries.push(target);
+                    }
+                }
+            }
+            let block = block_of[i];
+            if blocks[block].instrs.end != i + 1 {
+                continue;
+            }
+            let (edge, falls_through) = match instr.op {
+                OpCode::Jump | OpCode::JumpR | OpCode::JumpF | OpCode::JumpB => {
+                    (Some(Edge::Jump), false)
+                }
+                OpCode::JumpEq | OpCode::JumpNeq => (Some(Edge::Taken), true),
+                op if op.is_branch() => (Some(Edge::Taken), true),
+                OpCode::Call => (Some(Edge::Call), true),
+                OpCode::Halt | OpCode::Return | OpCode::IntReturn => (None, false),
+                _ => (None, true),
+            };
+            if let Some(edge) = edge {
+                match target {
+                    Some(target) => blocks[block].succs.push((target, edge)),
+                    None => blocks[block].unresolved = true,
+                }
+            }
+            if falls_through && i + 1 < n {
+                blocks[block].succs.push((block + 1, Edge::Next));
+            }
+        }
+        for b in 0..blocks.len() {
+            for (succ, _) in blocks[b].succs.clone() {
+                if !blocks[succ].preds.contains(&b) {
+                    blocks[succ].preds.push(b);
+                }
+            }
+        }
+
+        Self {
+            decoded,
+            blocks,
+            entries,
+            block_of,
+        }
+    }
+
+    /// The index of the block containing the instruction starting at `pc`
+    pub fn block_at(&self, pc: usize) -> Option<usize> {
+        self.decoded
+            .position(pc)
+            .and_then(|i| self.block_of.get(i).copied())
+    }
+
+    /// Renders the graph in Graphviz's DOT language, with one node per block
+    /// listing its instructions, annotated with their source lines if a
+    /// source map is given. Entry blocks are drawn in bold, and unresolved
+    /// jumps lead to a `?` node.
+    pub fn to_dot(&self, map: Option<&SourceMap>) -> String {
+        let mut out = String::new();
+        out.push_str("digraph cfg {\n");
+        out.push_str("    node [shape=box, fontname=\"monospace\"];\n");
+        for (b, block) in self.blocks.iter().enumerate() {
+            let mut label = String::new();
+            for i in block.instrs.clone() {
+                let at = pc(&self.decoded, i);
+                let text = match &self.decoded.instrs[i] {
+                    Ok(instr) => instr.to_string(),
+                    Err(trap) => format!("; {}", trap),
+                };
+                write!(label, "0x{:04x}  {}", at, text).unwrap();
+                if let Some(line) = map.and_then(|map| map.line(at)) {
+                    write!(label, "  ; line {}", line).unwrap();
+                }
+                label.push_str("\\l");
+            }
+            let style = if self.entries.contains(&b) {
+                ", style=bold"
+            } else {
+                ""
+            };
+            writeln!(out, "    b{} [label=\"{}\"{}];", b, label, style).unwrap();
+        }
+        for (b, block) in self.blocks.iter().enumerate() {
+            for (succ, edge) in &block.succs {
+                writeln!(out, "    b{} -> b{} [label=\"{}\"];", b, succ, edge).unwrap();
+            }
+            if block.unresolved {
+                writeln!(out, "    b{} -> unknown [style=dashed];", b).unwrap();
+            }
+        }
+        if self.blocks.iter().any(|block| block.unresolved) {
+            out.push_str("    unknown [label=\"?\", shape=circle];\n");
+        }
+        out.push_str("}\n");
+        out
+    }
+}
+
+/// The byte offset of the instruction at index `i`
+fn pc(decoded: &Decoded, i: usize) -> usize {
+    match decoded.instrs[i] {
+        Ok(instr) => instr.pc,
+        Err(trap) => trap.pc(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::parser::{Parser, Program};
+
+    fn assemble(src: &str) -> Program {
+        let program = Parser::new(src).program().unwrap();
+        assert!(program.errors.is_empty());
+        program
+    }
+
+    #[test]
+    fn test_blocks() {
+        let src = "
+            load $0 #0
+            load $1 #10
+            loop: inc $0
+            blt $0 $1 @loop
+            load $2 @done
+            jmp $2
+            inc $3
+            done: halt
+        ";
+        let cfg = Cfg::new(&assemble(src).bytes());
+        let spans = cfg
+            .blocks
+            .iter()
+            .map(|block| block.instrs.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(spans, vec![0..2, 2..4, 4..6, 6..7, 7..8]);
+        let succs = cfg
+            .blocks
+            .iter()
+            .map(|block| block.succs.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            succs,
+            vec![
+                vec![(1, Edge::Next)],
+                vec![(1, Edge::Taken), (2, Edge::Next)],
+                vec![(4, Edge::Jump)],
+                vec![(4, Edge::Next)],
+                vec![],
+            ]
+        );
+        assert_eq!(cfg.blocks[4].preds, vec![2, 3]);
+        // the unreachable `inc $3` has no predecessors
+        assert!(cfg.blocks[3].preds.is_empty());
+        assert_eq!(cfg.block_at(cfg.blocks[2].start), Some(2));
+        assert_eq!(cfg.blocks[1].start, 8);
+        assert_eq!(cfg.blocks[1].end, 15);
+    }
+
+    #[test]
+    fn test_calls_and_entries() {
+        let src = "
+            load $0 @handler
+            vec $0 #1
+            load $1 @sub
+            call $1
+            halt
+            sub: ret
+            handler: jmp $5
+        ";
+        let cfg = Cfg::new(&assemble(src).bytes());
+        assert_eq!(cfg.blocks.len(), 4);
+        assert_eq!(cfg.blocks[0].succs, vec![(2, Edge::Call), (1, Edge::Next)]);
+        assert_eq!(cfg.entries, vec![0, 3]);
+        assert!(cfg.blocks[3].unresolved);
+        assert!(cfg.blocks[3].succs.is_empty());
+        assert!(!cfg.blocks[2].unresolved);
+    }
+
+    #[test]
+    fn test_jump_into_a_block() {
+        // the target of the jump is only known once the jump ends its block,
+        // and splits the block it lands in
+        let src = "load $0 @mid\ninc $1\nmid: inc $2\njmp $0";
+        let cfg = Cfg::new(&assemble(src).bytes());
+        let spans = cfg
+            .blocks
+            .iter()
+            .map(|block| block.instrs.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(spans, vec![0..2, 2..4]);
+        // `$0` isn't known to hold `@mid` at the top of the second block
+        assert!(cfg.blocks[1].unresolved);
+    }
+
+    #[test]
+    fn test_to_dot() {
+        let program = assemble("load $0 @end\njmp $0\nend: halt\njmp $9");
+        let cfg = Cfg::new(&program.bytes());
+        let dot = cfg.to_dot(Some(&program.source_map()));
+        assert_eq!(
+            dot,
+            "digraph cfg {
+    node [shape=box, fontname=\"monospace\"];
+    b0 [label=\"0x0000  load $0 #6  ; line 1\\l0x0004  jmp $0  ; line 2\\l\", style=bold];
+    b1 [label=\"0x0006  halt  ; line 3\\l\"];
+    b2 [label=\"0x0007  jmp $9  ; line 4\\l\"];
+    b0 -> b1 [label=\"jump\"];
+    b2 -> unknown [style=dashed];
+    unknown [label=\"?\", shape=circle];
+}
+"
+        );
+    }
+}
@@ -0,0 +1,356 @@
+//! Constant propagation over bytecode.
+//!
+//! Every jump other than the compare-and-branch instructions goes through a
+//! register, so where it lands depends on what that register holds. The
+//! verifier and `cfg` only look for a `LOAD` earlier in the same straight run
+//! of code; `Constants` instead abstractly interprets the whole program,
+//! following every path from the entry point and tracking which registers are
+//! known to hold the same constant whichever way execution got there, so that
+//! targets computed with arithmetic, moved between registers, or loaded
+//! before a branch are resolved too.
+//!
+//! Some assumptions keep this tractable:
+//!
+//! * registers start out unknown, since the host may set them before running
+//! * a `CALL` may clobber any register, so nothing is known once it returns
+//! * interrupt handlers leave the registers as they found them
+//! * a jump whose target isn't known might land on any instruction whose
+//!   address is `LOAD`ed somewhere in the program, with nothing known
+use std::fmt::Write;
+
+use crate::assembler::parser::SourceMap;
+use crate::bytecode::OpCode;
+use crate::decode::{Decoded, DecodedInstr};
+use crate::verify::jump_target;
+
+/// What is known about a register
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Known {
+    Const(i32),
+    Unknown,
+}
+
+impl Known {
+    fn join(self, other: Known) -> Known {
+        if self == other {
+            self
+        } else {
+            Known::Unknown
+        }
+    }
+
+    fn value(self) -> Option<i32> {
+        match self {
+            Known::Const(n) => Some(n),
+            Known::Unknown => None,
+        }
+    }
+}
+
+type State = [Known; 32];
+
+const UNKNOWN: State = [Known::Unknown; 32];
+
+/// The result of interpreting a program: what is known about each register
+/// before every instruction, and where each jump goes.
+#[derive(Clone, Debug)]
+pub struct Constants {
+    pub decoded: Decoded,
+    /// the state before each instruction, or `None` if it is never reached
+    states: Vec<Option<State>>,
+}
+
+/// Whether the instruction takes a code address to jump to, call, or start
+/// running elsewhere
+fn takes_address(op: OpCode) -> bool {
+    matches!(
+        op,
+        OpCode::Jump
+            | OpCode::JumpR
+            | OpCode::JumpF
+            | OpCode::JumpB
+            | OpCode::JumpEq
+            | OpCode::JumpNeq
+            | OpCode::Call
+            | OpCode::SetVector
+            | OpCode::Spawn
+    ) || op.is_branch()
+}
+
+/// The value the instruction leaves in the register it writes, given the state
+/// before it; only integer arithmetic and moves are worked out.
+fn eval(instr: &DecodedInstr, state: &State) -> Known {
+    let [a, b, _] = instr.regs;
+    let x = state[a as usize].value();
+    let y = state[b as usize].value();
+    let imm = instr.imm as i16 as i32;
+    let val = match instr.op {
+        OpCode::Load => Some(instr.imm as u32 as i32),
+        OpCode::Mov => x,
+        OpCode::AddI => x.map(|x| x.wrapping_add(imm)),
+        OpCode::SubI => x.map(|x| x.wrapping_sub(imm)),
+        OpCode::MulI => x.map(|x| x.wrapping_mul(imm)),
+        OpCode::Inc => x.map(|x| x.wrapping_add(1)),
+        OpCode::Dec => x.map(|x| x.wrapping_sub(1)),
+        OpCode::Not => x.map(|x| !x),
+        op => match (x, y) {
+            (Some(x), Some(y)) => match op {
+                OpCode::Add => Some(x.wrapping_add(y)),
+                OpCode::Sub => Some(x.wrapping_sub(y)),
+                OpCode::Mul => Some(x.wrapping_mul(y)),
+                OpCode::And => Some(x & y),
+                OpCode::Or => Some(x | y),
+                OpCode::Xor => Some(x ^ y),
+                OpCode::Shl => Some(x.checked_shl(y as u32).unwrap_or(0)),
+                OpCode::Shr => Some((x as u32).checked_shr(y as u32).unwrap_or(0) as i32),
+                OpCode::Sar => Some(x >> (y as u32).min(31)),
+                _ => None,
+            },
+            _ => None,
+        },
+    };
+    val.map_or(Known::Unknown, Known::Const)
+}
+
+fn known(state: &State) -> [Option<i32>; 32] {
+    let mut known = [None; 32];
+    for (k, s) in known.iter_mut().zip(state) {
+        *k = s.value();
+    }
+    known
+}
+
+impl Constants {
+    pub fn new(code: &[u8]) -> Self {
+        let decoded = Decoded::new(code);
+        let n = decoded.instrs.len();
+        let position = |dest: i64| decoded.jump_position(dest).filter(|i| *i < n);
+        // where jumps with unknown targets might land
+        let mut taken = vec![];
+        for instr in decoded.instrs.iter().flatten() {
+            if instr.op == OpCode::Load {
+                if let Some(i) = position(instr.imm as i64) {
+                    taken.push(i);
+                }
+            }
+        }
+
+        let mut states: Vec<Option<State>> = vec![None; n];
+        let mut work = vec![];
+        if n > 0 {
+            states[0] = Some(UNKNOWN);
+            work.push(0);
+        }
+        while let Some(i) = work.pop() {
+            let state = states[i].unwrap();
+            let instr = match decoded.instrs[i] {
+                Ok(instr) => instr,
+                Err(_) => continue,
+            };
+            let mut after = state;
+            if let Some(r) = instr.written() {
+                after[r] = eval(&instr, &state);
+            }
+            let mut succs = vec![];
+            if takes_address(instr.op) {
+                let target = jump_target(&instr, &known(&state));
+                let entry = match instr.op {
+                    // these start running code elsewhere, with registers of its own
+                    OpCode::SetVector | OpCode::Spawn => UNKNOWN,
+                    _ => after,
+                };
+                match target.and_then(position) {
+                    Some(j) => succs.push((j, entry)),
+                    // one that is known but out of range traps instead
+                    None if target.is_none() => {
+                        succs.extend(taken.iter().map(|j| (*j, UNKNOWN)));
+                    }
+                    None => {}
+                }
+            }
+            if i + 1 < n {
+                match instr.op {
+                    OpCode::Call => succs.push((i + 1, UNKNOWN)),
+                    op if !op.ends_block() || op.is_branch() => succs.push((i + 1, after)),
+                    OpCode::JumpEq | OpCode::JumpNeq => succs.push((i + 1, after)),
+                    _ => {}
+                }
+            }
+            for (j, incoming) in succs {
+                let merged = match states[j] {
+                    Some(state) => {
+                        let mut merged = state;
+                        for (m, k) in merged.iter_mut().zip(&incoming) {
+                            *m = m.join(*k);
+                        }
+                        merged
+                    }
+                    None => incoming,
+                };
+                if states[j] != Some(merged) {
+                    states[j] = Some(merged);
+                    work.push(j);
+                }
+            }
+        }
+        Self { decoded, states }
+    }
+
+    /// The constant known to be in `reg` just before the instruction at `pc`
+    /// runs, if any
+    pub fn value(&self, pc: usize, reg: usize) -> Option<i32> {
+        let i = self.decoded.position(pc)?;
+        self.states.get(i)?.as_ref()?[reg].value()
+    }
+
+    /// Whether the instruction at `pc` is ever reached
+    pub fn is_reached(&self, pc: usize) -> bool {
+        let i = self.decoded.position(pc);
+        i.is_some_and(|i| matches!(self.states.get(i), Some(Some(_))))
+    }
+
+    /// Where the jump (or call, etc.) at `pc` goes, if it always goes to the
+    /// same place; this may lie outside of the code, in which case the jump
+    /// always traps
+    pub fn target(&self, pc: usize) -> Option<i64> {
+        let i = self.decoded.position(pc)?;
+        let instr = self.decoded.instrs.get(i)?.as_ref().ok()?;
+        let state = self.states[i].as_ref()?;
+        if !takes_address(instr.op) {
+            return None;
+        }
+        jump_target(instr, &known(state))
+    }
+
+    /// A listing of the program, with each jump's target (`?` if it isn't
+    /// known) and each instruction's source line, if a source map is given.
+    /// Instructions that are never reached are marked as such.
+    pub fn disassemble(&self, map: Option<&SourceMap>) -> String {
+        let mut out = String::new();
+        for (i, instr) in self.decoded.instrs.iter().enumerate() {
+            let (pc, text) = match instr {
+                Ok(instr) => (instr.pc, instr.to_string()),
+                Err(trap) => (trap.pc(), format!("<{}>", trap)),
+            };
+            let mut notes = vec![];
+            if let Ok(instr) = instr {
+                if takes_address(instr.op) && self.states[i].is_some() {
+                    notes.push(match self.target(pc) {
+                        Some(dest) if dest >= 0 => format!("-> 0x{:04x}", dest),
+                        Some(dest) => format!("-> {}", dest),
+                        None => "-> ?".to_string(),
+                    });
+                }
+            }
+            if self.states[i].is_none() {
+                notes.push("unreached".to_string());
+            }
+            if let Some(line) = map.and_then(|map| map.line(pc)) {
+                notes.push(format!("line {}", line));
+            }
+            write!(out, "0x{:04x}  {}", pc, text).unwrap();
+            if !notes.is_empty() {
+                write!(
+                    out,
+                    "{:width$}; {}",
+                    "",
+                    notes.join(", "),
+                    width = 24usize.saturating_sub(text.len())
+                )
+                .unwrap();
+            }
+            out.push('\n');
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::parser::Parser;
+
+    fn constants(src: &str) -> Constants {
+        let program = Parser::new(src).program().unwrap();
+        assert!(program.errors.is_empty());
+        Constants::new(&program.bytes())
+    }
+
+    #[test]
+    fn test_across_blocks() {
+        // the address is loaded before a branch, and worked out with
+        // arithmetic on one side of it, but is the same either way
+        let src = "
+            load $0 @end
+            load $1 #1
+            beq $1 $2 @skip
+            mov $0 $3
+            load $0 #0
+            add $0 $3 $0
+            skip: jmp $0
+            inc $5
+            end: halt
+        ";
+        let consts = constants(src);
+        assert_eq!(consts.target(25), Some(29));
+        assert_eq!(consts.value(25, 1), Some(1));
+        assert_eq!(consts.value(25, 3), None);
+        assert!(!consts.is_reached(27));
+        assert!(consts.is_reached(29));
+    }
+
+    #[test]
+    fn test_unknown_targets() {
+        // the two paths into the jump disagree
+        let src = "
+            load $0 @a
+            beq $1 $2 @go
+            load $0 @b
+            go: jmp $0
+            a: halt
+            b: inc $1
+            halt
+        ";
+        let consts = constants(src);
+        assert_eq!(consts.target(13), None);
+        // both addresses were loaded, so either might be jumped to
+        assert!(consts.is_reached(15));
+        assert!(consts.is_reached(16));
+        assert_eq!(consts.value(16, 0), None);
+    }
+
+    #[test]
+    fn test_calls() {
+        let src = "
+            load $0 @sub
+            load $1 #7
+            call $0
+            inc $1
+            halt
+            sub: load $2 #1
+            ret
+        ";
+        let consts = constants(src);
+        assert_eq!(consts.target(8), Some(13));
+        assert_eq!(consts.value(13, 1), Some(7));
+        // nothing survives the call
+        assert_eq!(consts.value(10, 1), None);
+    }
+
+    #[test]
+    fn test_disassemble() {
+        let program = Parser::new("load $0 #3\naddi $0 #3\njmp $0\ninc $1\nhalt")
+            .program()
+            .unwrap();
+        let consts = Constants::new(&program.bytes());
+        assert_eq!(
+            consts.disassemble(Some(&program.source_map())),
+            "0x0000  load $0 #3              ; line 1
+0x0004  addi $0 #3              ; line 2
+0x0008  jmp $0                  ; -> 0x0006, line 3
+0x000a  inc $1                  ; unreached, line 4
+0x000c  halt                    ; unreached, line 5
+"
+        );
+    }
+}
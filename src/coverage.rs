@@ -0,0 +1,165 @@
+//! Line-level code coverage for assembly programs.
+//!
+//! Coverage is derived from a run's `Profile`: every source line holding an
+//! instruction is *instrumented*, and its hit count is the number of times the
+//! instruction on that line was executed. Coverage for several runs (or
+//! several programs) can be merged, rendered as an annotated listing, or
+//! written out in the `lcov` tracefile format.
+use std::{collections::BTreeMap, fmt::Write};
+
+use crate::assembler::parser::SourceMap;
+use crate::profile::Profile;
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Coverage {
+    /// source file => (line => hit count), for every instrumented line
+    pub files: BTreeMap<String, BTreeMap<usize, u64>>,
+}
+
+impl Coverage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records the lines executed in `profile` against the source file `file`.
+    pub fn from_profile(file: &str, map: &SourceMap, profile: &Profile) -> Self {
+        let mut lines = BTreeMap::new();
+        for (pc, line) in map.iter() {
+            let hits = profile.pcs.get(&pc).map_or(0, |(_, n)| *n);
+            *lines.entry(line).or_insert(0) += hits;
+        }
+        let mut cov = Self::new();
+        cov.files.insert(file.to_string(), lines);
+        cov
+    }
+
+    /// Adds the hit counts of `other` into `self`. Lines instrumented in
+    /// either coverage are instrumented in the result.
+    pub fn merge(&mut self, other: &Coverage) {
+        for (file, lines) in &other.files {
+            let ours = self.files.entry(file.clone()).or_default();
+            for (line, hits) in lines {
+                *ours.entry(*line).or_insert(0) += hits;
+            }
+        }
+    }
+
+    /// `(lines hit, lines instrumented)` for the given file
+    pub fn summary(&self, file: &str) -> (usize, usize) {
+        self.files.get(file).map_or((0, 0), |lines| {
+            (lines.values().filter(|n| **n > 0).count(), lines.len())
+        })
+    }
+
+    /// Renders `src` with each line prefixed by its hit count, in the style
+    /// of `gcov`: lines that hold no instruction are marked with `-`, while
+    /// instructions that never ran are marked with `#####`.
+    pub fn listing(&self, file: &str, src: &str) -> String {
+        let lines = self.files.get(file);
+        let mut out = String::new();
+        for (i, text) in src.lines().enumerate() {
+            let count = match lines.and_then(|l| l.get(&(i + 1))) {
+                Some(0) => "#####".to_string(),
+                Some(n) => n.to_string(),
+                None => "-".to_string(),
+            };
+            let _ = writeln!(out, "{:>9}:{:>5}: {}", count, i + 1, text);
+        }
+        out
+    }
+
+    pub fn to_lcov(&self) -> String {
+        let mut out = String::new();
+        for (file, lines) in &self.files {
+            let _ = writeln!(out, "TN:\nSF:{}", file);
+            for (line, hits) in lines {
+                let _ = writeln!(out, "DA:{},{}", line, hits);
+            }
+            let (hit, found) = self.summary(file);
+            let _ = writeln!(out, "LF:{}\nLH:{}\nend_of_record", found, hit);
+        }
+        out
+    }
+
+    /// Reads back the `SF` and `DA` records of an lcov tracefile, so that
+    /// coverage from earlier runs can be merged with new results. Other
+    /// records are ignored.
+    pub fn from_lcov(src: &str) -> Result<Self, String> {
+        let mut cov = Self::new();
+        let mut file = None;
+        for (i, line) in src.lines().enumerate() {
+            let bad = || format!("malformed lcov record on line {}: `{}`", i + 1, line);
+            if let Some(path) = line.strip_prefix("SF:") {
+                cov.files.entry(path.to_string()).or_default();
+                file = Some(path.to_string());
+            } else if let Some(data) = line.strip_prefix("DA:") {
+                let lines = file
+                    .as_ref()
+                    .and_then(|f| cov.files.get_mut(f))
+                    .ok_or_else(bad)?;
+                let mut fields = data.split(',');
+                let ln = fields.next().and_then(|s| s.parse().ok()).ok_or_else(bad)?;
+                let hits: u64 = fields.next().and_then(|s| s.parse().ok()).ok_or_else(bad)?;
+                *lines.entry(ln).or_insert(0) += hits;
+            } else if line == "end_of_record" {
+                file = None;
+            }
+        }
+        Ok(cov)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::parser::Parser;
+    use crate::vm::{Vm, VmConfig};
+
+    /// skips over the `load` on line 4 whenever `$0` and `$1` are equal
+    const SRC: &str = "load $2 #14
+eq $0 $1
+jmpe $2
+load $3 #1
+halt";
+
+    fn covered(r0: i32) -> Coverage {
+        let program = Parser::new(SRC).program().unwrap();
+        let mut vm = Vm::with_config(VmConfig {
+            profile: true,
+            ..Default::default()
+        });
+        vm.regs[0] = r0;
+        vm.load(program.bytes()).unwrap();
+        vm.run();
+        Coverage::from_profile("skip.lvm", &program.source_map(), vm.profile().unwrap())
+    }
+
+    #[test]
+    fn test_coverage_lines() {
+        let cov = covered(0);
+        assert_eq!(cov.summary("skip.lvm"), (4, 5));
+        assert_eq!(cov.files["skip.lvm"].get(&4), Some(&0));
+        let listing = cov.listing("skip.lvm", SRC);
+        assert_eq!(listing.lines().nth(3), Some("    #####:    4: load $3 #1"));
+        assert_eq!(listing.lines().nth(4), Some("        1:    5: halt"));
+    }
+
+    #[test]
+    fn test_coverage_merge() {
+        let mut cov = covered(0);
+        cov.merge(&covered(1));
+        assert_eq!(cov.summary("skip.lvm"), (5, 5));
+        assert_eq!(cov.files["skip.lvm"].get(&1), Some(&2));
+        assert_eq!(cov.files["skip.lvm"].get(&4), Some(&1));
+    }
+
+    #[test]
+    fn test_lcov_roundtrip() {
+        let cov = covered(0);
+        let lcov = cov.to_lcov();
+        assert!(lcov.starts_with("TN:\nSF:skip.lvm\nDA:1,1\n"));
+        assert!(lcov.ends_with("LF:5\nLH:4\nend_of_record\n"));
+        assert_eq!(Coverage::from_lcov(&lcov), Ok(cov));
+        assert!(Coverage::from_lcov("DA:1,1").is_err());
+    }
+}
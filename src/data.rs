@@ -11,6 +11,20 @@ impl std::fmt::Display for Reg {
     }
 }
 
+/// A register in the floating-point register bank, written `%fN` in assembly
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct FReg(pub u8);
+impl FReg {
+    pub fn byte(&self) -> u8 {
+        self.0
+    }
+}
+impl std::fmt::Display for FReg {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        u8::fmt(&self.0, f)
+    }
+}
+
 #[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
 pub struct Int(pub i32);
 
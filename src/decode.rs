@@ -0,0 +1,316 @@
+//! Instruction decoding.
+//!
+//! Rather than pulling operands out of the raw bytecode one byte at a time on
+//! every step, the VM decodes the whole program up front into a stream of
+//! `DecodedInstr`s whose operands are already unpacked and validated, so that
+//! the interpreter loop only needs to dispatch on them.
+use crate::bytecode::{OpCode, OperandKind};
+use crate::vm::Trap;
+
+/// A single instruction with its operands unpacked.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct DecodedInstr {
+    pub op: OpCode,
+    /// byte offset of the start of the instruction
+    pub pc: usize,
+    /// byte offset just past the end of the instruction, i.e., where
+    /// execution falls through to
+    pub next: usize,
+    /// register operands, each guaranteed to be `< 32`; unused slots are `0`
+    pub regs: [u8; 3],
+    /// the 16-bit immediate operand, if the opcode takes one
+    pub imm: u16,
+}
+
+impl DecodedInstr {
+    /// The destination of a relative jump or branch, given the value of its
+    /// register operand (ignored by branches, whose offset is immediate).
+    /// Offsets are measured from the end of the instruction, i.e., `next`.
+    pub fn relative_target(&self, val: i32) -> Option<i64> {
+        let offset = match self.op {
+            OpCode::JumpR | OpCode::JumpF => val as i64,
+            OpCode::JumpB => -(val as i64),
+            op if op.is_branch() => self.imm as i16 as i64,
+            _ => return None,
+        };
+        Some(self.next as i64 + offset)
+    }
+
+    /// The integer registers the instruction reads. These always come first
+    /// among its register operands, so this is a prefix of `regs`.
+    pub fn read(&self) -> &[u8] {
+        let n = match self.op {
+            OpCode::SetField => 3,
+            OpCode::Eq
+            | OpCode::NotEq
+            | OpCode::Greater
+            | OpCode::Less
+            | OpCode::GreaterEq
+            | OpCode::LessEq
+            | OpCode::StoreWord
+            | OpCode::Send => 2,
+            op if op.is_branch() || op.operands() == [OperandKind::Reg; 3] => 2,
+            OpCode::Load
+            | OpCode::Nil
+            | OpCode::MovRem
+            | OpCode::MovCmp
+            | OpCode::Recv
+            | OpCode::Pop
+            | OpCode::Peek => 0,
+            op if op.operands().first() == Some(&OperandKind::Reg) => 1,
+            _ => 0,
+        };
+        &self.regs[..n]
+    }
+
+    /// The integer register the instruction stores its result in, if any
+    pub fn written(&self) -> Option<usize> {
+        match self.op {
+            OpCode::Load
+            | OpCode::AddI
+            | OpCode::SubI
+            | OpCode::MulI
+            | OpCode::Inc
+            | OpCode::Dec
+            | OpCode::MovRem
+            | OpCode::MovCmp
+            | OpCode::Recv
+            | OpCode::Wait
+            | OpCode::Pop
+            | OpCode::Peek
+            | OpCode::Nil => Some(self.regs[0] as usize),
+            OpCode::Not
+            | OpCode::FloatToInt
+            | OpCode::Mov
+            | OpCode::LoadWord
+            | OpCode::Spawn
+            | OpCode::New
+            | OpCode::Len
+            | OpCode::TypeOf => Some(self.regs[1] as usize),
+            OpCode::SetField => None,
+            op if op.operands() == [OperandKind::Reg; 3] => Some(self.regs[2] as usize),
+            _ => None,
+        }
+    }
+}
+
+/// Formats the instruction in assembly syntax, e.g., `load $0 #500`, with
+/// signed immediates shown as such.
+impl std::fmt::Display for DecodedInstr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.op)?;
+        let mut regs = self.regs.iter();
+        for kind in self.op.operands() {
+            match kind {
+                OperandKind::Reg => write!(f, " ${}", regs.next().unwrap())?,
+                OperandKind::FReg => write!(f, " %f{}", regs.next().unwrap())?,
+                OperandKind::Int if self.op.has_signed_imm() => write!(f, " #{}", self.imm as i16)?,
+                OperandKind::Int => write!(f, " #{}", self.imm)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Decodes the instruction starting at `pc`, checking that its opcode is
+/// valid, that it isn't cut short by the end of the code, and that its
+/// register operands are in range.
+pub fn decode_at(code: &[u8], pc: usize) -> Result<DecodedInstr, Trap> {
+    let byte = code[pc];
+    let op = OpCode::from(byte);
+    if op == OpCode::Bad {
+        return Err(Trap::IllegalOpcode { pc, byte });
+    }
+    let layout = op.layout();
+    let next = pc + layout.width();
+    if next > code.len() {
+        return Err(Trap::Truncated { pc });
+    }
+    let mut regs = [0; 3];
+    for (i, reg) in regs.iter_mut().take(layout.regs()).enumerate() {
+        *reg = code[pc + 1 + i];
+        if *reg as usize >= 32 {
+            return Err(Trap::BadRegister { pc, reg: *reg });
+        }
+    }
+    let imm = if layout.has_imm() {
+        let at = pc + 1 + layout.regs();
+        ((code[at] as u16) << 8) | code[at + 1] as u16
+    } else {
+        0
+    };
+    Ok(DecodedInstr {
+        op,
+        pc,
+        next,
+        regs,
+        imm,
+    })
+}
+
+/// A program decoded ahead of time. Instructions are decoded back to back
+/// starting from offset `0`; bytes that fail to decode are kept as the trap
+/// they would raise and skipped over one at a time, so that the error is only
+/// reported if execution actually reaches them.
+#[derive(Clone, Debug, Default)]
+pub struct Decoded {
+    pub instrs: Vec<Result<DecodedInstr, Trap>>,
+    /// for every byte offset in the code (plus one past the end), the index
+    /// into `instrs` of the instruction starting there, or `NONE`
+    index: Vec<u32>,
+}
+
+impl Decoded {
+    const NONE: u32 = u32::MAX;
+
+    pub fn new(code: &[u8]) -> Self {
+        let mut instrs = vec![];
+        let mut index = vec![Self::NONE; code.len() + 1];
+        let mut pc = 0;
+        while pc < code.len() {
+            index[pc] = instrs.len() as u32;
+            let instr = decode_at(code, pc);
+            pc = match instr {
+                Ok(DecodedInstr { next, .. }) => next,
+                Err(Trap::BadRegister { .. }) => pc + OpCode::from(code[pc]).width(),
+                // whatever is left belongs to the truncated instruction
+                Err(Trap::Truncated { .. }) => code.len(),
+                Err(_) => pc + 1,
+            };
+            instrs.push(instr);
+        }
+        index[code.len()] = instrs.len() as u32;
+        Self { instrs, index }
+    }
+
+    /// The index of the instruction starting at byte offset `pc`, if there is
+    /// one. The end of the code maps to `instrs.len()`.
+    pub fn position(&self, pc: usize) -> Option<usize> {
+        match self.index.get(pc) {
+            Some(&i) if i != Self::NONE => Some(i as usize),
+            _ => None,
+        }
+    }
+
+    /// The index of the instruction a jump to `dest` lands on, if it lands on
+    /// one at all (the end of the code counts). Unlike `position`, this takes
+    /// a target as worked out from a register, which may be negative.
+    pub fn jump_position(&self, dest: i64) -> Option<usize> {
+        if dest < 0 {
+            return None;
+        }
+        self.position(dest as usize)
+    }
+
+    /// Whether `pc` lies on an instruction boundary (or the end of the code)
+    pub fn is_boundary(&self, pc: usize) -> bool {
+        self.position(pc).is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_operands() {
+        let code = [OpCode::Load as u8, 3, 1, 244, OpCode::Add as u8, 0, 1, 2];
+        let load = decode_at(&code, 0).unwrap();
+        assert_eq!(
+            (load.op, load.regs, load.imm, load.next),
+            (OpCode::Load, [3, 0, 0], 500, 4)
+        );
+        let add = decode_at(&code, 4).unwrap();
+        assert_eq!((add.op, add.regs, add.next), (OpCode::Add, [0, 1, 2], 8));
+        assert_eq!(load.to_string(), "load $3 #500");
+        assert_eq!(add.to_string(), "add $0 $1 $2");
+        let code = [
+            OpCode::BranchLess as u8,
+            0,
+            1,
+            255,
+            249,
+            OpCode::FLoad as u8,
+            2,
+            0,
+            3,
+        ];
+        let blt = decode_at(&code, 0).unwrap();
+        assert_eq!(blt.to_string(), "blt $0 $1 #-7");
+        assert_eq!(decode_at(&code, 5).unwrap().to_string(), "fload %f2 #3");
+    }
+
+    #[test]
+    fn test_read_and_written() {
+        let code = [
+            OpCode::Load as u8,
+            3,
+            0,
+            1,
+            OpCode::Add as u8,
+            0,
+            1,
+            2,
+            OpCode::Mov as u8,
+            4,
+            5,
+            0,
+            OpCode::SetField as u8,
+            6,
+            7,
+            8,
+            OpCode::IntToFloat as u8,
+            9,
+            1,
+            0,
+        ];
+        let decoded = Decoded::new(&code);
+        let effects = decoded
+            .instrs
+            .iter()
+            .map(|instr| {
+                let instr = instr.unwrap();
+                (instr.read().to_vec(), instr.written())
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            effects,
+            vec![
+                (vec![], Some(3)),
+                (vec![0, 1], Some(2)),
+                (vec![4], Some(5)),
+                (vec![6, 7, 8], None),
+                (vec![9], None),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        assert_eq!(
+            decode_at(&[200], 0),
+            Err(Trap::IllegalOpcode { pc: 0, byte: 200 })
+        );
+        assert_eq!(
+            decode_at(&[OpCode::Load as u8, 0, 1], 0),
+            Err(Trap::Truncated { pc: 0 })
+        );
+        assert_eq!(
+            decode_at(&[OpCode::Jump as u8, 32], 0),
+            Err(Trap::BadRegister { pc: 0, reg: 32 })
+        );
+    }
+
+    #[test]
+    fn test_decoded_index() {
+        let code = [OpCode::Jump as u8, 0, 200, OpCode::Halt as u8];
+        let decoded = Decoded::new(&code);
+        assert_eq!(decoded.instrs.len(), 3);
+        assert_eq!(decoded.position(2), Some(1));
+        assert_eq!(decoded.position(3), Some(2));
+        assert_eq!(decoded.position(4), Some(3));
+        assert!(!decoded.is_boundary(1));
+        assert_eq!(decoded.jump_position(3), Some(2));
+        assert_eq!(decoded.jump_position(-2), None);
+    }
+}
@@ -0,0 +1,46 @@
+use std::time::Instant;
+
+use super::Device;
+
+/// A monotonic clock, counting the time elapsed since the device was created.
+/// Both counters wrap around once they no longer fit in 32 bits. Writes are
+/// ignored.
+///
+/// | offset | access | register     |
+/// |--------|--------|--------------|
+/// | `0`    | r      | milliseconds |
+/// | `4`    | r      | microseconds |
+#[derive(Clone, Debug)]
+pub struct Clock {
+    start: Instant,
+}
+
+impl Clock {
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+        }
+    }
+}
+
+impl Default for Clock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Device for Clock {
+    fn size(&self) -> u32 {
+        8
+    }
+
+    fn read(&mut self, offset: u32) -> i32 {
+        let elapsed = self.start.elapsed();
+        match offset {
+            0..=3 => elapsed.as_millis() as i32,
+            _ => elapsed.as_micros() as i32,
+        }
+    }
+
+    fn write(&mut self, _offset: u32, _val: i32) {}
+}
@@ -0,0 +1,98 @@
+use std::path::PathBuf;
+
+use super::Device;
+
+/// A `width` x `height` framebuffer, one word per pixel holding its color as
+/// `0x00RRGGBB`, in row-major order. Writing to the control register just past
+/// the pixels presents the frame, saving it as a PPM image if the framebuffer
+/// was given an output file; reading it gives the number of frames presented.
+///
+/// | offset                | access | register       |
+/// |-----------------------|--------|----------------|
+/// | `4 * (y * width + x)` | r/w    | pixel `(x, y)` |
+/// | `4 * width * height`  | r/w    | control        |
+#[derive(Clone, Debug)]
+pub struct Framebuffer {
+    width: u32,
+    height: u32,
+    pixels: Vec<u32>,
+    frames: u32,
+    output: Option<PathBuf>,
+}
+
+impl Framebuffer {
+    pub fn new(width: u32, height: u32) -> Self {
+        Self {
+            width,
+            height,
+            pixels: vec![0; (width * height) as usize],
+            frames: 0,
+            output: None,
+        }
+    }
+
+    /// Saves each presented frame to `path`, overwriting the previous one.
+    pub fn with_output(self, path: impl Into<PathBuf>) -> Self {
+        Self {
+            output: Some(path.into()),
+            ..self
+        }
+    }
+
+    pub fn pixel(&self, x: u32, y: u32) -> u32 {
+        self.pixels[(y * self.width + x) as usize]
+    }
+
+    /// The current frame as a binary (`P6`) PPM image
+    pub fn to_ppm(&self) -> Vec<u8> {
+        let mut ppm = format!("P6\n{} {}\n255\n", self.width, self.height).into_bytes();
+        for px in &self.pixels {
+            ppm.extend(&px.to_be_bytes()[1..]);
+        }
+        ppm
+    }
+
+    fn present(&mut self) {
+        self.frames += 1;
+        if let Some(path) = &self.output {
+            if let Err(e) = std::fs::write(path, self.to_ppm()) {
+                eprintln!("unable to write frame to `{}`: {}", path.display(), e);
+            }
+        }
+    }
+}
+
+impl Device for Framebuffer {
+    fn size(&self) -> u32 {
+        4 * (self.width * self.height + 1)
+    }
+
+    fn read(&mut self, offset: u32) -> i32 {
+        match self.pixels.get((offset / 4) as usize) {
+            Some(px) => *px as i32,
+            None => self.frames as i32,
+        }
+    }
+
+    fn write(&mut self, offset: u32, val: i32) {
+        match self.pixels.get_mut((offset / 4) as usize) {
+            Some(px) => *px = val as u32 & 0x00ff_ffff,
+            None => self.present(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_framebuffer_ppm() {
+        let mut fb = Framebuffer::new(2, 1);
+        fb.write(4, 0x7f12_3456);
+        fb.write(8, 0);
+        assert_eq!(fb.pixel(1, 0), 0x12_3456);
+        assert_eq!(fb.read(8), 1);
+        assert_eq!(fb.to_ppm(), b"P6\n2 1\n255\n\0\0\0\x12\x34\x56".to_vec());
+    }
+}
@@ -0,0 +1,119 @@
+//! Memory-mapped I/O.
+//!
+//! Besides RAM, the VM's address space can hold *devices*: each device is
+//! mapped over a range of addresses, and the `LDW`/`STW` instructions that
+//! touch those addresses are routed to the device instead of to memory. This
+//! lets programs do I/O without needing an opcode per device. Device ranges
+//! take precedence over RAM, so a device mapped inside of RAM shadows the
+//! memory underneath it.
+//!
+//! Devices are registered with `VmBuilder::device`. The standard devices
+//! provided here are usually mapped at the addresses below, which sit at the
+//! top of the default 64 KiB address space so that they can be reached with a
+//! single `LOAD`:
+//!
+//! | device        | base     |
+//! |---------------|----------|
+//! | `Framebuffer` | `0xc000` |
+//! | `Uart`        | `0xf000` |
+//! | `Rng`         | `0xf010` |
+//! | `Clock`       | `0xf020` |
+//!
+//! leaving room for a framebuffer of up to 3071 pixels, such as the 64 x 32
+//! one `lil-vm run --framebuffer` maps.
+mod clock;
+mod framebuffer;
+mod rng;
+mod uart;
+
+pub use clock::Clock;
+pub use framebuffer::Framebuffer;
+pub use rng::Rng;
+#[cfg(test)]
+pub(crate) use uart::Shared;
+pub use uart::Uart;
+
+pub const FRAMEBUFFER_BASE: u32 = 0xc000;
+pub const UART_BASE: u32 = 0xf000;
+pub const RNG_BASE: u32 = 0xf010;
+pub const CLOCK_BASE: u32 = 0xf020;
+
+/// A memory-mapped device. Devices are accessed a word at a time, with
+/// `offset` being the address accessed relative to where the device is mapped
+/// (which is always less than `size`). Devices must be `Send` so that the
+/// machine they are attached to can be moved to another thread.
+pub trait Device: std::fmt::Debug + Send {
+    /// Number of bytes of address space the device occupies
+    fn size(&self) -> u32;
+    fn read(&mut self, offset: u32) -> i32;
+    fn write(&mut self, offset: u32, val: i32);
+}
+
+/// The devices mapped into a VM's address space.
+#[derive(Debug, Default)]
+pub struct Bus {
+    /// `(base address, device)`, in ascending order of base address
+    devices: Vec<(u32, Box<dyn Device>)>,
+}
+
+impl Bus {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Maps `device` at `base`.
+    ///
+    /// # Panics
+    /// If the device would overlap one that is already mapped, or extend past
+    /// the end of the 32-bit address space.
+    pub fn attach(&mut self, base: u32, device: Box<dyn Device>) {
+        let end = base as u64 + device.size() as u64;
+        assert!(end <= 1 << 32, "device at 0x{:x} doesn't fit", base);
+        let at = self.devices.partition_point(|(b, _)| *b < base);
+        let clashes = |(b, d): &(u32, Box<dyn Device>)| {
+            (*b as u64) < end && (base as u64) < *b as u64 + d.size() as u64
+        };
+        assert!(
+            !self.devices.iter().any(clashes),
+            "device at 0x{:x} overlaps another device",
+            base
+        );
+        self.devices.insert(at, (base, device));
+    }
+
+    /// The device mapped over `addr`, and the offset of `addr` within it
+    pub fn find(&mut self, addr: u32) -> Option<(&mut dyn Device, u32)> {
+        let at = self.devices.partition_point(|(b, _)| *b <= addr);
+        let (base, device) = self.devices.get_mut(at.checked_sub(1)?)?;
+        let offset = addr - *base;
+        if offset < device.size() {
+            Some((device.as_mut(), offset))
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bus_routing() {
+        let mut bus = Bus::new();
+        bus.attach(0x20, Box::new(Rng::new(1)));
+        bus.attach(0x10, Box::new(Clock::new()));
+        assert!(bus.find(0x0f).is_none());
+        assert_eq!(bus.find(0x14).map(|(_, off)| off), Some(4));
+        assert_eq!(bus.find(0x20).map(|(_, off)| off), Some(0));
+        assert!(bus.find(0x18).is_none());
+    }
+
+    #[test]
+    #[should_panic(expected = "overlaps")]
+    fn test_bus_overlap() {
+        let mut bus = Bus::new();
+        bus.attach(0x10, Box::new(Clock::new()));
+        bus.attach(0x14, Box::new(Rng::new(1)));
+    }
+}
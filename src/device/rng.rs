@@ -0,0 +1,72 @@
+use super::Device;
+
+/// A pseudo-random number generator (xorshift32). Reading gives the next
+/// number in the sequence; writing reseeds it.
+///
+/// | offset | access | register          |
+/// |--------|--------|-------------------|
+/// | `0`    | r/w    | next number, seed |
+#[derive(Clone, Debug)]
+pub struct Rng {
+    state: u32,
+}
+
+impl Rng {
+    pub fn new(seed: u32) -> Self {
+        let mut rng = Self { state: 0 };
+        rng.seed(seed);
+        rng
+    }
+
+    /// Seeds the generator from the system clock.
+    pub fn from_time() -> Self {
+        let nanos = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |d| d.subsec_nanos());
+        Self::new(nanos)
+    }
+
+    /// xorshift gets stuck on a zero state, so a zero seed is swapped out
+    fn seed(&mut self, seed: u32) {
+        self.state = if seed == 0 { 0x9e37_79b9 } else { seed };
+    }
+
+    fn next(&mut self) -> u32 {
+        let mut x = self.state;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.state = x;
+        x
+    }
+}
+
+impl Device for Rng {
+    fn size(&self) -> u32 {
+        4
+    }
+
+    fn read(&mut self, _offset: u32) -> i32 {
+        self.next() as i32
+    }
+
+    fn write(&mut self, _offset: u32, val: i32) {
+        self.seed(val as u32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rng_reseed() {
+        let mut rng = Rng::new(7);
+        let first = (0..4).map(|_| rng.read(0)).collect::<Vec<_>>();
+        rng.write(0, 7);
+        let again = (0..4).map(|_| rng.read(0)).collect::<Vec<_>>();
+        assert_eq!(first, again);
+        assert_ne!(first[0], first[1]);
+        assert_ne!(Rng::new(0).read(0), 0);
+    }
+}
@@ -0,0 +1,99 @@
+use std::io::{self, Read, Write};
+
+use super::Device;
+
+/// A console UART. Writing sends the low byte of the value to the output,
+/// while reading takes the next byte of input, or `-1` once the input is
+/// exhausted.
+///
+/// | offset | access | register |
+/// |--------|--------|----------|
+/// | `0`    | r/w    | data     |
+pub struct Uart {
+    input: Box<dyn Read + Send>,
+    output: Box<dyn Write + Send>,
+}
+
+impl Uart {
+    pub fn new(input: impl Read + Send + 'static, output: impl Write + Send + 'static) -> Self {
+        Self {
+            input: Box::new(input),
+            output: Box::new(output),
+        }
+    }
+
+    /// A UART connected to the process's stdin and stdout
+    pub fn stdio() -> Self {
+        Self::new(io::stdin(), io::stdout())
+    }
+}
+
+impl std::fmt::Debug for Uart {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Uart").finish_non_exhaustive()
+    }
+}
+
+impl Device for Uart {
+    fn size(&self) -> u32 {
+        4
+    }
+
+    fn read(&mut self, _offset: u32) -> i32 {
+        let mut byte = [0u8; 1];
+        match self.input.read(&mut byte) {
+            Ok(1) => byte[0] as i32,
+            _ => -1,
+        }
+    }
+
+    fn write(&mut self, _offset: u32, val: i32) {
+        // there's nowhere to report a console that went away to, so output
+        // errors are dropped like they would be on real hardware
+        let _ = self
+            .output
+            .write_all(&[val as u8])
+            .and_then(|_| self.output.flush());
+    }
+}
+
+/// An output for a `Uart` in tests, which keeps hold of everything written
+/// to it while a clone of it is owned by the UART
+#[cfg(test)]
+#[derive(Clone, Default)]
+pub(crate) struct Shared(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+#[cfg(test)]
+impl Shared {
+    /// Everything written so far
+    pub(crate) fn contents(&self) -> Vec<u8> {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+impl Write for Shared {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_uart_echo() {
+        let out = Shared::default();
+        let mut uart = Uart::new(&b"hi"[..], out.clone());
+        while let c @ 0..=255 = uart.read(0) {
+            uart.write(0, c + 0x100);
+        }
+        assert_eq!(uart.read(0), -1);
+        assert_eq!(out.contents(), b"hi");
+    }
+}
@@ -0,0 +1,387 @@
+//! Core files written by the VM when it traps.
+//!
+//! A core file is a small binary snapshot of the machine at the moment it
+//! stopped: the trap itself, the registers and flags, a window of the bytecode
+//! around the faulting instruction and the tail of the execution trace. All
+//! multi-byte values are stored little endian.
+use std::{
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::Path,
+};
+
+use crate::value::Type;
+use crate::vm::{TraceEntry, Trap, Vm};
+
+pub const MAGIC: &[u8; 8] = b"LVMCORE\0";
+pub const VERSION: u8 = 5;
+/// How many code bytes are captured on either side of the faulting pc.
+pub const CODE_WINDOW: usize = 32;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct CoreDump {
+    pub trap: Trap,
+    pub pc: usize,
+    pub regs: [i32; 32],
+    /// the type of the value held in each register
+    pub tags: [Type; 32],
+    pub fregs: [f64; 32],
+    pub rem: u32,
+    pub cmp: bool,
+    pub overflow: bool,
+    pub carry: bool,
+    /// byte offset of `code[0]` within the original program
+    pub code_start: usize,
+    pub code: Vec<u8>,
+    pub trace: Vec<TraceEntry>,
+}
+
+impl CoreDump {
+    pub fn capture(vm: &Vm, trap: Trap) -> Self {
+        let code = vm.instructions();
+        let start = trap.pc().saturating_sub(CODE_WINDOW);
+        let end = (trap.pc() + CODE_WINDOW).min(code.len());
+        Self {
+            trap,
+            pc: vm.pc(),
+            regs: vm.regs,
+            tags: vm.tags,
+            fregs: vm.fregs,
+            rem: vm.rem(),
+            cmp: vm.cmp(),
+            overflow: vm.overflow(),
+            carry: vm.carry(),
+            code_start: start,
+            code: code[start.min(end)..end].to_vec(),
+            trace: vm.trace().copied().collect(),
+        }
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let mut out = BufWriter::new(File::create(path)?);
+        self.write_to(&mut out)?;
+        out.flush()
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::read_from(&mut BufReader::new(File::open(path)?))
+    }
+
+    pub fn write_to(&self, w: &mut impl Write) -> io::Result<()> {
+        w.write_all(MAGIC)?;
+        w.write_all(&[VERSION])?;
+        write_trap(w, &self.trap)?;
+        write_u64(w, self.pc as u64)?;
+        for r in self.regs {
+            w.write_all(&r.to_le_bytes())?;
+        }
+        w.write_all(&self.tags.map(|t| t.tag()))?;
+        for f in self.fregs {
+            write_u64(w, f.to_bits())?;
+        }
+        w.write_all(&self.rem.to_le_bytes())?;
+        w.write_all(&[self.cmp as u8, self.overflow as u8, self.carry as u8])?;
+        write_u64(w, self.code_start as u64)?;
+        write_u64(w, self.code.len() as u64)?;
+        w.write_all(&self.code)?;
+        write_u64(w, self.trace.len() as u64)?;
+        for entry in &self.trace {
+            write_u64(w, entry.pc as u64)?;
+            w.write_all(&[entry.byte])?;
+        }
+        Ok(())
+    }
+
+    pub fn read_from(r: &mut impl Read) -> io::Result<Self> {
+        let mut magic = [0u8; 8];
+        r.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(invalid("not a lil-vm core file"));
+        }
+        let version = read_u8(r)?;
+        if version != VERSION {
+            return Err(invalid(format!("unsupported core version {}", version)));
+        }
+        let trap = read_trap(r)?;
+        let pc = read_u64(r)? as usize;
+        let mut regs = [0; 32];
+        for reg in regs.iter_mut() {
+            *reg = read_i32(r)?;
+        }
+        let mut tags = [Type::Int; 32];
+        for tag in tags.iter_mut() {
+            *tag = read_type(r)?;
+        }
+        let mut fregs = [0.0; 32];
+        for freg in fregs.iter_mut() {
+            *freg = f64::from_bits(read_u64(r)?);
+        }
+        let rem = read_i32(r)? as u32;
+        let cmp = read_u8(r)? != 0;
+        let overflow = read_u8(r)? != 0;
+        let carry = read_u8(r)? != 0;
+        let code_start = read_u64(r)? as usize;
+        // a capture never holds more than the window around the pc, so
+        // anything longer is corrupt; checking first keeps a bad length from
+        // allocating an arbitrary amount
+        let len = read_u64(r)?;
+        if len > 2 * CODE_WINDOW as u64 {
+            return Err(invalid(format!("code window of {} bytes is too long", len)));
+        }
+        let mut code = vec![0; len as usize];
+        r.read_exact(&mut code)?;
+        let trace = (0..read_u64(r)?)
+            .map(|_| {
+                Ok(TraceEntry {
+                    pc: read_u64(r)? as usize,
+                    byte: read_u8(r)?,
+                })
+            })
+            .collect::<io::Result<_>>()?;
+        Ok(Self {
+            trap,
+            pc,
+            regs,
+            tags,
+            fregs,
+            rem,
+            cmp,
+            overflow,
+            carry,
+            code_start,
+            code,
+            trace,
+        })
+    }
+}
+
+fn invalid(msg: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.into())
+}
+
+fn write_u64(w: &mut impl Write, n: u64) -> io::Result<()> {
+    w.write_all(&n.to_le_bytes())
+}
+
+fn read_u8(r: &mut impl Read) -> io::Result<u8> {
+    let mut buf = [0u8; 1];
+    r.read_exact(&mut buf)?;
+    Ok(buf[0])
+}
+
+fn read_i32(r: &mut impl Read) -> io::Result<i32> {
+    let mut buf = [0u8; 4];
+    r.read_exact(&mut buf)?;
+    Ok(i32::from_le_bytes(buf))
+}
+
+fn read_type(r: &mut impl Read) -> io::Result<Type> {
+    let tag = read_u8(r)?;
+    Type::from_tag(tag).ok_or_else(|| invalid(format!("unknown value type {}", tag)))
+}
+
+fn read_u64(r: &mut impl Read) -> io::Result<u64> {
+    let mut buf = [0u8; 8];
+    r.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+fn write_trap(w: &mut impl Write, trap: &Trap) -> io::Result<()> {
+    match *trap {
+        Trap::IllegalOpcode { pc, byte } => {
+            w.write_all(&[0])?;
+            write_u64(w, pc as u64)?;
+            w.write_all(&[byte])
+        }
+        Trap::DivideByZero { pc } => {
+            w.write_all(&[1])?;
+            write_u64(w, pc as u64)
+        }
+        Trap::JumpOutOfRange { pc, dest } => {
+            w.write_all(&[2])?;
+            write_u64(w, pc as u64)?;
+            w.write_all(&dest.to_le_bytes())
+        }
+        Trap::MisalignedJump { pc, dest } => {
+            w.write_all(&[3])?;
+            write_u64(w, pc as u64)?;
+            write_u64(w, dest as u64)
+        }
+        Trap::Truncated { pc } => {
+            w.write_all(&[4])?;
+            write_u64(w, pc as u64)
+        }
+        Trap::BadRegister { pc, reg } => {
+            w.write_all(&[5])?;
+            write_u64(w, pc as u64)?;
+            w.write_all(&[reg])
+        }
+        Trap::Overflow { pc } => {
+            w.write_all(&[6])?;
+            write_u64(w, pc as u64)
+        }
+        Trap::BadVector { pc, line } => {
+            w.write_all(&[7])?;
+            write_u64(w, pc as u64)?;
+            w.write_all(&line.to_le_bytes())
+        }
+        Trap::StrayIret { pc } => {
+            w.write_all(&[8])?;
+            write_u64(w, pc as u64)
+        }
+        Trap::MemoryFault { pc, addr } => {
+            w.write_all(&[9])?;
+            write_u64(w, pc as u64)?;
+            w.write_all(&addr.to_le_bytes())
+        }
+        Trap::NotAReference { pc, reg } => {
+            w.write_all(&[10])?;
+            write_u64(w, pc as u64)?;
+            w.write_all(&[reg])
+        }
+        Trap::FieldOutOfRange { pc, field } => {
+            w.write_all(&[11])?;
+            write_u64(w, pc as u64)?;
+            w.write_all(&field.to_le_bytes())
+        }
+        Trap::OutOfMemory { pc, len } => {
+            w.write_all(&[12])?;
+            write_u64(w, pc as u64)?;
+            w.write_all(&len.to_le_bytes())
+        }
+        Trap::BadObjectKind { pc, kind } => {
+            w.write_all(&[13])?;
+            write_u64(w, pc as u64)?;
+            w.write_all(&kind.to_le_bytes())
+        }
+        Trap::StackOverflow { pc } => {
+            w.write_all(&[14])?;
+            write_u64(w, pc as u64)
+        }
+        Trap::StackUnderflow { pc } => {
+            w.write_all(&[15])?;
+            write_u64(w, pc as u64)
+        }
+        Trap::TypeError { pc, reg, found } => {
+            w.write_all(&[16])?;
+            write_u64(w, pc as u64)?;
+            w.write_all(&[reg, found.tag()])
+        }
+    }
+}
+
+fn read_trap(r: &mut impl Read) -> io::Result<Trap> {
+    let tag = read_u8(r)?;
+    let pc = read_u64(r)? as usize;
+    match tag {
+        0 => Ok(Trap::IllegalOpcode {
+            pc,
+            byte: read_u8(r)?,
+        }),
+        1 => Ok(Trap::DivideByZero { pc }),
+        2 => Ok(Trap::JumpOutOfRange {
+            pc,
+            dest: read_u64(r)? as i64,
+        }),
+        3 => Ok(Trap::MisalignedJump {
+            pc,
+            dest: read_u64(r)? as usize,
+        }),
+        4 => Ok(Trap::Truncated { pc }),
+        5 => Ok(Trap::BadRegister {
+            pc,
+            reg: read_u8(r)?,
+        }),
+        6 => Ok(Trap::Overflow { pc }),
+        7 => {
+            let mut line = [0u8; 2];
+            r.read_exact(&mut line)?;
+            Ok(Trap::BadVector {
+                pc,
+                line: u16::from_le_bytes(line),
+            })
+        }
+        8 => Ok(Trap::StrayIret { pc }),
+        9 => Ok(Trap::MemoryFault {
+            pc,
+            addr: read_i32(r)? as u32,
+        }),
+        10 => Ok(Trap::NotAReference {
+            pc,
+            reg: read_u8(r)?,
+        }),
+        11 => Ok(Trap::FieldOutOfRange {
+            pc,
+            field: read_i32(r)?,
+        }),
+        12 => Ok(Trap::OutOfMemory {
+            pc,
+            len: read_i32(r)?,
+        }),
+        13 => {
+            let mut kind = [0u8; 2];
+            r.read_exact(&mut kind)?;
+            Ok(Trap::BadObjectKind {
+                pc,
+                kind: u16::from_le_bytes(kind),
+            })
+        }
+        14 => Ok(Trap::StackOverflow { pc }),
+        15 => Ok(Trap::StackUnderflow { pc }),
+        16 => Ok(Trap::TypeError {
+            pc,
+            reg: read_u8(r)?,
+            found: read_type(r)?,
+        }),
+        t => Err(invalid(format!("unknown trap kind {}", t))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::OpCode;
+
+    #[test]
+    fn test_core_roundtrip() {
+        let mut vm = Vm::new();
+        vm.regs[0] = 42;
+        vm.set_code(vec![OpCode::Div as u8, 0, 1, 2]);
+        vm.run();
+        let dump = vm.core_dump(vm.trap().unwrap());
+        assert_eq!(dump.trap, Trap::DivideByZero { pc: 0 });
+        assert_eq!(dump.code, vm.instructions());
+
+        let mut bytes = vec![];
+        dump.write_to(&mut bytes).unwrap();
+        let read = CoreDump::read_from(&mut bytes.as_slice()).unwrap();
+        assert_eq!(read, dump)
+    }
+
+    #[test]
+    fn test_core_bad_code_len() {
+        let mut vm = Vm::new();
+        vm.set_code(vec![OpCode::Div as u8, 0, 1, 2]);
+        vm.run();
+        let dump = vm.core_dump(vm.trap().unwrap());
+        let mut bytes = vec![];
+        dump.write_to(&mut bytes).unwrap();
+        // the code length comes just before the code and the trace
+        let at = bytes.len() - (8 + 9 * dump.trace.len()) - dump.code.len() - 8;
+
+        let mut oversized = bytes.clone();
+        oversized[at..at + 8].copy_from_slice(&u64::MAX.to_le_bytes());
+        let err = CoreDump::read_from(&mut oversized.as_slice()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        let truncated = &bytes[..at + 4];
+        let err = CoreDump::read_from(&mut &truncated[..]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn test_core_bad_magic() {
+        let bytes = b"NOTACORE\x01".to_vec();
+        assert!(CoreDump::read_from(&mut bytes.as_slice()).is_err())
+    }
+}
@@ -0,0 +1,220 @@
+//! The garbage-collected object heap.
+//!
+//! Besides the raw bytes of RAM, programs can allocate *objects* with `NEW`:
+//! arrays, strings and records, each a fixed number of fields. A register
+//! holding an object holds a *reference* to it, which is just the object's
+//! handle, tagged so that the VM (and the collector) can tell it apart from an
+//! integer that happens to have the same value (see the `value` module). Only
+//! `NEW`, `MOV`, `GETF` and `POP` can produce a reference.
+//!
+//! Objects are reclaimed by a mark-and-sweep collector, whose roots are the
+//! references held in registers and on the stack. A collection runs whenever
+//! the number of fields allocated since the last one reaches a threshold,
+//! which is raised after each collection to twice the size of what survived.
+use std::collections::VecDeque;
+
+use crate::value::Value;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ObjKind {
+    /// fields hold any value
+    Array,
+    /// fields hold bytes; storing anything else keeps only the low byte
+    String,
+    /// like an array, but meant to be accessed by constant field numbers
+    Record,
+}
+
+impl ObjKind {
+    pub fn from_tag(tag: u16) -> Option<Self> {
+        match tag {
+            0 => Some(ObjKind::Array),
+            1 => Some(ObjKind::String),
+            2 => Some(ObjKind::Record),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Object {
+    pub kind: ObjKind,
+    pub fields: Vec<Value>,
+    marked: bool,
+}
+
+/// A snapshot of the heap's bookkeeping
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct HeapStats {
+    /// objects currently allocated, reachable or not
+    pub objects: usize,
+    /// fields across all allocated objects
+    pub fields: usize,
+    /// objects allocated over the heap's lifetime
+    pub allocated: u64,
+    /// objects reclaimed over the heap's lifetime
+    pub freed: u64,
+    /// number of collections run
+    pub collections: u64,
+}
+
+impl std::fmt::Display for HeapStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} objects ({} fields) live, {} allocated, {} freed in {} collections",
+            self.objects, self.fields, self.allocated, self.freed, self.collections
+        )
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Heap {
+    /// indexed by handle; freed objects leave a `None` behind
+    objects: Vec<Option<Object>>,
+    /// handles of freed objects, to be reused
+    free: Vec<u32>,
+    /// the most fields that may be allocated at once
+    limit: usize,
+    /// collect once this many fields have been allocated since the last
+    /// collection
+    threshold: usize,
+    since_gc: usize,
+    stats: HeapStats,
+}
+
+impl Heap {
+    /// The threshold for the first collection
+    const INITIAL_THRESHOLD: usize = 1024;
+
+    /// A heap holding at most `limit` fields at a time
+    pub fn new(limit: usize) -> Self {
+        Self {
+            objects: vec![],
+            free: vec![],
+            limit,
+            threshold: Self::INITIAL_THRESHOLD,
+            since_gc: 0,
+            stats: HeapStats::default(),
+        }
+    }
+
+    pub fn stats(&self) -> HeapStats {
+        self.stats
+    }
+
+    /// Whether allocating `len` more fields should be preceded by a
+    /// collection
+    pub fn wants_collection(&self, len: usize) -> bool {
+        self.since_gc + len > self.threshold || self.stats.fields + len > self.limit
+    }
+
+    /// Allocates an object of `len` zeroed fields, returning its handle, or
+    /// `None` if it wouldn't fit within the heap's limit.
+    pub fn alloc(&mut self, kind: ObjKind, len: usize) -> Option<u32> {
+        if self.stats.fields + len > self.limit {
+            return None;
+        }
+        let obj = Object {
+            kind,
+            fields: vec![Value::default(); len],
+            marked: false,
+        };
+        let handle = match self.free.pop() {
+            Some(handle) => {
+                self.objects[handle as usize] = Some(obj);
+                handle
+            }
+            None => {
+                self.objects.push(Some(obj));
+                (self.objects.len() - 1) as u32
+            }
+        };
+        self.since_gc += len;
+        self.stats.objects += 1;
+        self.stats.fields += len;
+        self.stats.allocated += 1;
+        Some(handle)
+    }
+
+    pub fn get(&self, handle: u32) -> Option<&Object> {
+        self.objects.get(handle as usize)?.as_ref()
+    }
+
+    pub fn get_mut(&mut self, handle: u32) -> Option<&mut Object> {
+        self.objects.get_mut(handle as usize)?.as_mut()
+    }
+
+    /// Frees every object that can't be reached from `roots`, returning how
+    /// many were freed.
+    pub fn collect(&mut self, roots: impl IntoIterator<Item = u32>) -> usize {
+        // mark
+        let mut work = roots.into_iter().collect::<VecDeque<_>>();
+        while let Some(handle) = work.pop_front() {
+            if let Some(obj) = self.get_mut(handle) {
+                if !obj.marked {
+                    obj.marked = true;
+                    work.extend(obj.fields.iter().filter_map(|f| match f {
+                        Value::Ref(r) => Some(*r),
+                        _ => None,
+                    }));
+                }
+            }
+        }
+        // sweep
+        let mut freed = 0;
+        for (handle, slot) in self.objects.iter_mut().enumerate() {
+            match slot {
+                Some(obj) if obj.marked => obj.marked = false,
+                Some(obj) => {
+                    self.stats.fields -= obj.fields.len();
+                    *slot = None;
+                    self.free.push(handle as u32);
+                    freed += 1;
+                }
+                None => {}
+            }
+        }
+        self.stats.objects -= freed;
+        self.stats.freed += freed as u64;
+        self.stats.collections += 1;
+        self.since_gc = 0;
+        self.threshold = (2 * self.stats.fields).max(Self::INITIAL_THRESHOLD);
+        freed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_collect_unreachable() {
+        let mut heap = Heap::new(100);
+        let a = heap.alloc(ObjKind::Array, 2).unwrap();
+        let b = heap.alloc(ObjKind::Record, 3).unwrap();
+        let c = heap.alloc(ObjKind::String, 4).unwrap();
+        // a -> b -> a is a cycle, only reachable through a
+        heap.get_mut(a).unwrap().fields[0] = Value::Ref(b);
+        heap.get_mut(b).unwrap().fields[2] = Value::Ref(a);
+        assert_eq!(heap.collect(vec![a]), 1);
+        assert!(heap.get(c).is_none());
+        assert_eq!(heap.stats().fields, 5);
+        // the cycle goes once nothing points into it, and handles get reused
+        assert_eq!(heap.collect(vec![]), 2);
+        assert_eq!(heap.alloc(ObjKind::Array, 1), Some(b));
+        let stats = heap.stats();
+        assert_eq!((stats.objects, stats.allocated, stats.freed), (1, 4, 3));
+        assert_eq!(stats.collections, 2);
+    }
+
+    #[test]
+    fn test_heap_limit() {
+        let mut heap = Heap::new(10);
+        assert!(heap.alloc(ObjKind::Array, 8).is_some());
+        assert!(heap.wants_collection(3));
+        assert_eq!(heap.alloc(ObjKind::Array, 3), None);
+        heap.collect(vec![]);
+        assert!(heap.alloc(ObjKind::Array, 10).is_some());
+    }
+}
@@ -0,0 +1,122 @@
+//! Interrupts.
+//!
+//! The VM has a small interrupt controller with `VECTORS` interrupt lines.
+//! Programs install a handler for a line with `VEC`, and enable or disable
+//! interrupts as a whole with `EI` and `DI`. Interrupts start out disabled.
+//!
+//! A line becomes *pending* when it is raised, either by the host through
+//! `Vm::interrupt` or by the built-in timer, which raises `TIMER` every `N`
+//! executed instructions when `VmConfig::timer` is `Some(N)`. Pending
+//! interrupts are checked between instructions: if interrupts are enabled, the
+//! lowest pending line with a handler installed is taken, which saves the pc
+//! and flags, disables interrupts, and jumps to the handler. `IRET` restores
+//! what was saved and re-enables interrupts.
+//!
+//! There is only room to save a single interrupted state, so handlers can't be
+//! interrupted themselves; anything raised in the meantime stays pending until
+//! the handler returns.
+
+/// Number of interrupt lines, and so the size of the vector table
+pub const VECTORS: usize = 8;
+/// The line raised by the timer
+pub const TIMER: usize = 0;
+
+/// What is saved when an interrupt is taken, and restored by `IRET`
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Saved {
+    /// where execution resumes after the handler returns
+    pub pc: usize,
+    pub cmp: bool,
+    pub overflow: bool,
+    pub carry: bool,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Interrupts {
+    /// whether pending interrupts may be taken, set by `EI` and cleared by
+    /// `DI` (and on entry to a handler)
+    pub enabled: bool,
+    /// bitmask of raised but not yet taken lines
+    pub pending: u8,
+    /// the handler address for each line, if one was installed
+    pub vectors: [Option<usize>; VECTORS],
+    /// the interrupted state, while a handler is running
+    pub saved: Option<Saved>,
+    /// number of instructions executed so far, which drives the timer
+    pub clock: u64,
+}
+
+impl Interrupts {
+    /// Makes `line` pending, returning `false` (and raising nothing) if
+    /// there is no such line.
+    pub fn raise(&mut self, line: usize) -> bool {
+        if line >= VECTORS {
+            return false;
+        }
+        self.pending |= 1 << line;
+        true
+    }
+
+    pub fn is_pending(&self, line: usize) -> bool {
+        line < VECTORS && self.pending & (1 << line) != 0
+    }
+
+    /// Advances the clock by one instruction, raising the timer line if the
+    /// given period has elapsed.
+    pub fn step(&mut self, timer: Option<u64>) {
+        self.clock += 1;
+        if matches!(timer, Some(n) if n > 0 && self.clock.is_multiple_of(n)) {
+            self.raise(TIMER);
+        }
+    }
+
+    /// Picks the interrupt to take next, if any, clearing it from the pending
+    /// set and returning its handler address.
+    pub fn take(&mut self) -> Option<usize> {
+        if !self.enabled || self.saved.is_some() {
+            return None;
+        }
+        let line = (0..VECTORS).find(|l| self.is_pending(*l) && self.vectors[*l].is_some())?;
+        self.pending &= !(1 << line);
+        self.vectors[line]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_take_lowest_handled() {
+        let mut irq = Interrupts {
+            enabled: true,
+            ..Default::default()
+        };
+        irq.vectors[3] = Some(30);
+        irq.vectors[5] = Some(50);
+        irq.raise(1);
+        irq.raise(5);
+        irq.raise(3);
+        assert_eq!(irq.take(), Some(30));
+        assert_eq!(irq.take(), Some(50));
+        // line 1 has no handler, so it stays pending
+        assert_eq!(irq.take(), None);
+        assert!(irq.is_pending(1));
+        // there is no such line
+        assert!(!irq.raise(VECTORS));
+        assert!(!irq.raise(100));
+        assert!(!irq.is_pending(100));
+        assert_eq!(irq.pending, 1 << 1);
+    }
+
+    #[test]
+    fn test_timer_period() {
+        let mut irq = Interrupts::default();
+        for _ in 0..2 {
+            irq.step(Some(3));
+        }
+        assert!(!irq.is_pending(TIMER));
+        irq.step(Some(3));
+        assert!(irq.is_pending(TIMER));
+    }
+}
@@ -0,0 +1,619 @@
+use std::collections::HashMap;
+use std::ops::RangeInclusive;
+
+use crate::assembler::parser::{Instruction, Operand, Program};
+use crate::bytecode::OpCode;
+use crate::data::{Int, Reg};
+use crate::device::UART_BASE;
+
+use super::parser::{BinOp, Expr, Function, Module, Stmt, StmtKind, UnOp};
+use super::Error;
+
+/// Registers handed out to variables and temporaries
+const POOL: RangeInclusive<u8> = 1..=27;
+/// How many registers of the pool are kept back for temporaries; variables
+/// declared once no more than this many are free live on the stack instead
+const TEMPS: usize = 4;
+/// Where functions leave their result
+const RESULT: u8 = 0;
+/// Holds a spilled left operand once it is popped back off the stack
+const SCRATCH: u8 = 28;
+/// Holds constants that only live for a single instruction or two
+const CONST: u8 = 29;
+/// Holds the address of a jump or call
+const ADDR: u8 = 30;
+
+/// An operand of an instruction that hasn't been laid out yet
+#[derive(Copy, Clone, Debug)]
+enum Arg {
+    Reg(u8),
+    Int(i32),
+    /// the offset of a label, relative for branches and absolute otherwise
+    Label(usize),
+}
+
+#[derive(Clone, Debug)]
+enum Item {
+    Label(usize),
+    Instr(usize, OpCode, Vec<Arg>),
+}
+
+/// Where a variable lives
+#[derive(Copy, Clone, Debug)]
+enum Loc {
+    Reg(u8),
+    /// the stack entry at this index within the frame
+    Stack(usize),
+}
+
+/// The result of an expression: the register holding it, and whether that is
+/// a temporary, to be freed once used (rather than a variable's register)
+#[derive(Copy, Clone, Debug)]
+struct Val {
+    reg: u8,
+    temp: bool,
+}
+
+/// The state of the function being compiled
+#[derive(Clone, Debug)]
+struct Frame {
+    /// variables in scope, innermost scope last
+    scopes: Vec<Vec<(String, Loc)>>,
+    /// registers of the pool not holding a variable or temporary
+    free: Vec<u8>,
+    /// number of stack entries above the start of the frame; a function's
+    /// frame starts with its arguments and return address
+    depth: usize,
+    /// the depth on entry, which `return` unwinds the stack back to
+    base: usize,
+    /// whether this is the top level, where `return` halts instead
+    main: bool,
+}
+
+impl Frame {
+    fn new(base: usize, main: bool) -> Self {
+        Self {
+            scopes: vec![vec![]],
+            // lowest registers are handed out first
+            free: POOL.rev().collect(),
+            depth: base,
+            base,
+            main,
+        }
+    }
+}
+
+/// Compiles a parsed `Module` into a `Program`.
+///
+/// Top-level statements come first, starting at offset `0` and ending with a
+/// `HALT`, followed by each function. Arguments are passed on the stack, with
+/// the return address on top, and results come back in `$0`. Callees are free
+/// to clobber every register, so callers push whatever registers are in use
+/// before a call and pop them afterwards.
+///
+/// Within a function, variables and temporaries are allocated from `$1` to
+/// `$27`. Once those run out, variables are spilled to stack slots accessed
+/// with `PEEK`/`POKE`, and the left operand of a binary operation is pushed
+/// while its right operand is evaluated.
+pub struct Codegen<'m> {
+    module: &'m Module,
+    items: Vec<Item>,
+    labels: usize,
+    /// function name => (label, number of parameters)
+    functions: HashMap<&'m str, (usize, usize)>,
+    /// the label of the routine `print` calls and the line of the first
+    /// `print`, which it is attributed to, once one is compiled
+    print: Option<(usize, usize)>,
+    frame: Frame,
+    /// the line of the statement being compiled
+    line: usize,
+}
+
+impl<'m> Codegen<'m> {
+    pub fn new(module: &'m Module) -> Result<Self, Error> {
+        let mut cg = Self {
+            module,
+            items: vec![],
+            labels: 0,
+            functions: HashMap::new(),
+            print: None,
+            frame: Frame::new(0, true),
+            line: 1,
+        };
+        for f in &module.functions {
+            let label = cg.label();
+            if cg
+                .functions
+                .insert(&f.name, (label, f.params.len()))
+                .is_some()
+            {
+                return Err(Error::DuplicateFunction(f.line, f.name.clone()));
+            }
+        }
+        Ok(cg)
+    }
+
+    pub fn program(mut self) -> Result<Program, Error> {
+        let module = self.module;
+        self.stmts(&module.main)?;
+        self.emit(OpCode::Halt, &[]);
+        for f in &module.functions {
+            self.function(f)?;
+        }
+        if let Some((label, line)) = self.print {
+            self.line = line;
+            self.place(label);
+            self.print_routine();
+        }
+        self.finish()
+    }
+
+    fn label(&mut self) -> usize {
+        self.labels += 1;
+        self.labels - 1
+    }
+
+    fn place(&mut self, label: usize) {
+        self.items.push(Item::Label(label));
+    }
+
+    fn emit(&mut self, op: OpCode, args: &[Arg]) {
+        self.items.push(Item::Instr(self.line, op, args.to_vec()));
+    }
+
+    /// Takes a free register. Expressions only ever need one more register
+    /// than they were started with, since left operands are spilled when the
+    /// pool is empty, and every statement starts with at least `TEMPS` free.
+    fn alloc(&mut self) -> u8 {
+        self.frame.free.pop().expect("register pool exhausted")
+    }
+
+    fn release(&mut self, val: Val) {
+        if val.temp {
+            self.frame.free.push(val.reg);
+        }
+    }
+
+    fn push(&mut self, reg: u8) {
+        self.emit(OpCode::Push, &[Arg::Reg(reg)]);
+        self.frame.depth += 1;
+    }
+
+    fn pop(&mut self, reg: u8) {
+        self.emit(OpCode::Pop, &[Arg::Reg(reg)]);
+        self.frame.depth -= 1;
+    }
+
+    /// The `PEEK`/`POKE` offset of the stack slot at `index`
+    fn offset(&self, index: usize) -> Arg {
+        Arg::Int((self.frame.depth - 1 - index) as i32)
+    }
+
+    fn jump(&mut self, label: usize) {
+        self.emit(OpCode::Load, &[Arg::Reg(ADDR), Arg::Label(label)]);
+        self.emit(OpCode::Jump, &[Arg::Reg(ADDR)]);
+    }
+
+    fn lookup(&self, name: &str) -> Result<Loc, Error> {
+        self.frame
+            .scopes
+            .iter()
+            .rev()
+            .flat_map(|scope| scope.iter().rev())
+            .find(|(var, _)| var == name)
+            .map(|(_, loc)| *loc)
+            .ok_or_else(|| Error::UndefinedVariable(self.line, name.to_string()))
+    }
+
+    /// Declares a variable, keeping `val` in a register if there are enough
+    /// to go around, and on the stack otherwise.
+    fn declare(&mut self, name: &str, val: Val) {
+        let loc = if val.temp && self.frame.free.len() >= TEMPS {
+            Loc::Reg(val.reg)
+        } else if !val.temp && self.frame.free.len() > TEMPS {
+            let reg = self.alloc();
+            self.emit(OpCode::Mov, &[Arg::Reg(val.reg), Arg::Reg(reg)]);
+            Loc::Reg(reg)
+        } else {
+            self.push(val.reg);
+            self.release(val);
+            Loc::Stack(self.frame.depth - 1)
+        };
+        let scope = self.frame.scopes.last_mut().unwrap();
+        scope.push((name.to_string(), loc));
+    }
+
+    fn function(&mut self, f: &Function) -> Result<(), Error> {
+        self.line = f.line;
+        let (label, arity) = self.functions[f.name.as_str()];
+        self.place(label);
+        self.frame = Frame::new(arity + 1, false);
+        for (i, param) in f.params.iter().enumerate() {
+            let loc = if self.frame.free.len() > TEMPS {
+                let reg = self.alloc();
+                let at = self.offset(i);
+                self.emit(OpCode::Peek, &[Arg::Reg(reg), at]);
+                Loc::Reg(reg)
+            } else {
+                Loc::Stack(i)
+            };
+            self.frame.scopes[0].push((param.clone(), loc));
+        }
+        self.stmts(&f.body)?;
+        // falling off the end returns `0`
+        self.emit(OpCode::Load, &[Arg::Reg(RESULT), Arg::Int(0)]);
+        self.emit(OpCode::Return, &[]);
+        Ok(())
+    }
+
+    fn stmts(&mut self, stmts: &[Stmt]) -> Result<(), Error> {
+        self.frame.scopes.push(vec![]);
+        for stmt in stmts {
+            self.line = stmt.line;
+            self.stmt(stmt)?;
+        }
+        // spilled variables are always on top of the stack by the end of
+        // their scope
+        for (_, loc) in self.frame.scopes.pop().unwrap().into_iter().rev() {
+            match loc {
+                Loc::Reg(reg) => self.frame.free.push(reg),
+                Loc::Stack(_) => self.pop(SCRATCH),
+            }
+        }
+        Ok(())
+    }
+
+    fn stmt(&mut self, stmt: &Stmt) -> Result<(), Error> {
+        match &stmt.kind {
+            StmtKind::Let(name, e) => {
+                let val = self.expr(e)?;
+                self.declare(name, val);
+            }
+            StmtKind::Assign(name, e) => {
+                let loc = self.lookup(name)?;
+                let val = self.expr(e)?;
+                match loc {
+                    Loc::Reg(reg) if reg == val.reg => {}
+                    Loc::Reg(reg) => self.emit(OpCode::Mov, &[Arg::Reg(val.reg), Arg::Reg(reg)]),
+                    Loc::Stack(index) => {
+                        let at = self.offset(index);
+                        self.emit(OpCode::Poke, &[Arg::Reg(val.reg), at]);
+                    }
+                }
+                self.release(val);
+            }
+            StmtKind::If(cond, then, otherwise) => {
+                let (other, end) = (self.label(), self.label());
+                self.branch_unless(cond, other)?;
+                self.stmts(then)?;
+                if otherwise.is_empty() {
+                    self.place(other);
+                } else {
+                    self.jump(end);
+                    self.place(other);
+                    self.stmts(otherwise)?;
+                    self.place(end);
+                }
+            }
+            StmtKind::While(cond, body) => {
+                let (top, end) = (self.label(), self.label());
+                self.place(top);
+                self.branch_unless(cond, end)?;
+                self.stmts(body)?;
+                self.jump(top);
+                self.place(end);
+            }
+            StmtKind::Print(e) => {
+                let label = match self.print {
+                    Some((label, _)) => label,
+                    None => {
+                        let label = self.label();
+                        self.print = Some((label, self.line));
+                        label
+                    }
+                };
+                self.call(label, std::slice::from_ref(e), false)?;
+            }
+            StmtKind::Return(_) if self.frame.main => self.emit(OpCode::Halt, &[]),
+            StmtKind::Return(e) => {
+                match e {
+                    Some(e) => {
+                        let val = self.expr(e)?;
+                        self.emit(OpCode::Mov, &[Arg::Reg(val.reg), Arg::Reg(RESULT)]);
+                        self.release(val);
+                    }
+                    None => self.emit(OpCode::Load, &[Arg::Reg(RESULT), Arg::Int(0)]),
+                }
+                // code following the return still expects the stack as it
+                // was, so the depth is left alone
+                for _ in self.frame.base..self.frame.depth {
+                    self.emit(OpCode::Pop, &[Arg::Reg(SCRATCH)]);
+                }
+                self.emit(OpCode::Return, &[]);
+            }
+            StmtKind::Expr(e) => {
+                let val = self.expr(e)?;
+                self.release(val);
+            }
+        }
+        Ok(())
+    }
+
+    /// Jumps to `label` if `cond` is zero, comparing directly with a branch
+    /// where possible
+    fn branch_unless(&mut self, cond: &Expr, label: usize) -> Result<(), Error> {
+        if let Expr::Binary(op, lhs, rhs) = cond {
+            let branch = match op {
+                BinOp::Eq => Some(OpCode::BranchNeq),
+                BinOp::NotEq => Some(OpCode::BranchEq),
+                BinOp::Less => Some(OpCode::BranchGreaterEq),
+                BinOp::LessEq => Some(OpCode::BranchGreater),
+                BinOp::Greater => Some(OpCode::BranchLessEq),
+                BinOp::GreaterEq => Some(OpCode::BranchLess),
+                _ => None,
+            };
+            if let Some(branch) = branch {
+                let (x, y) = self.operands(lhs, rhs)?;
+                self.emit(
+                    branch,
+                    &[Arg::Reg(x.reg), Arg::Reg(y.reg), Arg::Label(label)],
+                );
+                self.release(x);
+                self.release(y);
+                return Ok(());
+            }
+        }
+        let val = self.expr(cond)?;
+        self.emit(OpCode::Load, &[Arg::Reg(CONST), Arg::Int(0)]);
+        self.emit(
+            OpCode::BranchEq,
+            &[Arg::Reg(val.reg), Arg::Reg(CONST), Arg::Label(label)],
+        );
+        self.release(val);
+        Ok(())
+    }
+
+    /// Evaluates both operands of a binary operation. If the left operand
+    /// took the last free register, it is pushed while the right operand is
+    /// evaluated and comes back in `SCRATCH`.
+    fn operands(&mut self, lhs: &Expr, rhs: &Expr) -> Result<(Val, Val), Error> {
+        let x = self.expr(lhs)?;
+        if !(x.temp && self.frame.free.is_empty()) {
+            return Ok((x, self.expr(rhs)?));
+        }
+        self.push(x.reg);
+        self.release(x);
+        let y = self.expr(rhs)?;
+        self.pop(SCRATCH);
+        let x = Val {
+            reg: SCRATCH,
+            temp: false,
+        };
+        Ok((x, y))
+    }
+
+    /// A register to store the result of an operation on `vals` in, reusing
+    /// one of their temporaries if there is one and freeing the rest
+    fn dest(&mut self, vals: &[Val]) -> u8 {
+        let reg = match vals.iter().find(|v| v.temp) {
+            Some(v) => v.reg,
+            None => self.alloc(),
+        };
+        for val in vals.iter().filter(|v| v.reg != reg) {
+            self.release(*val);
+        }
+        reg
+    }
+
+    fn expr(&mut self, e: &Expr) -> Result<Val, Error> {
+        let reg = match e {
+            Expr::Int(n) => {
+                let reg = self.alloc();
+                self.load_int(reg, *n);
+                reg
+            }
+            Expr::Var(name) => match self.lookup(name)? {
+                Loc::Reg(reg) => return Ok(Val { reg, temp: false }),
+                Loc::Stack(index) => {
+                    let reg = self.alloc();
+                    let at = self.offset(index);
+                    self.emit(OpCode::Peek, &[Arg::Reg(reg), at]);
+                    reg
+                }
+            },
+            Expr::Unary(op, inner) => {
+                let val = self.expr(inner)?;
+                let dst = self.dest(&[val]);
+                match op {
+                    UnOp::Neg => {
+                        self.emit(OpCode::Load, &[Arg::Reg(CONST), Arg::Int(0)]);
+                        self.emit(
+                            OpCode::Sub,
+                            &[Arg::Reg(CONST), Arg::Reg(val.reg), Arg::Reg(dst)],
+                        );
+                    }
+                    UnOp::Not => {
+                        self.emit(OpCode::CmpI, &[Arg::Reg(val.reg), Arg::Int(0)]);
+                        self.emit(OpCode::MovCmp, &[Arg::Reg(dst)]);
+                    }
+                }
+                dst
+            }
+            Expr::Binary(op, lhs, rhs) => {
+                let (x, y) = self.operands(lhs, rhs)?;
+                let dst = self.dest(&[y, x]);
+                let args = [Arg::Reg(x.reg), Arg::Reg(y.reg), Arg::Reg(dst)];
+                match op {
+                    BinOp::Add => self.emit(OpCode::Add, &args),
+                    BinOp::Sub => self.emit(OpCode::Sub, &args),
+                    BinOp::Mul => self.emit(OpCode::Mul, &args),
+                    BinOp::Div => self.emit(OpCode::Div, &args),
+                    BinOp::Rem => {
+                        self.emit(OpCode::Div, &args);
+                        self.emit(OpCode::MovRem, &[Arg::Reg(dst)]);
+                    }
+                    _ => {
+                        let cmp = match op {
+                            BinOp::Eq => OpCode::Eq,
+                            BinOp::NotEq => OpCode::NotEq,
+                            BinOp::Less => OpCode::Less,
+                            BinOp::LessEq => OpCode::LessEq,
+                            BinOp::Greater => OpCode::Greater,
+                            _ => OpCode::GreaterEq,
+                        };
+                        self.emit(cmp, &args[..2]);
+                        self.emit(OpCode::MovCmp, &[Arg::Reg(dst)]);
+                    }
+                }
+                dst
+            }
+            Expr::Call(name, args) => {
+                let (label, arity) = match self.functions.get(name.as_str()) {
+                    Some(f) => *f,
+                    None => return Err(Error::UndefinedFunction(self.line, name.clone())),
+                };
+                if arity != args.len() {
+                    return Err(Error::Arity(self.line, name.clone(), arity, args.len()));
+                }
+                return Ok(self.call(label, args, true)?.unwrap());
+            }
+        };
+        Ok(Val { reg, temp: true })
+    }
+
+    /// Calls the function at `label`, saving the registers in use around the
+    /// call, and returns a temporary holding its result if `result` is set
+    fn call(&mut self, label: usize, args: &[Expr], result: bool) -> Result<Option<Val>, Error> {
+        let saved = POOL
+            .filter(|reg| !self.frame.free.contains(reg))
+            .collect::<Vec<_>>();
+        for reg in &saved {
+            self.push(*reg);
+        }
+        for arg in args {
+            let val = self.expr(arg)?;
+            self.push(val.reg);
+            self.release(val);
+        }
+        self.emit(OpCode::Load, &[Arg::Reg(ADDR), Arg::Label(label)]);
+        self.emit(OpCode::Call, &[Arg::Reg(ADDR)]);
+        for _ in args {
+            self.pop(SCRATCH);
+        }
+        for reg in saved.iter().rev() {
+            self.pop(*reg);
+        }
+        if !result {
+            return Ok(None);
+        }
+        let reg = self.alloc();
+        self.emit(OpCode::Mov, &[Arg::Reg(RESULT), Arg::Reg(reg)]);
+        Ok(Some(Val { reg, temp: true }))
+    }
+
+    fn load_int(&mut self, reg: u8, n: i32) {
+        let r = Arg::Reg(reg);
+        match n {
+            0..=0xffff => self.emit(OpCode::Load, &[r, Arg::Int(n)]),
+            -0x8000..=-1 => {
+                self.emit(OpCode::Load, &[r, Arg::Int(0)]);
+                self.emit(OpCode::AddI, &[r, Arg::Int(n)]);
+            }
+            _ => {
+                let c = Arg::Reg(CONST);
+                self.emit(OpCode::Load, &[r, Arg::Int((n as u32 >> 16) as i32)]);
+                self.emit(OpCode::Load, &[c, Arg::Int(16)]);
+                self.emit(OpCode::Shl, &[r, c, r]);
+                self.emit(OpCode::Load, &[c, Arg::Int(n & 0xffff)]);
+                self.emit(OpCode::Or, &[r, c, r]);
+            }
+        }
+    }
+
+    /// Writes its argument to the UART in decimal, followed by a newline.
+    /// Digits are worked out least significant first and pushed, so that
+    /// they can be popped off in the right order; negative numbers are never
+    /// negated, so that `i32::MIN` works too.
+    fn print_routine(&mut self) {
+        let [val, uart, ten, count, digit, zero, sign] = [1, 2, 3, 4, 5, 6, 7].map(Arg::Reg);
+        let (digits, out) = (self.label(), self.label());
+        self.emit(OpCode::Peek, &[val, Arg::Int(1)]);
+        self.emit(OpCode::Load, &[uart, Arg::Int(UART_BASE as i32)]);
+        self.emit(OpCode::Load, &[ten, Arg::Int(10)]);
+        self.emit(OpCode::Load, &[count, Arg::Int(0)]);
+        self.emit(OpCode::Load, &[zero, Arg::Int(0)]);
+        self.emit(OpCode::Load, &[sign, Arg::Int(1)]);
+        self.emit(OpCode::BranchGreaterEq, &[val, zero, Arg::Label(digits)]);
+        self.emit(OpCode::Load, &[digit, Arg::Int(b'-' as i32)]);
+        self.emit(OpCode::StoreWord, &[digit, uart]);
+        self.emit(OpCode::Load, &[sign, Arg::Int(0)]);
+        self.emit(OpCode::Dec, &[sign]);
+        self.place(digits);
+        self.emit(OpCode::Div, &[val, ten, val]);
+        self.emit(OpCode::MovRem, &[digit]);
+        self.emit(OpCode::Mul, &[digit, sign, digit]);
+        self.emit(OpCode::AddI, &[digit, Arg::Int(b'0' as i32)]);
+        self.emit(OpCode::Push, &[digit]);
+        self.emit(OpCode::Inc, &[count]);
+        self.emit(OpCode::BranchNeq, &[val, zero, Arg::Label(digits)]);
+        self.place(out);
+        self.emit(OpCode::Pop, &[digit]);
+        self.emit(OpCode::StoreWord, &[digit, uart]);
+        self.emit(OpCode::Dec, &[count]);
+        self.emit(OpCode::BranchNeq, &[count, zero, Arg::Label(out)]);
+        self.emit(OpCode::Load, &[digit, Arg::Int(b'\n' as i32)]);
+        self.emit(OpCode::StoreWord, &[digit, uart]);
+        self.emit(OpCode::Return, &[]);
+    }
+
+    /// Lays out the instructions, resolving labels to offsets
+    fn finish(self) -> Result<Program, Error> {
+        let mut offsets = vec![0; self.labels];
+        let mut at = 0;
+        for item in &self.items {
+            match item {
+                Item::Label(label) => offsets[*label] = at,
+                Item::Instr(_, op, _) => at += op.width(),
+            }
+        }
+        let mut instrs = vec![];
+        at = 0;
+        for item in &self.items {
+            let (line, op, args) = match item {
+                Item::Instr(line, op, args) => (*line, *op, args),
+                Item::Label(_) => continue,
+            };
+            let next = at + op.width();
+            let operands = args
+                .iter()
+                .map(|arg| {
+                    let (n, range) = match *arg {
+                        Arg::Reg(reg) => return Ok(Operand::Reg(Reg(reg))),
+                        Arg::Int(n) => (n as i64, i16::MIN as i64..=u16::MAX as i64),
+                        Arg::Label(label) if op.is_branch() => (
+                            offsets[label] as i64 - next as i64,
+                            i16::MIN as i64..=i16::MAX as i64,
+                        ),
+                        Arg::Label(label) => (offsets[label] as i64, 0..=u16::MAX as i64),
+                    };
+                    if range.contains(&n) {
+                        Ok(Operand::Int(Int(n as i32)))
+                    } else {
+                        Err(Error::Range(line))
+                    }
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            let instr = Instruction::new(line, op, &operands);
+            if args.iter().any(|arg| matches!(arg, Arg::Label(_))) {
+                instrs.push(instr.as_address());
+            } else {
+                instrs.push(instr);
+            }
+            at = next;
+        }
+        Ok(Program {
+            instrs,
+            errors: vec![],
+        })
+    }
+}
@@ -0,0 +1,175 @@
+use super::Error;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Tok {
+    Int(i32),
+    Ident(String),
+    Let,
+    If,
+    Else,
+    While,
+    Fn,
+    Return,
+    Print,
+    LParen,
+    RParen,
+    LBrace,
+    RBrace,
+    Comma,
+    Semi,
+    Assign,
+    Plus,
+    Minus,
+    Star,
+    Slash,
+    Percent,
+    Bang,
+    EqEq,
+    NotEq,
+    Less,
+    LessEq,
+    Greater,
+    GreaterEq,
+    Eof,
+}
+
+impl std::fmt::Display for Tok {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            Tok::Int(n) => return write!(f, "{}", n),
+            Tok::Ident(name) => return write!(f, "{}", name),
+            Tok::Let => "let",
+            Tok::If => "if",
+            Tok::Else => "else",
+            Tok::While => "while",
+            Tok::Fn => "fn",
+            Tok::Return => "return",
+            Tok::Print => "print",
+            Tok::LParen => "(",
+            Tok::RParen => ")",
+            Tok::LBrace => "{",
+            Tok::RBrace => "}",
+            Tok::Comma => ",",
+            Tok::Semi => ";",
+            Tok::Assign => "=",
+            Tok::Plus => "+",
+            Tok::Minus => "-",
+            Tok::Star => "*",
+            Tok::Slash => "/",
+            Tok::Percent => "%",
+            Tok::Bang => "!",
+            Tok::EqEq => "==",
+            Tok::NotEq => "!=",
+            Tok::Less => "<",
+            Tok::LessEq => "<=",
+            Tok::Greater => ">",
+            Tok::GreaterEq => ">=",
+            Tok::Eof => "end of input",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+/// Splits `src` into tokens, each paired with the (1-based) line it starts
+/// on. Comments run from `//` to the end of the line.
+pub fn tokenize(src: &str) -> Result<Vec<(usize, Tok)>, Error> {
+    let mut toks = vec![];
+    let mut line = 1;
+    let mut chars = src.chars().peekable();
+    while let Some(c) = chars.next() {
+        let tok = match c {
+            '\n' => {
+                line += 1;
+                continue;
+            }
+            c if c.is_whitespace() => continue,
+            '/' if chars.peek() == Some(&'/') => {
+                while chars.next_if(|c| *c != '\n').is_some() {}
+                continue;
+            }
+            '0'..='9' => {
+                let mut n = c.to_digit(10).unwrap() as i64;
+                while let Some(d) = chars.next_if(char::is_ascii_digit) {
+                    n = n * 10 + d.to_digit(10).unwrap() as i64;
+                    if n > i32::MAX as i64 {
+                        return Err(Error::IntRange(line));
+                    }
+                }
+                Tok::Int(n as i32)
+            }
+            c if c.is_alphabetic() || c == '_' => {
+                let mut word = c.to_string();
+                while let Some(c) = chars.next_if(|c| c.is_alphanumeric() || *c == '_') {
+                    word.push(c);
+                }
+                match word.as_str() {
+                    "let" => Tok::Let,
+                    "if" => Tok::If,
+                    "else" => Tok::Else,
+                    "while" => Tok::While,
+                    "fn" => Tok::Fn,
+                    "return" => Tok::Return,
+                    "print" => Tok::Print,
+                    _ => Tok::Ident(word),
+                }
+            }
+            '(' => Tok::LParen,
+            ')' => Tok::RParen,
+            '{' => Tok::LBrace,
+            '}' => Tok::RBrace,
+            ',' => Tok::Comma,
+            ';' => Tok::Semi,
+            '+' => Tok::Plus,
+            '-' => Tok::Minus,
+            '*' => Tok::Star,
+            '/' => Tok::Slash,
+            '%' => Tok::Percent,
+            '=' if chars.next_if_eq(&'=').is_some() => Tok::EqEq,
+            '=' => Tok::Assign,
+            '!' if chars.next_if_eq(&'=').is_some() => Tok::NotEq,
+            '!' => Tok::Bang,
+            '<' if chars.next_if_eq(&'=').is_some() => Tok::LessEq,
+            '<' => Tok::Less,
+            '>' if chars.next_if_eq(&'=').is_some() => Tok::GreaterEq,
+            '>' => Tok::Greater,
+            c => return Err(Error::BadChar(line, c)),
+        };
+        toks.push((line, tok));
+    }
+    toks.push((line, Tok::Eof));
+    Ok(toks)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tokenize() {
+        let toks = tokenize("let x = 10; // ten\nif x <= 3 { print -x; }").unwrap();
+        let (lines, toks): (Vec<_>, Vec<_>) = toks.into_iter().unzip();
+        assert_eq!(
+            toks,
+            vec![
+                Tok::Let,
+                Tok::Ident("x".into()),
+                Tok::Assign,
+                Tok::Int(10),
+                Tok::Semi,
+                Tok::If,
+                Tok::Ident("x".into()),
+                Tok::LessEq,
+                Tok::Int(3),
+                Tok::LBrace,
+                Tok::Print,
+                Tok::Minus,
+                Tok::Ident("x".into()),
+                Tok::Semi,
+                Tok::RBrace,
+                Tok::Eof,
+            ]
+        );
+        assert_eq!((lines[4], lines[5]), (1, 2));
+        assert_eq!(tokenize("let x = 1 # 2;"), Err(Error::BadChar(1, '#')));
+    }
+}
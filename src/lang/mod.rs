@@ -0,0 +1,253 @@
+//! A small high-level language that compiles to lil-vm bytecode.
+//!
+//! Programs are made of integer expressions, variables, `if`/`else`,
+//! `while`, functions and `print`, which writes a number in decimal to the
+//! UART at `UART_BASE`:
+//!
+//! ```txt
+//! fn fib(n) {
+//!     if n < 2 { return n; }
+//!     return fib(n - 1) + fib(n - 2);
+//! }
+//!
+//! let i = 0;
+//! while i < 10 {
+//!     print fib(i);
+//!     i = i + 1;
+//! }
+//! ```
+//!
+//! Statements outside of any function make up the main program, which runs
+//! from the top. Conditions are true when non-zero, and comparisons evaluate
+//! to `1` or `0`. A program compiles to the assembler's `Program`, with each
+//! instruction attributed to the line of the statement it was compiled from,
+//! so that source maps, profiles and coverage work just like they do for
+//! assembly. See `codegen` for the calling convention and register
+//! allocation.
+pub mod codegen;
+pub mod lexer;
+pub mod parser;
+
+use crate::assembler::parser::Program;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// a character that doesn't start any token, on the given line
+    BadChar(usize, char),
+    /// an integer literal too large for a register
+    IntRange(usize),
+    /// expected the first string, but found the second
+    Unexpected(usize, String, String),
+    UndefinedVariable(usize, String),
+    UndefinedFunction(usize, String),
+    DuplicateFunction(usize, String),
+    /// a call to the named function expecting the first number of arguments
+    /// with the second
+    Arity(usize, String, usize, usize),
+    /// a jump or stack offset on the given line doesn't fit in its immediate,
+    /// i.e., the program or a function's frame is too large
+    Range(usize),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::BadChar(line, c) => write!(f, "line {}: unexpected character `{}`", line, c),
+            Error::IntRange(line) => {
+                write!(f, "line {}: integer literal doesn't fit in 32 bits", line)
+            }
+            Error::Unexpected(line, expected, found) => write!(
+                f,
+                "line {}: expected {}, but found `{}` instead",
+                line, expected, found
+            ),
+            Error::UndefinedVariable(line, name) => {
+                write!(f, "line {}: variable `{}` is never declared", line, name)
+            }
+            Error::UndefinedFunction(line, name) => {
+                write!(f, "line {}: function `{}` is never declared", line, name)
+            }
+            Error::DuplicateFunction(line, name) => {
+                write!(f, "line {}: function `{}` is already declared", line, name)
+            }
+            Error::Arity(line, name, expected, found) => write!(
+                f,
+                "line {}: `{}` takes {} arguments, but was given {}",
+                line, name, expected, found
+            ),
+            Error::Range(line) => write!(
+                f,
+                "line {}: jump or stack offset out of range of its immediate",
+                line
+            ),
+        }
+    }
+}
+
+/// Compiles the program in `src`.
+pub fn compile(src: &str) -> Result<Program, Error> {
+    let module = parser::Parser::new(src)?.module()?;
+    codegen::Codegen::new(&module)?.program()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::{Shared, Uart};
+    use crate::vm::Vm;
+    use std::io;
+
+    /// Compiles and runs `src`, returning what it printed
+    fn run(src: &str) -> String {
+        run_program(&compile(src).unwrap())
+    }
+
+    fn run_program(program: &Program) -> String {
+        let out = Shared::default();
+        let mut vm = Vm::builder()
+            .device(
+                crate::device::UART_BASE,
+                Uart::new(io::empty(), out.clone()),
+            )
+            .build();
+        vm.load(program.bytes()).unwrap();
+        vm.run();
+        assert_eq!(vm.trap(), None);
+        assert!(vm.stack().is_empty());
+        String::from_utf8(out.contents()).unwrap()
+    }
+
+    #[test]
+    fn test_arithmetic() {
+        let src = "print 1 + 2 * 3;\nprint (1 + 2) * 3;\nprint -7 / 2;\nprint 7 % 3;\n\
+                   print 100000 * 3;\nprint 2147483647 + 1;\nprint 3 < 4;\nprint !(3 < 4);";
+        assert_eq!(run(src), "7\n9\n-3\n1\n300000\n-2147483648\n1\n0\n");
+    }
+
+    #[test]
+    fn test_control_flow() {
+        let src = "
+            let i = 0;
+            let evens = 0;
+            while i < 10 {
+                if i % 2 == 0 {
+                    evens = evens + 1;
+                } else if i == 5 {
+                    print i;
+                }
+                i = i + 1;
+            }
+            print evens;
+            return;
+            print 1;
+        ";
+        assert_eq!(run(src), "5\n5\n");
+    }
+
+    #[test]
+    fn test_functions() {
+        let src = "
+            fn fib(n) {
+                if n < 2 { return n; }
+                return fib(n - 1) + fib(n - 2);
+            }
+            fn sub(a, b) { return a - b; }
+            fn nothing() {}
+            let x = 3;
+            print fib(10) + x;
+            print sub(x, 10);
+            print nothing();
+        ";
+        assert_eq!(run(src), "58\n-7\n0\n");
+    }
+
+    #[test]
+    fn test_spilling() {
+        // more variables than registers, each read back after the rest are
+        // declared
+        let mut src = String::new();
+        for i in 0..40 {
+            src += &format!("let v{} = {};\n", i, i);
+        }
+        src += "let sum = 0;\n";
+        for i in 0..40 {
+            src += &format!("sum = sum + v{};\nv{} = 0;\n", i, i);
+        }
+        src += "print sum;\nprint v39;\n";
+        // and an expression nested deeper than there are registers
+        let deep = (1..=40).fold("0".to_string(), |acc, n| format!("{} + ({}", n, acc));
+        src += &format!("print {}{};\n", deep, ")".repeat(40));
+        assert_eq!(run(&src), "780\n0\n820\n");
+    }
+
+    #[test]
+    fn test_spilled_params() {
+        let params = (0..30).map(|i| format!("p{}", i)).collect::<Vec<_>>();
+        let args = (0..30).map(|i| i.to_string()).collect::<Vec<_>>();
+        let src = format!(
+            "fn f({}) {{ let x = p0 + p29; return x * p28; }}\nprint f({});",
+            params.join(", "),
+            args.join(", ")
+        );
+        assert_eq!(run(&src), "812\n");
+    }
+
+    #[test]
+    fn test_optimized() {
+        let src = "
+            fn fact(n) {
+                if n <= 1 { return 1; }
+                return n * fact(n - 1);
+            }
+            let i = 0;
+            while i < 8 {
+                print fact(i) + (1 + 2);
+                i = i + 1;
+            }
+        ";
+        let mut program = compile(src).unwrap();
+        let expected = run_program(&program);
+        let mut direct = program.clone();
+        assert!(crate::assembler::optimize::Optimizer::default().optimize(&mut program) > 0);
+        assert_eq!(run_program(&program), expected);
+        let optimizer = crate::assembler::optimize::Optimizer {
+            direct_jumps: true,
+            ..Default::default()
+        };
+        optimizer.optimize(&mut direct);
+        assert_eq!(run_program(&direct), expected);
+    }
+
+    #[test]
+    fn test_compile_errors() {
+        assert_eq!(
+            compile("print x;"),
+            Err(Error::UndefinedVariable(1, "x".into()))
+        );
+        assert_eq!(
+            compile("fn f(a) {}\n\nf(1, 2);"),
+            Err(Error::Arity(3, "f".into(), 1, 2))
+        );
+        assert_eq!(
+            compile("fn f() {}\nfn f() {}"),
+            Err(Error::DuplicateFunction(2, "f".into()))
+        );
+        assert_eq!(
+            compile("g();"),
+            Err(Error::UndefinedFunction(1, "g".into()))
+        );
+        // a variable goes out of scope at the end of its block
+        assert_eq!(
+            compile("if 1 {\nlet y = 1;\n}\nprint y;"),
+            Err(Error::UndefinedVariable(4, "y".into()))
+        );
+    }
+
+    #[test]
+    fn test_source_lines() {
+        let program = compile("let x = 1;\n\nprint x;").unwrap();
+        let lines = program.instrs.iter().map(|i| i.line()).collect::<Vec<_>>();
+        assert_eq!(lines[0], 1);
+        assert!(lines[1..].iter().all(|line| *line == 3));
+    }
+}
@@ -0,0 +1,402 @@
+use super::lexer::{tokenize, Tok};
+use super::Error;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+    Eq,
+    NotEq,
+    Less,
+    LessEq,
+    Greater,
+    GreaterEq,
+}
+
+impl BinOp {
+    pub fn is_comparison(&self) -> bool {
+        !matches!(
+            self,
+            BinOp::Add | BinOp::Sub | BinOp::Mul | BinOp::Div | BinOp::Rem
+        )
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum UnOp {
+    Neg,
+    Not,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Expr {
+    Int(i32),
+    Var(String),
+    Unary(UnOp, Box<Expr>),
+    Binary(BinOp, Box<Expr>, Box<Expr>),
+    Call(String, Vec<Expr>),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum StmtKind {
+    Let(String, Expr),
+    Assign(String, Expr),
+    If(Expr, Vec<Stmt>, Vec<Stmt>),
+    While(Expr, Vec<Stmt>),
+    Print(Expr),
+    Return(Option<Expr>),
+    Expr(Expr),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Stmt {
+    /// the line the statement starts on
+    pub line: usize,
+    pub kind: StmtKind,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Function {
+    pub line: usize,
+    pub name: String,
+    pub params: Vec<String>,
+    pub body: Vec<Stmt>,
+}
+
+/// A whole program: its function definitions, and the statements outside of
+/// any function, which are run in order from the top.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Module {
+    pub functions: Vec<Function>,
+    pub main: Vec<Stmt>,
+}
+
+/// A recursive descent parser over the tokens of a whole program.
+#[derive(Clone, Debug)]
+pub struct Parser {
+    toks: Vec<(usize, Tok)>,
+    at: usize,
+}
+
+impl Parser {
+    pub fn new(src: &str) -> Result<Self, Error> {
+        Ok(Self {
+            toks: tokenize(src)?,
+            at: 0,
+        })
+    }
+
+    fn peek(&self) -> &Tok {
+        &self.toks[self.at].1
+    }
+
+    fn line(&self) -> usize {
+        self.toks[self.at].0
+    }
+
+    fn bump(&mut self) -> Tok {
+        let tok = self.toks[self.at].1.clone();
+        // the trailing `Eof` is never consumed
+        if self.at + 1 < self.toks.len() {
+            self.at += 1;
+        }
+        tok
+    }
+
+    fn eat(&mut self, tok: &Tok) -> bool {
+        if self.peek() == tok {
+            self.bump();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, tok: Tok) -> Result<(), Error> {
+        if self.eat(&tok) {
+            Ok(())
+        } else {
+            Err(self.unexpected(&tok.to_string()))
+        }
+    }
+
+    fn unexpected(&self, expected: &str) -> Error {
+        Error::Unexpected(self.line(), expected.to_string(), self.peek().to_string())
+    }
+
+    fn ident(&mut self) -> Result<String, Error> {
+        match self.peek() {
+            Tok::Ident(name) => {
+                let name = name.clone();
+                self.bump();
+                Ok(name)
+            }
+            _ => Err(self.unexpected("a name")),
+        }
+    }
+
+    pub fn module(&mut self) -> Result<Module, Error> {
+        let mut module = Module::default();
+        while *self.peek() != Tok::Eof {
+            if *self.peek() == Tok::Fn {
+                module.functions.push(self.function()?);
+            } else {
+                module.main.push(self.stmt()?);
+            }
+        }
+        Ok(module)
+    }
+
+    fn function(&mut self) -> Result<Function, Error> {
+        let line = self.line();
+        self.expect(Tok::Fn)?;
+        let name = self.ident()?;
+        self.expect(Tok::LParen)?;
+        let mut params = vec![];
+        if !self.eat(&Tok::RParen) {
+            loop {
+                params.push(self.ident()?);
+                if self.eat(&Tok::RParen) {
+                    break;
+                }
+                self.expect(Tok::Comma)?;
+            }
+        }
+        let body = self.block()?;
+        Ok(Function {
+            line,
+            name,
+            params,
+            body,
+        })
+    }
+
+    fn block(&mut self) -> Result<Vec<Stmt>, Error> {
+        self.expect(Tok::LBrace)?;
+        let mut stmts = vec![];
+        while !self.eat(&Tok::RBrace) {
+            if *self.peek() == Tok::Eof {
+                return Err(self.unexpected("}"));
+            }
+            stmts.push(self.stmt()?);
+        }
+        Ok(stmts)
+    }
+
+    fn stmt(&mut self) -> Result<Stmt, Error> {
+        let line = self.line();
+        let kind = match self.peek() {
+            Tok::Let => {
+                self.bump();
+                let name = self.ident()?;
+                self.expect(Tok::Assign)?;
+                let val = self.expr()?;
+                self.expect(Tok::Semi)?;
+                StmtKind::Let(name, val)
+            }
+            Tok::If => return self.if_stmt(),
+            Tok::While => {
+                self.bump();
+                let cond = self.expr()?;
+                StmtKind::While(cond, self.block()?)
+            }
+            Tok::Print => {
+                self.bump();
+                let val = self.expr()?;
+                self.expect(Tok::Semi)?;
+                StmtKind::Print(val)
+            }
+            Tok::Return => {
+                self.bump();
+                let val = if *self.peek() == Tok::Semi {
+                    None
+                } else {
+                    Some(self.expr()?)
+                };
+                self.expect(Tok::Semi)?;
+                StmtKind::Return(val)
+            }
+            Tok::Ident(_) if self.toks[self.at + 1].1 == Tok::Assign => {
+                let name = self.ident()?;
+                self.bump();
+                let val = self.expr()?;
+                self.expect(Tok::Semi)?;
+                StmtKind::Assign(name, val)
+            }
+            _ => {
+                let val = self.expr()?;
+                self.expect(Tok::Semi)?;
+                StmtKind::Expr(val)
+            }
+        };
+        Ok(Stmt { line, kind })
+    }
+
+    fn if_stmt(&mut self) -> Result<Stmt, Error> {
+        let line = self.line();
+        self.expect(Tok::If)?;
+        let cond = self.expr()?;
+        let then = self.block()?;
+        let otherwise = if !self.eat(&Tok::Else) {
+            vec![]
+        } else if *self.peek() == Tok::If {
+            vec![self.if_stmt()?]
+        } else {
+            self.block()?
+        };
+        Ok(Stmt {
+            line,
+            kind: StmtKind::If(cond, then, otherwise),
+        })
+    }
+
+    pub fn expr(&mut self) -> Result<Expr, Error> {
+        let lhs = self.sum()?;
+        let op = match self.peek() {
+            Tok::EqEq => BinOp::Eq,
+            Tok::NotEq => BinOp::NotEq,
+            Tok::Less => BinOp::Less,
+            Tok::LessEq => BinOp::LessEq,
+            Tok::Greater => BinOp::Greater,
+            Tok::GreaterEq => BinOp::GreaterEq,
+            _ => return Ok(lhs),
+        };
+        self.bump();
+        let rhs = self.sum()?;
+        Ok(Expr::Binary(op, Box::new(lhs), Box::new(rhs)))
+    }
+
+    fn sum(&mut self) -> Result<Expr, Error> {
+        let mut lhs = self.product()?;
+        loop {
+            let op = match self.peek() {
+                Tok::Plus => BinOp::Add,
+                Tok::Minus => BinOp::Sub,
+                _ => return Ok(lhs),
+            };
+            self.bump();
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(self.product()?));
+        }
+    }
+
+    fn product(&mut self) -> Result<Expr, Error> {
+        let mut lhs = self.unary()?;
+        loop {
+            let op = match self.peek() {
+                Tok::Star => BinOp::Mul,
+                Tok::Slash => BinOp::Div,
+                Tok::Percent => BinOp::Rem,
+                _ => return Ok(lhs),
+            };
+            self.bump();
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(self.unary()?));
+        }
+    }
+
+    fn unary(&mut self) -> Result<Expr, Error> {
+        let op = match self.peek() {
+            Tok::Minus => UnOp::Neg,
+            Tok::Bang => UnOp::Not,
+            _ => return self.primary(),
+        };
+        self.bump();
+        Ok(Expr::Unary(op, Box::new(self.unary()?)))
+    }
+
+    fn primary(&mut self) -> Result<Expr, Error> {
+        match self.peek().clone() {
+            Tok::Int(n) => {
+                self.bump();
+                Ok(Expr::Int(n))
+            }
+            Tok::Ident(name) => {
+                self.bump();
+                if !self.eat(&Tok::LParen) {
+                    return Ok(Expr::Var(name));
+                }
+                let mut args = vec![];
+                if !self.eat(&Tok::RParen) {
+                    loop {
+                        args.push(self.expr()?);
+                        if self.eat(&Tok::RParen) {
+                            break;
+                        }
+                        self.expect(Tok::Comma)?;
+                    }
+                }
+                Ok(Expr::Call(name, args))
+            }
+            Tok::LParen => {
+                self.bump();
+                let inner = self.expr()?;
+                self.expect(Tok::RParen)?;
+                Ok(inner)
+            }
+            _ => Err(self.unexpected("an expression")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_precedence() {
+        let expr = Parser::new("1 + 2 * -x < f(3) - 4")
+            .unwrap()
+            .expr()
+            .unwrap();
+        let int = |n| Box::new(Expr::Int(n));
+        assert_eq!(
+            expr,
+            Expr::Binary(
+                BinOp::Less,
+                Box::new(Expr::Binary(
+                    BinOp::Add,
+                    int(1),
+                    Box::new(Expr::Binary(
+                        BinOp::Mul,
+                        int(2),
+                        Box::new(Expr::Unary(UnOp::Neg, Box::new(Expr::Var("x".into()))))
+                    ))
+                )),
+                Box::new(Expr::Binary(
+                    BinOp::Sub,
+                    Box::new(Expr::Call("f".into(), vec![Expr::Int(3)])),
+                    int(4)
+                ))
+            )
+        );
+    }
+
+    #[test]
+    fn test_parse_module() {
+        let src = "fn f(a, b) {\n  return a;\n}\nlet x = f(1, 2);\nif x { print x; } else if 0 {} else { x = 1; }";
+        let module = Parser::new(src).unwrap().module().unwrap();
+        assert_eq!(module.functions.len(), 1);
+        assert_eq!(module.functions[0].params, vec!["a", "b"]);
+        assert_eq!(module.functions[0].body[0].line, 2);
+        assert_eq!(module.main.len(), 2);
+        match &module.main[1].kind {
+            StmtKind::If(_, then, otherwise) => {
+                assert_eq!(then.len(), 1);
+                assert!(matches!(
+                    otherwise[..],
+                    [Stmt {
+                        kind: StmtKind::If(..),
+                        ..
+                    }]
+                ));
+            }
+            kind => panic!("expected an if, found {:?}", kind),
+        }
+        assert_eq!(
+            Parser::new("let = 3;").unwrap().module(),
+            Err(Error::Unexpected(1, "a name".into(), "=".into()))
+        );
+    }
+}
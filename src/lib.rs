@@ -0,0 +1,19 @@
+pub mod assembler;
+pub mod bytecode;
+pub mod cfg;
+pub mod constprop;
+pub mod coverage;
+pub mod data;
+pub mod decode;
+pub mod device;
+pub mod dump;
+pub mod heap;
+pub mod interrupt;
+pub mod lang;
+pub mod pool;
+pub mod profile;
+pub mod repl;
+pub mod scheduler;
+pub mod value;
+pub mod verify;
+pub mod vm;
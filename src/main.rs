@@ -1,9 +1,187 @@
-pub mod assembler;
-pub mod bytecode;
-pub mod data;
-pub mod repl;
-pub mod vm;
+use lil_vm::assembler::optimize::Optimizer;
+use lil_vm::assembler::parser::{Parser, Program};
+use lil_vm::coverage::Coverage;
+use lil_vm::device::{Clock, Framebuffer, Rng, Uart};
+use lil_vm::profile::Profile;
+use lil_vm::vm::{Overflow, Vm, VmConfig};
+use lil_vm::{assembler, cfg, constprop, device, dump, lang, repl};
+
+const USAGE: &str = "usage:
+    lil-vm                                  start the REPL
+    lil-vm run [--checked] [--optimize] [--direct-jumps] [--profile]
+               [--folded <out>] [--coverage <lcov>] [--framebuffer <ppm>]
+               <file>
+                                            assemble (or compile, for `.lil`
+                                            files) and run a program, with a
+                                            console, rng and clock mapped in
+    lil-vm cfg <file>                       print a program's control-flow graph
+                                            in Graphviz's DOT language
+    lil-vm disasm <file>                    disassemble a program, showing where
+                                            each jump goes where that's known
+    lil-vm inspect <core-file>              open a core file in the debugger";
 
 fn main() {
-    repl::Repl::new().run()
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    match args.iter().map(String::as_str).collect::<Vec<_>>()[..] {
+        [] => repl::Repl::new().run(),
+        ["inspect", path] => match dump::CoreDump::load(path) {
+            Ok(core) => repl::Repl::inspect(core).run(),
+            Err(e) => fail(format!("unable to read core file `{}`: {}", path, e)),
+        },
+        ["run", ref rest @ ..] => run(rest),
+        ["cfg", path] => {
+            let (_, program) = assemble(path);
+            let cfg = cfg::Cfg::new(&program.bytes());
+            print!("{}", cfg.to_dot(Some(&program.source_map())));
+        }
+        ["disasm", path] => {
+            let (_, program) = assemble(path);
+            let consts = constprop::Constants::new(&program.bytes());
+            print!("{}", consts.disassemble(Some(&program.source_map())));
+        }
+        _ => {
+            eprintln!("{}", USAGE);
+            std::process::exit(2)
+        }
+    }
+}
+
+fn fail(msg: String) -> ! {
+    eprintln!("{}", msg);
+    std::process::exit(1)
+}
+
+/// Reads and assembles the program at `path`, bailing on any parse errors and
+/// printing any lint warnings. Files ending in `.lil` are compiled from the
+/// high-level language instead.
+fn assemble(path: &str) -> (String, Program) {
+    let src = std::fs::read_to_string(path)
+        .unwrap_or_else(|e| fail(format!("unable to read `{}`: {}", path, e)));
+    if path.ends_with(".lil") {
+        let program = lang::compile(&src).unwrap_or_else(|e| fail(format!("{}: {}", path, e)));
+        return (src, program);
+    }
+    let program = Parser::new(&src)
+        .program()
+        .unwrap_or_else(|e| fail(format!("{}: {}", path, e)));
+    if !program.errors.is_empty() {
+        for err in &program.errors {
+            eprintln!("{}: {}", path, err);
+        }
+        std::process::exit(1)
+    }
+    for warning in assembler::lint::lint(&program) {
+        eprintln!("{}: {}", path, warning);
+    }
+    (src, program)
+}
+
+/// Prints an annotated listing of the run's coverage, and merges it into the
+/// lcov tracefile at `out` (creating it if it doesn't exist yet).
+fn report_coverage(path: &str, src: &str, program: &Program, profile: &Profile, out: &str) {
+    let mut cov = match std::fs::read_to_string(out) {
+        Ok(prev) => Coverage::from_lcov(&prev).unwrap_or_else(|e| fail(format!("{}: {}", out, e))),
+        Err(_) => Coverage::new(),
+    };
+    let run = Coverage::from_profile(path, &program.source_map(), profile);
+    cov.merge(&run);
+    print!("{}", run.listing(path, src));
+    let (hit, found) = cov.summary(path);
+    println!("{}: {}/{} lines covered", path, hit, found);
+    if let Err(e) = std::fs::write(out, cov.to_lcov()) {
+        fail(format!("unable to write `{}`: {}", out, e));
+    }
+}
+
+fn run(args: &[&str]) {
+    let mut config = VmConfig {
+        core_dump: Some("core.lvmcore".into()),
+        ..Default::default()
+    };
+    let mut report = false;
+    let mut folded = None;
+    let mut lcov = None;
+    let mut framebuffer = None;
+    let mut optimizer = Optimizer::none();
+    let mut path = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match *arg {
+            "--checked" => config.overflow = Overflow::Trap,
+            "--optimize" => {
+                optimizer = Optimizer {
+                    direct_jumps: optimizer.direct_jumps,
+                    ..Optimizer::default()
+                }
+            }
+            "--direct-jumps" => optimizer.direct_jumps = true,
+            "--profile" => {
+                config.profile = true;
+                report = true;
+            }
+            "--folded" => {
+                config.profile = true;
+                folded = Some(args.next().unwrap_or_else(|| fail(USAGE.into())));
+            }
+            "--coverage" => {
+                config.profile = true;
+                lcov = Some(args.next().unwrap_or_else(|| fail(USAGE.into())));
+            }
+            "--framebuffer" => {
+                framebuffer = Some(args.next().unwrap_or_else(|| fail(USAGE.into())));
+            }
+            file if path.is_none() => path = Some(file),
+            _ => fail(USAGE.into()),
+        }
+    }
+    let path = path.unwrap_or_else(|| fail(USAGE.into()));
+
+    let (src, mut program) = assemble(path);
+    if optimizer != Optimizer::none() {
+        optimizer.optimize(&mut program);
+    }
+    let mut builder = Vm::builder()
+        .config(config)
+        .device(device::UART_BASE, Uart::stdio())
+        .device(device::RNG_BASE, Rng::from_time())
+        .device(device::CLOCK_BASE, Clock::new());
+    if let Some(out) = framebuffer {
+        builder = builder.device(
+            device::FRAMEBUFFER_BASE,
+            Framebuffer::new(64, 32).with_output(out),
+        );
+    }
+    let mut vm = builder.build();
+    if let Err(violations) = vm.load(program.bytes()) {
+        let map = program.source_map();
+        for trap in violations {
+            match map.line(trap.pc()) {
+                Some(line) => eprintln!("{}:{}: {}", path, line, trap),
+                None => eprintln!("{}: {}", path, trap),
+            }
+        }
+        std::process::exit(1)
+    }
+    vm.run();
+    if let Some(trap) = vm.trap() {
+        eprintln!("trap: {} (core dumped to `core.lvmcore`)", trap);
+    }
+
+    if let (Some(profile), Some(out)) = (vm.profile(), lcov) {
+        report_coverage(path, &src, &program, profile, out);
+    }
+    if let Some(profile) = vm.profile() {
+        let map = program.source_map();
+        if report {
+            print!("{}", profile.report(Some(&map)));
+        }
+        if let Some(out) = folded {
+            if let Err(e) = std::fs::write(out, profile.folded(Some(&map))) {
+                fail(format!("unable to write `{}`: {}", out, e));
+            }
+        }
+    }
+    if vm.trap().is_some() {
+        std::process::exit(1)
+    }
 }
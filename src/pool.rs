@@ -0,0 +1,128 @@
+//! Running many independent programs in parallel.
+//!
+//! A `VmPool` runs a batch of machines to completion across a number of OS
+//! threads. Jobs are dealt out to the workers up front, and a worker that runs
+//! out of jobs of its own steals from the back of the other workers' queues,
+//! so that a few long-running programs don't leave the rest of the pool idle.
+//!
+//! Programs meant to be run many times over should be verified once into an
+//! `Image` and loaded with `Vm::load_image`, so that every machine shares the
+//! same bytecode instead of holding its own copy.
+use std::{collections::VecDeque, num::NonZeroUsize, sync::Mutex, thread};
+
+use crate::vm::Vm;
+
+#[derive(Clone, Debug)]
+pub struct VmPool {
+    threads: usize,
+}
+
+impl VmPool {
+    /// A pool with the given number of worker threads (at least one)
+    pub fn new(threads: usize) -> Self {
+        Self {
+            threads: threads.max(1),
+        }
+    }
+
+    /// A pool with one worker per CPU core
+    pub fn per_core() -> Self {
+        Self::new(thread::available_parallelism().map_or(1, NonZeroUsize::get))
+    }
+
+    pub fn threads(&self) -> usize {
+        self.threads
+    }
+
+    /// Runs every machine until it halts, traps or runs off the end of its
+    /// code, returning them in the order they were given, so that their
+    /// registers and traps can be inspected.
+    ///
+    /// Each machine is run with `Vm::run`, so a program that never stops will
+    /// hold up its worker (and the whole batch) forever.
+    pub fn run(&self, vms: impl IntoIterator<Item = Vm>) -> Vec<Vm> {
+        let queues = (0..self.threads)
+            .map(|_| Mutex::new(VecDeque::new()))
+            .collect::<Vec<_>>();
+        let mut count = 0;
+        for (i, vm) in vms.into_iter().enumerate() {
+            queues[i % self.threads].lock().unwrap().push_back((i, vm));
+            count += 1;
+        }
+        let mut done = thread::scope(|scope| {
+            let workers = (0..self.threads)
+                .map(|id| {
+                    let queues = &queues;
+                    scope.spawn(move || work(id, queues))
+                })
+                .collect::<Vec<_>>();
+            workers
+                .into_iter()
+                .flat_map(|w| w.join().unwrap())
+                .collect::<Vec<_>>()
+        });
+        debug_assert_eq!(done.len(), count);
+        done.sort_unstable_by_key(|(i, _)| *i);
+        done.into_iter().map(|(_, vm)| vm).collect()
+    }
+}
+
+type Queue = Mutex<VecDeque<(usize, Vm)>>;
+
+/// Runs jobs from the worker's own queue, then steals from the others until
+/// every queue is empty. Jobs never get added once the workers have started,
+/// so an empty sweep means there's nothing left to do.
+fn work(id: usize, queues: &[Queue]) -> Vec<(usize, Vm)> {
+    let mut done = vec![];
+    loop {
+        let own = queues[id].lock().unwrap().pop_front();
+        let job = own.or_else(|| {
+            (1..queues.len())
+                .map(|k| (id + k) % queues.len())
+                .find_map(|victim| queues[victim].lock().unwrap().pop_back())
+        });
+        match job {
+            Some((i, mut vm)) => {
+                vm.run();
+                done.push((i, vm));
+            }
+            None => return done,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::parser::Parser;
+    use crate::vm::{Image, Trap};
+
+    fn assert_send<T: Send>() {}
+
+    #[test]
+    fn test_vm_is_send() {
+        assert_send::<Vm>();
+        assert_send::<crate::scheduler::Scheduler>();
+    }
+
+    #[test]
+    fn test_pool_runs_all() {
+        // sums 1..=$1 into $0, or traps if $1 is zero
+        let src = "load $2 #1\ndiv $2 $1 $3\nloop: add $0 $1 $0\ndec $1\nbne $1 $4 @loop";
+        let image = Image::new(Parser::new(src).program().unwrap().bytes()).unwrap();
+        let vms = (0..200).map(|n| {
+            let mut vm = Vm::new();
+            vm.load_image(&image);
+            vm.regs[1] = n;
+            vm
+        });
+        let done = VmPool::new(4).run(vms);
+        assert_eq!(done.len(), 200);
+        assert_eq!(done[0].trap(), Some(Trap::DivideByZero { pc: 4 }));
+        for (n, vm) in done.iter().enumerate().skip(1) {
+            assert_eq!(vm.regs[0], (n * (n + 1) / 2) as i32);
+            // every machine runs off the one copy of the code
+            assert_eq!(vm.instructions().as_ptr(), image.code().as_ptr());
+        }
+    }
+}
@@ -0,0 +1,179 @@
+//! Instruction-level profiling.
+//!
+//! When enabled through `VmConfig::profile`, the VM counts how many times each
+//! instruction (keyed by the byte offset it starts at) and each `OpCode` was
+//! executed, as well as how often the conditional jumps `JMPE`/`JMPNE` were
+//! actually taken.
+use std::{collections::BTreeMap, fmt::Write};
+
+use crate::assembler::parser::SourceMap;
+use crate::bytecode::OpCode;
+
+/// Outcomes of a single conditional jump instruction.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Branch {
+    pub taken: u64,
+    pub not_taken: u64,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Profile {
+    /// execution counts and opcode for each instruction, keyed by pc
+    pub pcs: BTreeMap<usize, (OpCode, u64)>,
+    /// execution counts per opcode, indexed by `OpCode::as_usize`
+    pub ops: Vec<u64>,
+    /// conditional jump outcomes, keyed by pc
+    pub branches: BTreeMap<usize, Branch>,
+}
+
+impl Profile {
+    pub fn new() -> Self {
+        Self {
+            pcs: BTreeMap::new(),
+            ops: vec![0; OpCode::VARIANTS.len()],
+            branches: BTreeMap::new(),
+        }
+    }
+
+    /// Total number of instructions executed
+    pub fn total(&self) -> u64 {
+        self.ops.iter().sum()
+    }
+
+    pub fn count(&mut self, pc: usize, op: OpCode) {
+        self.pcs.entry(pc).or_insert((op, 0)).1 += 1;
+        self.ops[op.as_usize()] += 1;
+    }
+
+    pub fn branch(&mut self, pc: usize, taken: bool) {
+        let branch = self.branches.entry(pc).or_default();
+        if taken {
+            branch.taken += 1;
+        } else {
+            branch.not_taken += 1;
+        }
+    }
+
+    /// Instructions sorted from most to least executed, ties broken by pc
+    pub fn hottest(&self) -> Vec<(usize, OpCode, u64)> {
+        let mut rows = self
+            .pcs
+            .iter()
+            .map(|(pc, (op, n))| (*pc, *op, *n))
+            .collect::<Vec<_>>();
+        rows.sort_by(|a, b| b.2.cmp(&a.2).then(a.0.cmp(&b.0)));
+        rows
+    }
+
+    /// Renders the per-pc and per-opcode tables. If a source map is given,
+    /// each instruction is labelled with the source line it came from.
+    pub fn report(&self, map: Option<&SourceMap>) -> String {
+        let total = self.total().max(1) as f64;
+        let mut out = String::new();
+        let _ = writeln!(
+            out,
+            "{:>10} {:>7}  {:>6}  {:>5}  op",
+            "count", "%", "pc", "line"
+        );
+        for (pc, op, n) in self.hottest() {
+            let line = map
+                .and_then(|m| m.line(pc))
+                .map_or_else(|| "-".to_string(), |l| l.to_string());
+            let _ = write!(
+                out,
+                "{:>10} {:>6.2}%  0x{:04x}  {:>5}  {}",
+                n,
+                100.0 * n as f64 / total,
+                pc,
+                line,
+                op
+            );
+            if let Some(b) = self.branches.get(&pc) {
+                let _ = write!(out, "  (taken {}/{})", b.taken, b.taken + b.not_taken);
+            }
+            out.push('\n');
+        }
+        let mut ops = OpCode::VARIANTS
+            .iter()
+            .map(|op| (*op, self.ops[op.as_usize()]))
+            .filter(|(_, n)| *n > 0)
+            .collect::<Vec<_>>();
+        ops.sort_by_key(|(_, n)| std::cmp::Reverse(*n));
+        let _ = writeln!(out, "\n{:>10} {:>7}  op", "count", "%");
+        for (op, n) in ops {
+            let _ = writeln!(out, "{:>10} {:>6.2}%  {}", n, 100.0 * n as f64 / total, op);
+        }
+        out
+    }
+
+    /// Renders the profile in the "folded stacks" format consumed by
+    /// flamegraph tools, i.e., one `frame;frame;frame count` line per
+    /// instruction. Since the VM has no call stack, each instruction gets a
+    /// two-frame stack made up of its source line (or pc) and its opcode.
+    pub fn folded(&self, map: Option<&SourceMap>) -> String {
+        let mut out = String::new();
+        for (pc, (op, n)) in &self.pcs {
+            let _ = match map.and_then(|m| m.line(*pc)) {
+                Some(line) => writeln!(out, "lil-vm;line {};{} {}", line, op, n),
+                None => writeln!(out, "lil-vm;0x{:04x};{} {}", pc, op, n),
+            };
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::parser::Parser;
+    use crate::vm::{Vm, VmConfig};
+
+    /// counts down from 3 to 0 in `$0`, looping back through `JMPNE`
+    const LOOP: &str = "load $0 #3
+load $1 #1
+load $2 #0
+load $3 #16
+sub $0 $1 $0
+eq $0 $2
+jmpne $3
+halt";
+
+    fn profiled() -> (Profile, SourceMap) {
+        let program = Parser::new(LOOP).program().unwrap();
+        let mut vm = Vm::with_config(VmConfig {
+            profile: true,
+            ..Default::default()
+        });
+        vm.load(program.bytes()).unwrap();
+        vm.run();
+        (vm.profile().unwrap().clone(), program.source_map())
+    }
+
+    #[test]
+    fn test_profile_counts() {
+        let (profile, _) = profiled();
+        assert_eq!(profile.ops[OpCode::Sub.as_usize()], 3);
+        assert_eq!(profile.ops[OpCode::Load.as_usize()], 4);
+        assert_eq!(profile.total(), 4 + 3 * 3 + 1);
+        assert_eq!(
+            profile.branches.get(&24),
+            Some(&Branch {
+                taken: 2,
+                not_taken: 1
+            })
+        );
+        let (pc, op, n) = profile.hottest()[0];
+        assert_eq!((pc, op, n), (16, OpCode::Sub, 3));
+    }
+
+    #[test]
+    fn test_profile_report_lines() {
+        let (profile, map) = profiled();
+        let report = profile.report(Some(&map));
+        let hottest = report.lines().nth(1).unwrap();
+        assert!(hottest.contains(&format!("0x0010      5  {}", OpCode::Sub)));
+        let folded = profile.folded(Some(&map));
+        assert!(folded.contains(&format!("lil-vm;line 5;{} 3\n", OpCode::Sub)));
+        assert!(folded.contains(&format!("lil-vm;line 8;{} 1\n", OpCode::Halt)));
+    }
+}
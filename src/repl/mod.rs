@@ -1,6 +1,7 @@
 use stringy::stringy;
 
-use crate::vm::Vm;
+use crate::dump::CoreDump;
+use crate::vm::{Vm, VmConfig};
 use std::{
     io::{self, Write},
     num::ParseIntError,
@@ -8,24 +9,52 @@ use std::{
 
 const STARTUP_MSG: &'static str = "Hello! I'm a machine.";
 const PROMPT: &'static str = ">> ";
+/// where the REPL's machine writes its core file when it traps
+const CORE_FILE: &str = "core.lvmcore";
 
 stringy! { Cmd =
     Quit ":quit" | ":q" | ":Q"
     History ":history" | ":h" | ":hist"
     Program ":program" | ":prog"
     Registers ":registers" | ":r"
+    FRegisters ":fregisters" | ":f"
+    Trace ":trace" | ":t"
+    Trap ":trap"
+    Heap ":heap"
 }
 
 pub struct Repl {
     vm: Vm,
     log: Vec<String>,
+    /// the core file being inspected, if the REPL was opened on one
+    core: Option<CoreDump>,
 }
 
 impl Repl {
     pub fn new() -> Self {
         Self {
-            vm: Vm::new(),
+            vm: Vm::with_config(VmConfig {
+                core_dump: Some(CORE_FILE.into()),
+                ..Default::default()
+            }),
             log: vec![],
+            core: None,
+        }
+    }
+
+    /// Opens the REPL as a debugger view over a core file. The machine's
+    /// registers are restored from the dump, and `:program`, `:trace` and
+    /// `:trap` report the state captured at the time of the trap.
+    pub fn inspect(core: CoreDump) -> Self {
+        let mut vm = Vm::new();
+        vm.regs = core.regs;
+        vm.tags = core.tags;
+        vm.fregs = core.fregs;
+        println!("core dumped at pc 0x{:04x}: {}", core.pc, core.trap);
+        Self {
+            vm,
+            log: vec![],
+            core: Some(core),
         }
     }
 
@@ -64,21 +93,68 @@ impl Repl {
                         }
                         println!("}}")
                     }
-                    Cmd::Program => {
-                        println!("code {{");
-                        for op in self.vm.instructions() {
-                            println!("    {:?}", op)
+                    Cmd::Program => match &self.core {
+                        Some(core) => {
+                            println!("code {{");
+                            for (i, op) in core.code.iter().enumerate() {
+                                let at = core.code_start + i;
+                                let mark = if at == core.trap.pc() { "=>" } else { "  " };
+                                println!("  {} 0x{:04x}\t{:?}", mark, at, op)
+                            }
+                            println!("}}");
                         }
-                        println!("}}");
-                    }
+                        None => {
+                            println!("code {{");
+                            for op in self.vm.instructions() {
+                                println!("    {:?}", op)
+                            }
+                            println!("}}");
+                        }
+                    },
                     Cmd::Registers => {
                         println!("registers {{");
                         for (a, r) in self.vm.regs.iter().enumerate() {
+                            println!("\t0x{:x}\t{:?}\t{}", a, r, self.vm.tags[a])
+                        }
+                        let (cmp, overflow, carry) = match &self.core {
+                            Some(core) => (core.cmp, core.overflow, core.carry),
+                            None => (self.vm.cmp(), self.vm.overflow(), self.vm.carry()),
+                        };
+                        println!("\tcmp\t{}\n\tof\t{}\n\tcf\t{}", cmp, overflow, carry);
+                        println!("}}")
+                    }
+                    Cmd::FRegisters => {
+                        println!("fregisters {{");
+                        for (a, r) in self.vm.fregs.iter().enumerate() {
                             println!("\t0x{:x}\t{:?}", a, r)
                         }
                         println!("}}")
                     }
+                    Cmd::Trace => {
+                        let trace = match &self.core {
+                            Some(core) => core.trace.clone(),
+                            None => self.vm.trace().copied().collect(),
+                        };
+                        println!("trace {{");
+                        for entry in trace {
+                            println!("\t0x{:04x}\t{}", entry.pc, entry.opcode())
+                        }
+                        println!("}}")
+                    }
+                    Cmd::Heap => {
+                        println!("heap {{");
+                        println!("\t{}", self.vm.heap_stats());
+                        println!("\tstack depth {}", self.vm.stack().len());
+                        println!("}}")
+                    }
+                    Cmd::Trap => match self.core.as_ref().map(|c| c.trap).or(self.vm.trap()) {
+                        Some(trap) => println!("{}", trap),
+                        None => println!("no trap"),
+                    },
                 },
+                None if self.core.is_some() => {
+                    println!("Inspecting a core file; instructions can't be executed.");
+                }
                 None => {
                     match parse_hex(buf) {
                         Ok(bytes) => {
@@ -92,7 +168,10 @@ impl Repl {
                             continue;
                         }
                     };
-                    self.vm.tick()
+                    self.vm.tick();
+                    if let Some(trap) = self.vm.trap() {
+                        println!("trap: {} (core dumped to `{}`)", trap, CORE_FILE);
+                    }
                 }
             }
         }
@@ -0,0 +1,447 @@
+//! Running several programs in one host.
+//!
+//! A `Scheduler` owns a set of processes, each its own `Vm`, and runs them
+//! cooperatively in round-robin order. Every turn a process is given a
+//! *quantum* of fuel, i.e., a number of instructions it may execute before the
+//! next process gets its turn; a process can hand over early with `YIELD`.
+//!
+//! Processes talk to each other by message passing: each has a mailbox, which
+//! `SEND $PID $VAL` adds to and `RECV $R` takes from, blocking while it is
+//! empty. Messages are delivered at the end of the sender's turn. The host can
+//! also inject messages with `Scheduler::send`, and collect the messages
+//! processes send to `Pid::HOST`. Messages sent to pids that don't exist are
+//! dropped.
+//!
+//! Processes can also start children of their own with `SPAWN`, which run the
+//! same code from a given entry point, and wait for a child (or any other
+//! process) to stop with `WAIT`.
+//!
+//! If every process that hasn't stopped is blocked in `RECV` or `WAIT`, none
+//! of them can ever be woken up (short of the host sending a message), and
+//! `run` reports the deadlock.
+use std::collections::BTreeMap;
+use std::ops::Bound;
+
+use crate::value::Value;
+use crate::vm::{Slice, Trap, Vm};
+
+/// Identifies a process within its scheduler. Pids are handed out in
+/// increasing order and never reused.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Pid(pub u32);
+
+impl Pid {
+    /// The pid messages to the host are sent to
+    pub const HOST: Pid = Pid(u32::MAX);
+}
+
+impl std::fmt::Display for Pid {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        u32::fmt(&self.0, f)
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Status {
+    /// still has instructions left to run
+    Running,
+    /// waiting in `RECV` for a message
+    Blocked,
+    /// waiting in `WAIT` for the given process to stop
+    Waiting(Pid),
+    /// halted or ran off the end of its code
+    Halted,
+    /// stopped by a trap
+    Trapped(Trap),
+}
+
+#[derive(Debug)]
+pub struct Process {
+    pub vm: Vm,
+    pub status: Status,
+    /// the parent, for processes started with `SPAWN`
+    pub parent: Option<Pid>,
+    /// where to store the exit value of the process being waited on
+    wait_dst: usize,
+}
+
+/// A message sent to the host
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Message {
+    pub from: Pid,
+    pub val: i32,
+}
+
+/// Every process is blocked. Maps each blocked process to the processes it is
+/// waiting on: for `WAIT`, the process it is waiting for, and for `RECV`, the
+/// processes that could still send it a message, i.e., the other processes
+/// that haven't stopped (which are themselves all blocked).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Deadlock {
+    pub waits: BTreeMap<Pid, Vec<Pid>>,
+}
+
+impl std::fmt::Display for Deadlock {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "deadlock:")?;
+        for (pid, on) in &self.waits {
+            write!(f, "\n\tprocess {} waits on ", pid)?;
+            if on.is_empty() {
+                write!(f, "nobody")?;
+            }
+            for (i, other) in on.iter().enumerate() {
+                let sep = if i == 0 { "" } else { ", " };
+                write!(f, "{}{}", sep, other)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub struct Scheduler {
+    procs: BTreeMap<Pid, Process>,
+    /// messages sent to `Pid::HOST`
+    host: Vec<Message>,
+    /// instructions a process may run per turn
+    quantum: u64,
+    next_pid: u32,
+    /// the process that ran last, so that the next turn goes to the one after
+    last: Option<Pid>,
+}
+
+impl Scheduler {
+    pub fn new(quantum: u64) -> Self {
+        Self {
+            procs: BTreeMap::new(),
+            host: vec![],
+            quantum,
+            next_pid: 0,
+            last: None,
+        }
+    }
+
+    /// Adds a process running `vm`, which should already have its code
+    /// loaded.
+    pub fn spawn(&mut self, vm: Vm) -> Pid {
+        self.insert(vm, None)
+    }
+
+    fn insert(&mut self, vm: Vm, parent: Option<Pid>) -> Pid {
+        let pid = Pid(self.next_pid);
+        self.next_pid += 1;
+        self.procs.insert(
+            pid,
+            Process {
+                vm,
+                status: Status::Running,
+                parent,
+                wait_dst: 0,
+            },
+        );
+        pid
+    }
+
+    /// What `WAIT` gives for process `pid`, once it has stopped: its `$0` if
+    /// it halted, and `i32::MIN` if it trapped or doesn't exist
+    pub fn exit_value(&self, pid: Pid) -> Option<i32> {
+        match self.procs.get(&pid) {
+            Some(proc) => match proc.status {
+                Status::Halted => Some(proc.vm.regs[0]),
+                Status::Trapped(_) => Some(i32::MIN),
+                _ => None,
+            },
+            None => Some(i32::MIN),
+        }
+    }
+
+    pub fn status(&self, pid: Pid) -> Option<Status> {
+        self.procs.get(&pid).map(|p| p.status)
+    }
+
+    pub fn process(&self, pid: Pid) -> Option<&Process> {
+        self.procs.get(&pid)
+    }
+
+    pub fn process_mut(&mut self, pid: Pid) -> Option<&mut Process> {
+        self.procs.get_mut(&pid)
+    }
+
+    /// Sends `val` to the process `pid` on behalf of the host, returning
+    /// whether there is such a process.
+    pub fn send(&mut self, pid: Pid, val: i32) -> bool {
+        match self.procs.get_mut(&pid) {
+            Some(proc) => {
+                proc.vm.deliver(val);
+                if proc.status == Status::Blocked {
+                    proc.status = Status::Running;
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Takes every message waiting in the mailbox of `pid`
+    pub fn drain(&mut self, pid: Pid) -> Vec<i32> {
+        self.procs
+            .get_mut(&pid)
+            .map_or_else(Vec::new, |p| p.vm.drain_mailbox())
+    }
+
+    /// Takes every message sent to the host so far
+    pub fn drain_host(&mut self) -> Vec<Message> {
+        std::mem::take(&mut self.host)
+    }
+
+    /// Every process and its status, in order of pid
+    pub fn processes(&self) -> impl Iterator<Item = (Pid, Status)> + '_ {
+        self.procs.iter().map(|(pid, p)| (*pid, p.status))
+    }
+
+    /// The next running process after the one that ran last, wrapping around
+    fn pick(&self) -> Option<Pid> {
+        let after = match self.last {
+            Some(pid) => Bound::Excluded(pid),
+            None => Bound::Unbounded,
+        };
+        let running = |(pid, p): (&Pid, &Process)| match p.status {
+            Status::Running => Some(*pid),
+            _ => None,
+        };
+        self.procs
+            .range((after, Bound::Unbounded))
+            .find_map(running)
+            .or_else(|| self.procs.iter().find_map(running))
+    }
+
+    /// Gives the next running process its turn, returning its pid, or `None`
+    /// if every process has stopped.
+    pub fn step(&mut self) -> Option<Pid> {
+        let pid = self.pick()?;
+        let quantum = self.quantum;
+        let proc = self.procs.get_mut(&pid)?;
+        let slice = proc.vm.run_slice(quantum);
+        let outbox = proc.vm.take_outbox();
+        match slice {
+            Slice::Done => {
+                proc.status = match proc.vm.trap() {
+                    Some(trap) => Status::Trapped(trap),
+                    None => Status::Halted,
+                };
+            }
+            Slice::Blocked => proc.status = Status::Blocked,
+            Slice::Spawn { entry, dst } => {
+                let child = proc.vm.fork(entry);
+                let child = self.insert(child, Some(pid));
+                if let Some(proc) = self.procs.get_mut(&pid) {
+                    proc.vm.regs[dst] = child.0 as i32;
+                }
+            }
+            Slice::Wait { pid: on, dst } => {
+                proc.status = Status::Waiting(Pid(on));
+                proc.wait_dst = dst;
+            }
+            Slice::Yielded | Slice::OutOfFuel => {}
+        }
+        for (to, val) in outbox {
+            match Pid(to) {
+                Pid::HOST => self.host.push(Message { from: pid, val }),
+                to => {
+                    self.send(to, val);
+                }
+            }
+        }
+        self.wake_waiters();
+        self.last = Some(pid);
+        Some(pid)
+    }
+
+    /// Hands the exit values of stopped processes to those waiting on them
+    fn wake_waiters(&mut self) {
+        let woken = self
+            .procs
+            .iter()
+            .filter_map(|(pid, proc)| match proc.status {
+                Status::Waiting(on) => Some((*pid, self.exit_value(on)?)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        for (pid, val) in woken {
+            if let Some(proc) = self.procs.get_mut(&pid) {
+                proc.vm.set_value(proc.wait_dst, Value::Int(val));
+                proc.status = Status::Running;
+            }
+        }
+    }
+
+    /// Runs every process until they have all stopped, or until the ones
+    /// left are deadlocked.
+    pub fn run(&mut self) -> Result<(), Deadlock> {
+        while self.step().is_some() {}
+        match self.deadlock() {
+            Some(deadlock) => Err(deadlock),
+            None => Ok(()),
+        }
+    }
+
+    /// Checks whether the processes that haven't stopped are all blocked
+    pub fn deadlock(&self) -> Option<Deadlock> {
+        let live = self
+            .processes()
+            .filter(|(_, s)| matches!(s, Status::Running | Status::Blocked | Status::Waiting(_)))
+            .collect::<Vec<_>>();
+        if live.is_empty() || live.iter().any(|(_, s)| *s == Status::Running) {
+            return None;
+        }
+        let waits = live
+            .iter()
+            .map(|(pid, status)| match status {
+                Status::Waiting(on) => (*pid, vec![*on]),
+                _ => {
+                    let on = live.iter().map(|(p, _)| *p).filter(|p| p != pid);
+                    (*pid, on.collect())
+                }
+            })
+            .collect();
+        Some(Deadlock { waits })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::parser::Parser;
+
+    fn process(src: &str) -> Vm {
+        let mut vm = Vm::new();
+        vm.load(Parser::new(src).program().unwrap().bytes())
+            .unwrap();
+        vm
+    }
+
+    #[test]
+    fn test_round_robin() {
+        let mut sched = Scheduler::new(3);
+        // counts to 5, yielding after every increment
+        let a = sched.spawn(process(
+            "load $1 #5\nloop: inc $0\nyield\nblt $0 $1 @loop\nhalt",
+        ));
+        let b = sched.spawn(process("load $0 #1\nload $0 #2\nload $0 #3\nload $0 #4"));
+        let c = sched.spawn(process("load $1 #0\ndiv $0 $1 $2"));
+        let order = (0..6).map(|_| sched.step().unwrap()).collect::<Vec<_>>();
+        // `a` yields after 3 instructions the first time and 3 more the
+        // second; `b` needs a second turn for its fourth `load`, and `c` traps
+        assert_eq!(order, vec![a, b, c, a, b, a]);
+        assert_eq!(sched.status(a), Some(Status::Running));
+        assert_eq!(sched.status(b), Some(Status::Halted));
+        assert_eq!(
+            sched.status(c),
+            Some(Status::Trapped(Trap::DivideByZero { pc: 4 }))
+        );
+        assert_eq!(sched.run(), Ok(()));
+        assert_eq!(sched.status(a), Some(Status::Halted));
+        assert_eq!(sched.process(a).unwrap().vm.regs[0], 5);
+        assert_eq!(sched.step(), None);
+        assert_eq!(sched.status(Pid(9)), None)
+    }
+
+    #[test]
+    fn test_message_passing() {
+        let mut sched = Scheduler::new(100);
+        // doubles whatever it receives and sends it on to the host, until it
+        // receives a zero
+        let doubler = sched.spawn(process(
+            "dec $1\nloop: recv $0\nbeq $0 $2 @end\nadd $0 $0 $0\nsend $1 $0\nbeq $0 $0 @loop\nend: halt",
+        ));
+        // sends its first message to the doubler (pid 0), then a zero to stop
+        let feeder = sched.spawn(process("recv $1\nsend $0 $1\nsend $0 $0"));
+        sched.step();
+        assert_eq!(sched.status(doubler), Some(Status::Blocked));
+        assert!(sched.send(feeder, 21));
+        assert!(sched.send(doubler, 4));
+        assert!(!sched.send(Pid(7), 1));
+        assert_eq!(sched.run(), Ok(()));
+        let sent = sched.drain_host();
+        let vals = sent.iter().map(|m| m.val).collect::<Vec<_>>();
+        assert_eq!(vals, vec![8, 42]);
+        assert!(sent.iter().all(|m| m.from == doubler));
+        assert_eq!(sched.status(feeder), Some(Status::Halted));
+    }
+
+    #[test]
+    fn test_spawn_and_wait() {
+        let mut sched = Scheduler::new(2);
+        // spawns two children that each compute a value, then adds up what
+        // they exit with
+        let parent = sched.spawn(process(
+            "load $0 @one\nspawn $0 $1\nload $0 @two\nspawn $0 $2\nwait $1\nwait $2\nadd $1 $2 $0\nhalt\none: load $0 #40\nhalt\ntwo: load $0 #2\ninc $0\nload $1 #0\ndiv $0 $1 $0",
+        ));
+        assert_eq!(sched.run(), Ok(()));
+        let children = sched
+            .processes()
+            .filter(|(pid, _)| sched.process(*pid).unwrap().parent == Some(parent))
+            .collect::<Vec<_>>();
+        assert_eq!(children.len(), 2);
+        assert_eq!(children[0].1, Status::Halted);
+        assert!(matches!(children[1].1, Status::Trapped(_)));
+        assert_eq!(sched.exit_value(children[0].0), Some(40));
+        assert_eq!(sched.exit_value(parent), Some(40i32.wrapping_add(i32::MIN)));
+    }
+
+    #[test]
+    fn test_spawned_trap_leaves_core_file_alone() {
+        let path =
+            std::env::temp_dir().join(format!("lil-vm-spawn-{}.lvmcore", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut vm = Vm::with_config(crate::vm::VmConfig {
+            core_dump: Some(path.clone()),
+            ..Default::default()
+        });
+        let src = "load $0 @child\nspawn $0 $1\nwait $1\nhalt\nchild: load $1 #0\ndiv $0 $1 $0";
+        vm.load(Parser::new(src).program().unwrap().bytes())
+            .unwrap();
+        let mut sched = Scheduler::new(10);
+        let parent = sched.spawn(vm);
+        assert_eq!(sched.run(), Ok(()));
+        assert_eq!(sched.status(parent), Some(Status::Halted));
+        let (child, status) = sched.processes().find(|(pid, _)| *pid != parent).unwrap();
+        assert!(matches!(status, Status::Trapped(_)));
+        assert_eq!(sched.process(child).unwrap().parent, Some(parent));
+        // only the process the host configured writes a core file
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_wait_deadlock() {
+        let mut sched = Scheduler::new(10);
+        let a = sched.spawn(process("load $0 #1\nwait $0"));
+        let b = sched.spawn(process("load $0 #0\nwait $0"));
+        let c = sched.spawn(process("load $0 #2\nwait $0"));
+        let deadlock = sched.run().unwrap_err();
+        assert_eq!(deadlock.waits[&a], vec![b]);
+        assert_eq!(deadlock.waits[&b], vec![a]);
+        assert_eq!(deadlock.waits[&c], vec![c]);
+    }
+
+    #[test]
+    fn test_deadlock() {
+        let mut sched = Scheduler::new(10);
+        let a = sched.spawn(process("recv $0"));
+        let b = sched.spawn(process("recv $0\nsend $0 $0"));
+        let c = sched.spawn(process("halt"));
+        let deadlock = sched.run().unwrap_err();
+        assert_eq!(deadlock.waits[&a], vec![b]);
+        assert_eq!(deadlock.waits[&b], vec![a]);
+        assert!(!deadlock.waits.contains_key(&c));
+        assert_eq!(
+            deadlock.to_string(),
+            "deadlock:\n\tprocess 0 waits on 1\n\tprocess 1 waits on 0"
+        );
+        // the host can still break it up
+        sched.send(b, 0);
+        assert_eq!(sched.run(), Ok(()));
+        assert_eq!(sched.drain(a), vec![]);
+        assert_eq!(sched.status(a), Some(Status::Halted))
+    }
+}
@@ -0,0 +1,133 @@
+//! Tagged values.
+//!
+//! Registers hold raw `i32`s, but alongside each one the VM keeps a `Type`
+//! tag saying what the bits mean, so that the pair can be viewed as a
+//! `Value`. The same `Value`s are what get pushed onto the stack and stored in
+//! the fields of heap objects.
+//!
+//! By default the tags only matter to the garbage collector, which needs to
+//! know which registers hold references. With `VmConfig::typed` set, the VM
+//! also checks the type of every operand an instruction reads, raising
+//! `Trap::TypeError` when, say, a boolean is added to an integer or a jump
+//! goes through a reference. In that mode comparisons also produce booleans
+//! rather than `0`/`1`, registers start out as `nil`, and values of different
+//! types never compare equal, which makes the VM a reasonable target for a
+//! dynamically typed language.
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Type {
+    Nil,
+    Int,
+    Bool,
+    /// a reference to an object on the heap
+    Ref,
+}
+
+impl Type {
+    /// The number `TYPEOF` reports for this type
+    pub fn tag(&self) -> u8 {
+        match self {
+            Type::Nil => 0,
+            Type::Int => 1,
+            Type::Bool => 2,
+            Type::Ref => 3,
+        }
+    }
+
+    pub fn from_tag(tag: u8) -> Option<Self> {
+        match tag {
+            0 => Some(Type::Nil),
+            1 => Some(Type::Int),
+            2 => Some(Type::Bool),
+            3 => Some(Type::Ref),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for Type {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Type::Nil => write!(f, "nil"),
+            Type::Int => write!(f, "int"),
+            Type::Bool => write!(f, "bool"),
+            Type::Ref => write!(f, "ref"),
+        }
+    }
+}
+
+/// A value stored in a register, on the stack, or in an object's field
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Value {
+    Nil,
+    Int(i32),
+    Bool(bool),
+    /// the handle of an object on the heap
+    Ref(u32),
+}
+
+impl Value {
+    /// Reassembles a value from the raw bits of a register and its tag
+    pub fn new(raw: i32, ty: Type) -> Self {
+        match ty {
+            Type::Nil => Value::Nil,
+            Type::Int => Value::Int(raw),
+            Type::Bool => Value::Bool(raw != 0),
+            Type::Ref => Value::Ref(raw as u32),
+        }
+    }
+
+    /// The raw bits, as stored in a register
+    pub fn raw(&self) -> i32 {
+        match self {
+            Value::Nil => 0,
+            Value::Int(n) => *n,
+            Value::Bool(b) => *b as i32,
+            Value::Ref(r) => *r as i32,
+        }
+    }
+
+    pub fn ty(&self) -> Type {
+        match self {
+            Value::Nil => Type::Nil,
+            Value::Int(_) => Type::Int,
+            Value::Bool(_) => Type::Bool,
+            Value::Ref(_) => Type::Ref,
+        }
+    }
+
+    pub fn is_ref(&self) -> bool {
+        matches!(self, Value::Ref(_))
+    }
+}
+
+impl Default for Value {
+    fn default() -> Self {
+        Value::Int(0)
+    }
+}
+
+impl std::fmt::Display for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Value::Nil => write!(f, "nil"),
+            Value::Int(n) => write!(f, "{}", n),
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::Ref(r) => write!(f, "ref {}", r),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_value_roundtrip() {
+        let values = [Value::Nil, Value::Int(-5), Value::Bool(true), Value::Ref(7)];
+        for val in values {
+            assert_eq!(Value::new(val.raw(), val.ty()), val);
+            assert_eq!(Type::from_tag(val.ty().tag()), Some(val.ty()));
+        }
+    }
+}
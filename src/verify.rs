@@ -0,0 +1,283 @@
+//! Static verification of bytecode before it is run.
+//!
+//! The verifier decodes the whole program and reports every problem it finds,
+//! each as the `Trap` that executing the offending instruction would raise:
+//!
+//! * bytes that don't name a valid opcode
+//! * instructions cut short by the end of the code
+//! * register operands that are out of range (`>= 32`)
+//! * jumps (as well as interrupt handlers installed with `VEC`, and entry
+//!   points passed to `SPAWN`) whose target is known ahead of time but lies
+//!   outside of the code or in the middle of an instruction
+//!
+//! The targets of the compare-and-branch instructions are encoded in the
+//! instruction itself and so are always checked. Every other jump goes through
+//! a register, so its target is only known if its register was `LOAD`ed with
+//! a constant (or `MOV`ed from one that was) earlier in the same basic block,
+//! i.e., with no jump or `HALT` in between, and nothing that is known to jump
+//! to somewhere in between (see `leaders`). A jump whose own target isn't
+//! known might still land in between, unnoticed.
+use crate::bytecode::OpCode;
+use crate::decode::{Decoded, DecodedInstr};
+use crate::vm::Trap;
+
+/// Checks `code`, returning its decoded form if no problems were found and
+/// every violation (in order of offset) otherwise.
+pub fn verify(code: &[u8]) -> Result<Decoded, Vec<Trap>> {
+    let decoded = Decoded::new(code);
+    let (_, targets) = leaders(&decoded);
+    let mut violations = vec![];
+    for (instr, target) in decoded.instrs.iter().zip(targets) {
+        let instr = match instr {
+            Ok(instr) => instr,
+            Err(trap) => {
+                violations.push(*trap);
+                continue;
+            }
+        };
+        if let Some(dest) = target {
+            if dest < 0 || dest > code.len() as i64 {
+                violations.push(Trap::JumpOutOfRange { pc: instr.pc, dest });
+            } else if !decoded.is_boundary(dest as usize) {
+                violations.push(Trap::MisalignedJump {
+                    pc: instr.pc,
+                    dest: dest as usize,
+                });
+            }
+        }
+    }
+    if violations.is_empty() {
+        Ok(decoded)
+    } else {
+        Err(violations)
+    }
+}
+
+/// Which instructions in `decoded` start a basic block (with one more entry,
+/// for the end of the code), along with the target of every instruction,
+/// where it is known (see `track`). A block starts after any instruction
+/// that ends one, and at any known target; resolving targets depends on where
+/// blocks start, since constants aren't tracked across blocks, so this
+/// repeats until no more blocks are found.
+pub fn leaders(decoded: &Decoded) -> (Vec<bool>, Vec<Option<i64>>) {
+    let n = decoded.instrs.len();
+    let mut leaders = vec![false; n + 1];
+    leaders[0] = true;
+    loop {
+        let targets = resolve(decoded, &leaders);
+        let mut changed = false;
+        for (i, instr) in decoded.instrs.iter().enumerate() {
+            let ends = instr.map_or(true, |instr| instr.op.ends_block());
+            let mut starts = vec![];
+            if ends {
+                starts.push(i + 1);
+            }
+            if let Some(at) = targets[i].and_then(|dest| decoded.jump_position(dest)) {
+                starts.push(at);
+            }
+            for at in starts {
+                changed |= !leaders[at];
+                leaders[at] = true;
+            }
+        }
+        if !changed {
+            return (leaders, targets);
+        }
+    }
+}
+
+/// The target of each instruction that takes a code address, where known,
+/// given which instructions start blocks
+fn resolve(decoded: &Decoded, leaders: &[bool]) -> Vec<Option<i64>> {
+    let mut known: [Option<i32>; 32] = [None; 32];
+    let mut targets = vec![];
+    for (i, instr) in decoded.instrs.iter().enumerate() {
+        // an instruction that fails to decode ends its block
+        targets.push(match instr {
+            Ok(instr) => track(&mut known, instr, leaders[i]),
+            Err(_) => None,
+        });
+    }
+    targets
+}
+
+/// Returns the target of `instr` (see `jump_target`) given the constants
+/// `known` to be in each register, and updates them to what is known after
+/// it. Nothing is known at the start of a basic block, i.e., if `leader`, nor
+/// after an instruction that ends one; otherwise a register is only known
+/// after a `LOAD`, or a `MOV` from a register that was.
+fn track(known: &mut [Option<i32>; 32], instr: &DecodedInstr, leader: bool) -> Option<i64> {
+    if leader {
+        *known = [None; 32];
+    }
+    let target = jump_target(instr, known);
+    if instr.op.ends_block() {
+        *known = [None; 32];
+    } else if let Some(r) = instr.written() {
+        known[r] = match instr.op {
+            OpCode::Load => Some(instr.imm as u32 as i32),
+            OpCode::Mov => known[instr.regs[0] as usize],
+            _ => None,
+        };
+    }
+    target
+}
+
+/// The destination of a jump (or of anything else taking a code address, such
+/// as `CALL`, `VEC` and `SPAWN`), given the constants known to be in each
+/// register, if it is known ahead of time
+pub fn jump_target(instr: &DecodedInstr, known: &[Option<i32>; 32]) -> Option<i64> {
+    if instr.op.is_branch() {
+        return instr.relative_target(0);
+    }
+    let val = known[instr.regs[0] as usize]?;
+    match instr.op {
+        OpCode::Jump
+        | OpCode::JumpEq
+        | OpCode::JumpNeq
+        | OpCode::SetVector
+        | OpCode::Spawn
+        | OpCode::Call => Some(val as i64),
+        _ => instr.relative_target(val),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verify_ok() {
+        let code = [
+            OpCode::Load as u8,
+            0,
+            0,
+            6, // load 6 into $0
+            OpCode::Jump as u8,
+            0, // jmp $0
+            OpCode::Halt as u8,
+        ];
+        let decoded = verify(&code).unwrap();
+        assert_eq!(decoded.instrs.len(), 3);
+    }
+
+    #[test]
+    fn test_verify_reports_all() {
+        let code = [
+            OpCode::Load as u8,
+            0,
+            0,
+            5, // load 5 into $0
+            OpCode::Jump as u8,
+            0, // jmp $0, which lands mid-instruction
+            OpCode::Add as u8,
+            0,
+            1,
+            99,  // add $0 $1 $99
+            200, // not an opcode
+            OpCode::Load as u8,
+            1,
+            1,
+            0, // load 256 into $1
+            OpCode::JumpF as u8,
+            1, // jmpf $1, past the end of the code
+            OpCode::Load as u8,
+            0,
+        ];
+        assert_eq!(
+            verify(&code).unwrap_err(),
+            vec![
+                Trap::MisalignedJump { pc: 4, dest: 5 },
+                Trap::BadRegister { pc: 6, reg: 99 },
+                Trap::IllegalOpcode { pc: 10, byte: 200 },
+                Trap::JumpOutOfRange { pc: 15, dest: 273 },
+                Trap::Truncated { pc: 17 },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_verify_branches() {
+        let code = [
+            OpCode::BranchEq as u8,
+            0,
+            1,
+            255,
+            251, // beq $0 $1 #-5, back to itself
+            OpCode::BranchLess as u8,
+            0,
+            1,
+            0,
+            2, // blt $0 $1 #2, past the end of the code
+        ];
+        assert_eq!(
+            verify(&code).unwrap_err(),
+            vec![Trap::JumpOutOfRange { pc: 5, dest: 12 }]
+        );
+    }
+
+    #[test]
+    fn test_verify_follows_moves() {
+        let code = [
+            OpCode::Load as u8,
+            0,
+            0,
+            3, // load 3 into $0
+            OpCode::Mov as u8,
+            0,
+            1,
+            0, // mov $0 $1
+            OpCode::Jump as u8,
+            1, // jmp $1, which lands mid-instruction
+        ];
+        assert_eq!(
+            verify(&code).unwrap_err(),
+            vec![Trap::MisalignedJump { pc: 8, dest: 3 }]
+        );
+    }
+
+    #[test]
+    fn test_verify_forgets_after_jumps() {
+        // the `jmp $0` at 8 may be reached from elsewhere, so the constant
+        // loaded at 0 can't be trusted there
+        let code = [
+            OpCode::Load as u8,
+            0,
+            0,
+            3,
+            OpCode::JumpEq as u8,
+            1,
+            OpCode::Halt as u8,
+            OpCode::Halt as u8,
+            OpCode::Jump as u8,
+            0,
+        ];
+        assert!(verify(&code).is_ok());
+    }
+
+    #[test]
+    fn test_verify_forgets_at_targets() {
+        // the `jmp $0` at 14 is only ever jumped to, with `$0` set to 16, so
+        // the out-of-range constant loaded just before it doesn't count
+        let code = [
+            OpCode::Load as u8,
+            0,
+            0,
+            16, // load $0 #16
+            OpCode::Load as u8,
+            1,
+            0,
+            14, // load $1 #14
+            OpCode::Jump as u8,
+            1, // jmp $1
+            OpCode::Load as u8,
+            0,
+            3,
+            232, // load $0 #1000
+            OpCode::Jump as u8,
+            0, // jmp $0
+            OpCode::Halt as u8,
+        ];
+        assert!(verify(&code).is_ok());
+    }
+}
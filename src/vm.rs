@@ -1,61 +1,710 @@
 ///! NOTE: THE MACHINE IN WHICH THIS WAS WRITTEN USES BIG ENDIAN!!!!!!
 ///
 /// Todo: maybe figure something out abt this later idk
+use std::{collections::VecDeque, path::PathBuf, sync::Arc};
+
 use crate::bytecode::OpCode;
+use crate::decode::{decode_at, Decoded, DecodedInstr};
+use crate::device::{Bus, Device};
+use crate::dump::CoreDump;
+use crate::heap::{Heap, HeapStats, ObjKind};
+use crate::interrupt::{Interrupts, Saved, VECTORS};
+use crate::profile::Profile;
+use crate::value::{Type, Value};
+use crate::verify::verify;
+
+/// Fatal conditions that stop the machine. The `pc` carried by each variant
+/// is the byte offset of the *start* of the offending instruction.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Trap {
+    /// the byte at `pc` does not name a valid opcode
+    IllegalOpcode { pc: usize, byte: u8 },
+    /// `DIV` was given a zero divisor
+    DivideByZero { pc: usize },
+    /// a jump tried to move the program counter outside of the bytecode
+    JumpOutOfRange { pc: usize, dest: i64 },
+    /// a jump landed in the middle of an instruction
+    MisalignedJump { pc: usize, dest: usize },
+    /// the instruction at `pc` runs past the end of the bytecode
+    Truncated { pc: usize },
+    /// an instruction named a register that doesn't exist
+    BadRegister { pc: usize, reg: u8 },
+    /// arithmetic overflowed while the VM was configured with
+    /// `Overflow::Trap`
+    Overflow { pc: usize },
+    /// `VEC` named an interrupt line that doesn't exist
+    BadVector { pc: usize, line: u16 },
+    /// `IRET` was executed outside of an interrupt handler
+    StrayIret { pc: usize },
+    /// a load or store touched an address that is neither in RAM nor mapped
+    /// to a device
+    MemoryFault { pc: usize, addr: u32 },
+    /// an object instruction was given a register that doesn't hold a
+    /// reference
+    NotAReference { pc: usize, reg: u8 },
+    /// `GETF` or `SETF` named a field past the end of the object
+    FieldOutOfRange { pc: usize, field: i32 },
+    /// `NEW` asked for more fields than the heap has room for, even after
+    /// collecting, or for a negative number of them
+    OutOfMemory { pc: usize, len: i32 },
+    /// `NEW` was given an object kind that doesn't exist
+    BadObjectKind { pc: usize, kind: u16 },
+    /// `PUSH` onto a full stack
+    StackOverflow { pc: usize },
+    /// `POP` off an empty stack
+    StackUnderflow { pc: usize },
+    /// an instruction was given a value of the wrong type while the VM was
+    /// configured to be `typed`
+    TypeError { pc: usize, reg: u8, found: Type },
+}
+
+impl Trap {
+    pub fn pc(&self) -> usize {
+        match self {
+            Trap::IllegalOpcode { pc, .. }
+            | Trap::DivideByZero { pc }
+            | Trap::JumpOutOfRange { pc, .. }
+            | Trap::MisalignedJump { pc, .. }
+            | Trap::Truncated { pc }
+            | Trap::BadRegister { pc, .. }
+            | Trap::Overflow { pc }
+            | Trap::BadVector { pc, .. }
+            | Trap::StrayIret { pc }
+            | Trap::MemoryFault { pc, .. }
+            | Trap::NotAReference { pc, .. }
+            | Trap::FieldOutOfRange { pc, .. }
+            | Trap::OutOfMemory { pc, .. }
+            | Trap::BadObjectKind { pc, .. }
+            | Trap::StackOverflow { pc }
+            | Trap::StackUnderflow { pc }
+            | Trap::TypeError { pc, .. } => *pc,
+        }
+    }
+}
+
+impl std::fmt::Display for Trap {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Trap::IllegalOpcode { pc, byte } => {
+                write!(f, "illegal opcode 0x{:02x} at 0x{:04x}", byte, pc)
+            }
+            Trap::DivideByZero { pc } => write!(f, "division by zero at 0x{:04x}", pc),
+            Trap::JumpOutOfRange { pc, dest } => {
+                write!(f, "jump to {} out of range at 0x{:04x}", dest, pc)
+            }
+            Trap::MisalignedJump { pc, dest } => write!(
+                f,
+                "jump to 0x{:04x} lands mid-instruction at 0x{:04x}",
+                dest, pc
+            ),
+            Trap::Truncated { pc } => write!(f, "truncated instruction at 0x{:04x}", pc),
+            Trap::BadRegister { pc, reg } => {
+                write!(f, "no such register ${} at 0x{:04x}", reg, pc)
+            }
+            Trap::Overflow { pc } => write!(f, "arithmetic overflow at 0x{:04x}", pc),
+            Trap::BadVector { pc, line } => {
+                write!(f, "no such interrupt line {} at 0x{:04x}", line, pc)
+            }
+            Trap::StrayIret { pc } => {
+                write!(f, "return from interrupt outside a handler at 0x{:04x}", pc)
+            }
+            Trap::MemoryFault { pc, addr } => {
+                write!(f, "bad memory access to 0x{:08x} at 0x{:04x}", addr, pc)
+            }
+            Trap::NotAReference { pc, reg } => {
+                write!(f, "${} is not a reference at 0x{:04x}", reg, pc)
+            }
+            Trap::FieldOutOfRange { pc, field } => {
+                write!(f, "no such field {} at 0x{:04x}", field, pc)
+            }
+            Trap::OutOfMemory { pc, len } => write!(
+                f,
+                "unable to allocate an object of {} fields at 0x{:04x}",
+                len, pc
+            ),
+            Trap::BadObjectKind { pc, kind } => {
+                write!(f, "no such object kind {} at 0x{:04x}", kind, pc)
+            }
+            Trap::StackOverflow { pc } => write!(f, "stack overflow at 0x{:04x}", pc),
+            Trap::StackUnderflow { pc } => write!(f, "pop from empty stack at 0x{:04x}", pc),
+            Trap::TypeError { pc, reg, found } => {
+                write!(f, "unexpected {} in ${} at 0x{:04x}", found, reg, pc)
+            }
+        }
+    }
+}
+
+/// A single record in the VM's execution trace: where an instruction started
+/// and the (raw) opcode byte found there.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct TraceEntry {
+    pub pc: usize,
+    pub byte: u8,
+}
+
+impl TraceEntry {
+    pub fn opcode(&self) -> OpCode {
+        OpCode::from(self.byte)
+    }
+}
+
+/// Why `Vm::run_slice` returned
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Slice {
+    /// the program executed `YIELD`
+    Yielded,
+    /// the program used up its fuel
+    OutOfFuel,
+    /// the program is waiting in `RECV` for a message to arrive
+    Blocked,
+    /// the program asked (with `SPAWN`) for a child process starting at
+    /// `entry`, whose pid should be stored in register `dst`
+    Spawn { entry: usize, dst: usize },
+    /// the program is waiting (with `WAIT`) for process `pid` to stop, and
+    /// its exit value should be stored in register `dst`
+    Wait { pid: u32, dst: usize },
+    /// the program halted, trapped or ran off the end of the code
+    Done,
+}
+
+/// What happens when an arithmetic operation overflows.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Overflow {
+    /// wrap around in two's complement (the default)
+    Wrap,
+    /// stop the machine with `Trap::Overflow`
+    Trap,
+}
+
+#[derive(Clone, Debug)]
+pub struct VmConfig {
+    /// where to write a core file when the machine traps; no core is written
+    /// if this is `None`
+    pub core_dump: Option<PathBuf>,
+    /// how many of the most recently executed instructions to remember
+    pub trace_len: usize,
+    /// whether to collect a `Profile` of executed instructions
+    pub profile: bool,
+    /// how arithmetic overflow is handled
+    pub overflow: Overflow,
+    /// raise the timer interrupt every this many executed instructions; the
+    /// timer is off if this is `None`
+    pub timer: Option<u64>,
+    /// bytes of RAM, mapped from address `0` up
+    pub memory: usize,
+    /// the most values the stack can hold
+    pub stack_size: usize,
+    /// the most object fields the heap can hold at once
+    pub heap_size: usize,
+    /// whether to type check the operands of every instruction (see the
+    /// `value` module)
+    pub typed: bool,
+}
+
+impl Default for VmConfig {
+    fn default() -> Self {
+        Self {
+            core_dump: None,
+            trace_len: 16,
+            profile: false,
+            overflow: Overflow::Wrap,
+            timer: None,
+            memory: 64 * 1024,
+            stack_size: 4096,
+            heap_size: 1 << 20,
+            typed: false,
+        }
+    }
+}
 
 #[derive(Debug)]
 pub struct Vm {
     /// simulated hardware 32 registers
     pub(crate) regs: [i32; 32],
+    /// the type of the value held in each of `regs`
+    pub(crate) tags: [Type; 32],
+    /// a separate bank of 32 floating point registers, only touched by the
+    /// float instructions (`FLOAD`, `FADD`, ...)
+    pub(crate) fregs: [f64; 32],
     /// program counter tracks which byte is being executed
     pc: usize,
-    /// program bytecode being run
-    pub code: Vec<u8>,
+    /// program bytecode being run, which may be shared with other machines
+    code: Arc<[u8]>,
+    /// the pre-decoded form of `code`: decoded up front when verified code is
+    /// loaded, and otherwise on the first run, then kept until the code
+    /// changes
+    decoded: Option<Arc<Decoded>>,
     /// special register holding the result (remainder) for the last division
     /// operation. since register values are already signed, we don't need to
     /// carry sign information on the remainder
     rem: u32,
     /// special register holding the result of the last comparison operation
     cmp: bool,
+    /// flag set when the last arithmetic operation overflowed as a *signed*
+    /// operation, i.e., its true result doesn't fit in an `i32`
+    overflow: bool,
+    /// flag set when the last arithmetic operation carried out of (or, for
+    /// subtraction, borrowed into) the top bit when treated as *unsigned*
+    carry: bool,
+    /// the fatal condition that stopped the machine, if any
+    trap: Option<Trap>,
+    /// ring buffer of the last `config.trace_len` executed instructions
+    trace: VecDeque<TraceEntry>,
+    /// execution counts, if profiling was enabled
+    profile: Option<Profile>,
+    /// interrupt controller state, including the vector table
+    irq: Interrupts,
+    /// main memory, accessed with `LDW` and `STW`
+    mem: Vec<u8>,
+    /// devices mapped over the address space, shadowing `mem`
+    bus: Bus,
+    /// set by `YIELD` and a blocking `RECV` to stop the interpreter loop,
+    /// holding the reason it stopped
+    pause: Option<Slice>,
+    /// messages received but not yet taken by `RECV`, oldest first
+    mailbox: VecDeque<i32>,
+    /// messages sent with `SEND`, as `(pid, value)`, waiting to be delivered
+    /// by the scheduler
+    outbox: Vec<(u32, i32)>,
+    /// values pushed with `PUSH`, top last
+    stack: Vec<Value>,
+    /// objects allocated with `NEW`
+    heap: Heap,
+    config: VmConfig,
+}
+
+/// A verified program, ready to be loaded into any number of machines. The
+/// bytecode and its decoded form are shared between every `Vm` it is loaded
+/// into, so it only needs to be verified once.
+#[derive(Clone, Debug)]
+pub struct Image {
+    code: Arc<[u8]>,
+    decoded: Arc<Decoded>,
+}
+
+impl Image {
+    /// Verifies `code`, returning every violation found if it is rejected
+    pub fn new(code: impl Into<Arc<[u8]>>) -> Result<Self, Vec<Trap>> {
+        let code = code.into();
+        let decoded = verify(&code)?;
+        Ok(Self {
+            code,
+            decoded: Arc::new(decoded),
+        })
+    }
+
+    pub fn code(&self) -> &Arc<[u8]> {
+        &self.code
+    }
+}
+
+/// Builds a `Vm` with memory-mapped devices attached, e.g.,
+///
+/// ```ignore
+/// let vm = Vm::builder()
+///     .config(VmConfig { timer: Some(1000), ..Default::default() })
+///     .device(device::UART_BASE, Uart::stdio())
+///     .build();
+/// ```
+#[derive(Debug, Default)]
+pub struct VmBuilder {
+    config: VmConfig,
+    bus: Bus,
+}
+
+impl VmBuilder {
+    pub fn config(self, config: VmConfig) -> Self {
+        Self { config, ..self }
+    }
+
+    /// Maps `device` into the address space at `base`.
+    ///
+    /// # Panics
+    /// If the device overlaps one that was already added.
+    pub fn device(mut self, base: u32, device: impl Device + 'static) -> Self {
+        self.bus.attach(base, Box::new(device));
+        self
+    }
+
+    pub fn build(self) -> Vm {
+        let mut vm = Vm::with_config(self.config);
+        vm.bus = self.bus;
+        vm
+    }
 }
 
 impl Vm {
     pub fn new() -> Self {
+        Self::with_config(VmConfig::default())
+    }
+
+    pub fn builder() -> VmBuilder {
+        VmBuilder::default()
+    }
+
+    pub fn with_config(config: VmConfig) -> Self {
         Self {
             regs: [0; 32],
+            tags: [if config.typed { Type::Nil } else { Type::Int }; 32],
+            fregs: [0.0; 32],
             pc: 0,
-            code: Default::default(),
+            code: Arc::from(vec![]),
+            decoded: None,
             rem: 0,
             cmp: false,
+            overflow: false,
+            carry: false,
+            trap: None,
+            trace: VecDeque::with_capacity(config.trace_len),
+            profile: if config.profile {
+                Some(Profile::new())
+            } else {
+                None
+            },
+            irq: Interrupts::default(),
+            mem: vec![0; config.memory],
+            bus: Bus::new(),
+            pause: None,
+            mailbox: VecDeque::new(),
+            outbox: vec![],
+            stack: vec![],
+            heap: Heap::new(config.heap_size),
+            config,
+        }
+    }
+
+    pub fn pc(&self) -> usize {
+        self.pc
+    }
+
+    pub fn rem(&self) -> u32 {
+        self.rem
+    }
+
+    pub fn cmp(&self) -> bool {
+        self.cmp
+    }
+
+    pub fn overflow(&self) -> bool {
+        self.overflow
+    }
+
+    pub fn carry(&self) -> bool {
+        self.carry
+    }
+
+    pub fn trap(&self) -> Option<Trap> {
+        self.trap
+    }
+
+    /// The most recently executed instructions, oldest first.
+    pub fn trace(&self) -> impl Iterator<Item = &TraceEntry> {
+        self.trace.iter()
+    }
+
+    pub fn profile(&self) -> Option<&Profile> {
+        self.profile.as_ref()
+    }
+
+    /// Whether register `reg` holds a reference rather than an integer
+    pub fn is_ref(&self, reg: usize) -> bool {
+        self.tags[reg] == Type::Ref
+    }
+
+    /// The value held in register `reg`, tagged with its type
+    pub fn value(&self, reg: usize) -> Value {
+        Value::new(self.regs[reg], self.tags[reg])
+    }
+
+    pub fn set_value(&mut self, reg: usize, val: Value) {
+        self.regs[reg] = val.raw();
+        self.tags[reg] = val.ty();
+    }
+
+    pub fn stack(&self) -> &[Value] {
+        &self.stack
+    }
+
+    pub fn heap(&self) -> &Heap {
+        &self.heap
+    }
+
+    pub fn heap_stats(&self) -> HeapStats {
+        self.heap.stats()
+    }
+
+    /// Runs the garbage collector, freeing every object that isn't reachable
+    /// from a register or the stack, and returns how many were freed.
+    pub fn collect(&mut self) -> usize {
+        let regs = (0..32)
+            .filter(|r| self.is_ref(*r))
+            .map(|r| self.regs[r] as u32);
+        let stack = self.stack.iter().filter_map(|val| match val {
+            Value::Ref(r) => Some(*r),
+            _ => None,
+        });
+        let roots = regs.chain(stack).collect::<Vec<_>>();
+        self.heap.collect(roots)
+    }
+
+    pub fn interrupts(&self) -> &Interrupts {
+        &self.irq
+    }
+
+    /// Raises interrupt line `line`, to be taken between instructions once
+    /// interrupts are enabled. Returns `false`, raising nothing, if there is
+    /// no such line, i.e., `line >= interrupt::VECTORS`.
+    pub fn interrupt(&mut self, line: usize) -> bool {
+        self.irq.raise(line)
+    }
+
+    /// Adds a message to the end of the mailbox, to be taken by `RECV`.
+    pub fn deliver(&mut self, val: i32) {
+        self.mailbox.push_back(val);
+    }
+
+    /// Empties the mailbox, returning the messages that weren't received.
+    pub fn drain_mailbox(&mut self) -> Vec<i32> {
+        self.mailbox.drain(..).collect()
+    }
+
+    /// Takes the messages sent since the last call, as `(pid, value)`.
+    pub fn take_outbox(&mut self) -> Vec<(u32, i32)> {
+        std::mem::take(&mut self.outbox)
+    }
+
+    /// Installs `handler` as the handler for interrupt line `line`, as `VEC`
+    /// does. Returns `false`, installing nothing, if there is no such line,
+    /// i.e., `line >= interrupt::VECTORS`, where `VEC` would trap.
+    pub fn set_vector(&mut self, line: usize, handler: usize) -> bool {
+        match self.irq.vectors.get_mut(line) {
+            Some(vector) => {
+                *vector = Some(handler);
+                true
+            }
+            None => false,
         }
     }
 
+    /// Captures the current machine state, with the code bytes surrounding
+    /// the trapping instruction.
+    pub fn core_dump(&self, trap: Trap) -> CoreDump {
+        CoreDump::capture(self, trap)
+    }
+
     pub fn instructions(&self) -> &[u8] {
-        self.code.as_slice()
+        &self.code
+    }
+
+    pub fn memory(&self) -> &[u8] {
+        &self.mem
+    }
+
+    /// Reads the word at `addr`, from a device if one is mapped there
+    fn read_word(&mut self, addr: u32) -> Option<i32> {
+        if let Some((device, offset)) = self.bus.find(addr) {
+            return Some(device.read(offset));
+        }
+        let at = addr as usize;
+        let bytes = self.mem.get(at..at.checked_add(4)?)?;
+        Some(i32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    /// Writes the word at `addr`, to a device if one is mapped there, and
+    /// returns whether the address was valid
+    fn write_word(&mut self, addr: u32, val: i32) -> bool {
+        if let Some((device, offset)) = self.bus.find(addr) {
+            device.write(offset, val);
+            return true;
+        }
+        let at = addr as usize;
+        match at.checked_add(4).and_then(|end| self.mem.get_mut(at..end)) {
+            Some(bytes) => {
+                bytes.copy_from_slice(&val.to_le_bytes());
+                true
+            }
+            None => false,
+        }
     }
 
     #[inline]
     fn is_done(&self) -> bool {
-        self.pc >= self.code.len()
+        self.trap.is_some() || self.pc >= self.code.len()
     }
 
+    /// Appends a byte to the program. Since the code may be shared, this
+    /// copies it, so it's best kept to building programs up interactively.
     pub fn add_byte(&mut self, byte: u8) {
-        self.code.push(byte);
+        self.decoded = None;
+        let mut code = self.code.to_vec();
+        code.push(byte);
+        self.code = code.into();
+    }
+
+    /// Replaces the program without verifying it first; any problems in the
+    /// bytecode are only caught as they are executed.
+    pub fn set_code(&mut self, code: impl Into<Arc<[u8]>>) {
+        self.decoded = None;
+        self.code = code.into();
+    }
+
+    /// Verifies and loads a program, returning every violation found if the
+    /// bytecode is rejected. Verified code is decoded once here, so `run`
+    /// doesn't need to re-decode and re-check it.
+    pub fn load(&mut self, code: impl Into<Arc<[u8]>>) -> Result<(), Vec<Trap>> {
+        self.load_image(&Image::new(code)?);
+        Ok(())
+    }
+
+    /// Loads an already verified program, sharing its code and decoded form
+    /// rather than copying them.
+    pub fn load_image(&mut self, image: &Image) {
+        self.code = image.code.clone();
+        self.decoded = Some(image.decoded.clone());
     }
 
     /// Execute one instruction, as opposed to running all instructions in the
     /// code
     pub fn tick(&mut self) {
         self.exec_instruction();
+        self.pause = None;
     }
 
+    /// Runs the program until it halts, traps or runs off the end of the
+    /// code. The bytecode is decoded once up front, and the interpreter loop
+    /// then dispatches over the decoded instructions instead of re-decoding
+    /// the raw bytes on every step.
+    ///
+    /// Code that went through `load` was already verified and decoded, so it
+    /// is dispatched on as-is; anything else is decoded (and checked) the
+    /// first time it is run, and kept for later runs.
+    ///
+    /// `YIELD` does nothing here; see `run_slice`. A `RECV` with nothing to
+    /// receive stops the machine *at* the `RECV`, so that it can be run
+    /// again once a message has been delivered.
     pub fn run(&mut self) {
-        let mut done = self.is_done();
-        while !done {
-            // NOTE: we will want to take a look at optimizing this later so
-            // that we don't add *another* call stack to the interpreter's loop
-            done = self.exec_instruction();
+        // with no scheduler to turn to, process requests just fail
+        loop {
+            match self.run_with(u64::MAX, false) {
+                Slice::Spawn { dst, .. } => self.set_value(dst, Value::Int(-1)),
+                Slice::Wait { dst, .. } => self.set_value(dst, Value::Int(i32::MIN)),
+                _ => return,
+            }
+        }
+    }
+
+    /// Runs the program for at most `fuel` instructions, stopping early if
+    /// it yields, blocks, halts, traps or runs off the end of the code. Running it
+    /// again picks up where it left off.
+    pub fn run_slice(&mut self, fuel: u64) -> Slice {
+        self.run_with(fuel, true)
+    }
+
+    fn run_with(&mut self, fuel: u64, yields: bool) -> Slice {
+        let code = &self.code;
+        let decoded = self
+            .decoded
+            .get_or_insert_with(|| Arc::new(Decoded::new(code)))
+            .clone();
+        self.run_decoded(&decoded, fuel, yields)
+    }
+
+    fn run_decoded(&mut self, decoded: &Decoded, mut fuel: u64, yields: bool) -> Slice {
+        if self.is_done() {
+            return Slice::Done;
+        }
+        let mut at = decoded.position(self.pc);
+        if at.is_none() {
+            // we were left mid-instruction (say, by `tick`ing through code
+            // that jumped into the middle of an instruction), so fall back to
+            // decoding one step at a time
+            return self.run_bytes_with(fuel, yields);
+        }
+        while let Some(i) = at {
+            if fuel == 0 {
+                return Slice::OutOfFuel;
+            }
+            fuel -= 1;
+            let instr = match decoded.instrs.get(i) {
+                Some(Ok(instr)) => *instr,
+                Some(Err(trap)) => {
+                    self.fault(*trap);
+                    return Slice::Done;
+                }
+                // ran off the end of the code
+                None => return Slice::Done,
+            };
+            if self.execute(instr) {
+                match self.take_pause() {
+                    Some(Slice::Yielded) if !yields => {}
+                    Some(slice) => return slice,
+                    None => return Slice::Done,
+                }
+            }
+            at = if self.pc == instr.next {
+                Some(i + 1)
+            } else {
+                match decoded.position(self.pc) {
+                    Some(j) => Some(j),
+                    None => {
+                        self.raise(Trap::MisalignedJump {
+                            pc: instr.pc,
+                            dest: self.pc,
+                        });
+                        None
+                    }
+                }
+            };
+        }
+        Slice::Done
+    }
+
+    /// Runs the program by decoding each instruction from the raw bytecode
+    /// as it is reached.
+    pub fn run_bytes(&mut self) {
+        self.run_bytes_with(u64::MAX, false);
+    }
+
+    fn run_bytes_with(&mut self, mut fuel: u64, yields: bool) -> Slice {
+        while !self.is_done() {
+            if fuel == 0 {
+                return Slice::OutOfFuel;
+            }
+            fuel -= 1;
+            if self.exec_instruction() {
+                match self.take_pause() {
+                    Some(Slice::Yielded) if !yields => {}
+                    Some(slice) => return slice,
+                    None => return Slice::Done,
+                }
+            }
         }
+        Slice::Done
+    }
+
+    /// Called when an instruction stopped the interpreter loop: if it was a
+    /// `YIELD` (that didn't also end the program), a blocking `RECV`, or a
+    /// request to the scheduler, clears and returns the reason.
+    fn take_pause(&mut self) -> Option<Slice> {
+        match self.pause.take() {
+            Some(Slice::Yielded) if self.is_done() => None,
+            pause => pause,
+        }
+    }
+
+    /// A new machine running the same code from `entry`, with the same
+    /// configuration but fresh registers, memory and flags, and no devices.
+    /// The new machine doesn't write a core file if it traps, so that it
+    /// can't overwrite the one describing this machine.
+    pub fn fork(&self, entry: usize) -> Vm {
+        let mut vm = Vm::with_config(VmConfig {
+            core_dump: None,
+            ..self.config.clone()
+        });
+        vm.code = self.code.clone();
+        vm.decoded = self.decoded.clone();
+        vm.pc = entry;
+        vm
     }
 
     /// Executes the next instruction and returns whether the program is done
@@ -71,179 +720,735 @@ impl Vm {
                 self.code.len()
             );
             return true;
-        } else {
-            match self.decode_opcode() {
-                OpCode::Halt => {
-                    #[cfg(test)]
-                    println!("encontered instruction: HALT");
+        }
+        match decode_at(&self.code, self.pc) {
+            Ok(instr) => self.execute(instr),
+            Err(trap) => {
+                self.fault(trap);
+                true
+            }
+        }
+    }
+
+    /// Raises a trap for an instruction that failed to decode. As with any
+    /// other instruction, its opcode byte counts as having been fetched.
+    fn fault(&mut self, trap: Trap) {
+        self.record(trap.pc());
+        self.pc = trap.pc() + 1;
+        self.raise(trap);
+    }
+
+    /// Executes a single decoded instruction, then takes a pending interrupt
+    /// if there is one, and returns whether the program is done running or
+    /// not
+    fn execute(&mut self, instr: DecodedInstr) -> bool {
+        let done = self.dispatch(instr);
+        // only the instructions that can produce something other than an
+        // integer tag their destination themselves; anything else leaves an
+        // integer behind, unless it trapped before writing it, or blocked
+        // (a `RECV` with nothing to receive, or a `WAIT`, whose result is
+        // written when it wakes), in which case the register must keep its tag
+        // so that a reference in it stays a root in the meantime
+        let blocked = matches!(self.pause, Some(Slice::Blocked) | Some(Slice::Wait { .. }));
+        if let Some(r) = instr.written() {
+            let tags = matches!(
+                instr.op,
+                OpCode::Mov
+                    | OpCode::Pop
+                    | OpCode::New
+                    | OpCode::GetField
+                    | OpCode::MovCmp
+                    | OpCode::Not
+                    | OpCode::And
+                    | OpCode::Or
+                    | OpCode::Xor
+                    | OpCode::Nil
+                    | OpCode::Peek
+            );
+            if !tags && self.trap.is_none() && !blocked {
+                self.tags[r] = Type::Int;
+            }
+        }
+        if done {
+            return true;
+        }
+        self.irq.step(self.config.timer);
+        match self.irq.take() {
+            Some(handler) => {
+                self.irq.saved = Some(Saved {
+                    pc: self.pc,
+                    cmp: self.cmp,
+                    overflow: self.overflow,
+                    carry: self.carry,
+                });
+                self.irq.enabled = false;
+                self.jump(instr.pc, handler as i64)
+            }
+            None => false,
+        }
+    }
+
+    /// Executes a single decoded instruction and returns whether the program
+    /// is done running or not
+    fn dispatch(&mut self, instr: DecodedInstr) -> bool {
+        let start = instr.pc;
+        self.record(start);
+        if let Some(profile) = &mut self.profile {
+            profile.count(start, instr.op);
+        }
+        // the next 8 bits in line should be an opcode !!
+        self.pc = instr.next;
+        if self.config.typed {
+            if let Some((reg, found)) = self.check_types(&instr) {
+                self.raise(Trap::TypeError {
+                    pc: start,
+                    reg,
+                    found,
+                });
+                return true;
+            }
+        }
+        let [a, b, c] = instr.regs;
+        let (a, b, c) = (a as usize, b as usize, c as usize);
+        match instr.op {
+            OpCode::Halt => {
+                #[cfg(test)]
+                println!("encontered instruction: HALT");
+                return true;
+            }
+            // this never decodes successfully, so we should never get here
+            OpCode::Bad => {
+                self.raise(Trap::IllegalOpcode {
+                    pc: start,
+                    byte: self.code[start],
+                });
+                return true;
+            }
+            OpCode::Load => {
+                // LOAD $REG #VAL
+                // since LOAD takes 2 operands, it has a layout of
+                // 8 bits + 8 bits + 16 bits
+                // ^^^^^^   ^^^^^^   ^^^^^^^
+                // opcode  register  value
+                // since our registers hold i32 values
+                self.regs[a] = instr.imm as u32 as i32;
+            }
+            // arithmetic wraps around on overflow, or traps if the VM was
+            // configured with `Overflow::Trap`; either way the `overflow` and
+            // `carry` flags are updated (see `Vm::arith`)
+            OpCode::Add => {
+                // ADD (val in) R1 with (val in) R2 and store in R3
+                if self.arith(start, c, add(self.regs[a], self.regs[b])) {
+                    return true;
+                }
+            }
+            OpCode::Sub => {
+                if self.arith(start, c, sub(self.regs[a], self.regs[b])) {
+                    return true;
+                }
+            }
+            OpCode::Mul => {
+                if self.arith(start, c, mul(self.regs[a], self.regs[b])) {
+                    return true;
+                }
+            }
+            // since division is not algebraically closed over integers we
+            // could store floats elsewhere, but instead we'll store
+            // *remainders* and keep things integer based.
+            //
+            // recall that for integers `a, b, q, r`, we have `a / b = q +
+            // r` where q is the *quotient* and r is the *remainder*
+            //
+            // so what do? store quotient in register and store remainder
+            // separately in the VM's `rem` field
+            OpCode::Div => {
+                let (r1, r2) = (self.regs[a], self.regs[b]);
+                if r2 == 0 {
+                    self.raise(Trap::DivideByZero { pc: start });
+                    return true;
+                }
+                // integer division; the only way this can overflow is
+                // `i32::MIN / -1`, which wraps back around to `i32::MIN` (with
+                // a remainder of `0`)
+                if self.arith(start, c, div(r1, r2)) {
+                    return true;
+                }
+                self.rem = r1.wrapping_rem(r2) as u32;
+            }
+            // the immediate forms read their operand as a *signed* 16-bit
+            // integer, unlike `LOAD`
+            OpCode::AddI => {
+                if self.arith(start, a, add(self.regs[a], instr.imm as i16 as i32)) {
+                    return true;
+                }
+            }
+            OpCode::SubI => {
+                if self.arith(start, a, sub(self.regs[a], instr.imm as i16 as i32)) {
+                    return true;
+                }
+            }
+            OpCode::MulI => {
+                if self.arith(start, a, mul(self.regs[a], instr.imm as i16 as i32)) {
+                    return true;
+                }
+            }
+            OpCode::CmpI => {
+                self.cmp = self.equal(self.value(a), Value::Int(instr.imm as i16 as i32));
+            }
+            // the bitwise operations double as logical ones on booleans
+            OpCode::And => {
+                let val = self.regs[a] & self.regs[b];
+                self.set_value(c, Value::new(val, self.logical(a, b)));
+            }
+            OpCode::Or => {
+                let val = self.regs[a] | self.regs[b];
+                self.set_value(c, Value::new(val, self.logical(a, b)));
+            }
+            OpCode::Xor => {
+                let val = self.regs[a] ^ self.regs[b];
+                self.set_value(c, Value::new(val, self.logical(a, b)));
+            }
+            OpCode::Not => match self.value(a) {
+                Value::Bool(x) => self.set_value(b, Value::Bool(!x)),
+                _ => self.set_value(b, Value::Int(!self.regs[a])),
+            },
+            // shift amounts are unsigned, and anything past 31 shifts every
+            // bit out of the register (rather than wrapping the amount around
+            // like the hardware we're running on might)
+            OpCode::Shl => {
+                let n = self.regs[b] as u32;
+                self.regs[c] = self.regs[a].checked_shl(n).unwrap_or(0);
+            }
+            OpCode::Shr => {
+                let n = self.regs[b] as u32;
+                self.regs[c] = (self.regs[a] as u32).checked_shr(n).unwrap_or(0) as i32;
+            }
+            OpCode::Sar => {
+                // shifting by 31 already leaves nothing but sign bits
+                let n = (self.regs[b] as u32).min(31);
+                self.regs[c] = self.regs[a] >> n;
+            }
+            // float arithmetic follows IEEE 754, so it never traps and leaves
+            // the overflow and carry flags alone
+            OpCode::FLoad => {
+                self.fregs[a] = instr.imm as i16 as f64;
+            }
+            OpCode::FAdd => {
+                self.fregs[c] = self.fregs[a] + self.fregs[b];
+            }
+            OpCode::FSub => {
+                self.fregs[c] = self.fregs[a] - self.fregs[b];
+            }
+            OpCode::FMul => {
+                self.fregs[c] = self.fregs[a] * self.fregs[b];
+            }
+            OpCode::FDiv => {
+                self.fregs[c] = self.fregs[a] / self.fregs[b];
+            }
+            OpCode::FCmp => {
+                self.cmp = self.fregs[a] == self.fregs[b];
+            }
+            OpCode::FLess => {
+                self.cmp = self.fregs[a] < self.fregs[b];
+            }
+            OpCode::IntToFloat => {
+                self.fregs[b] = self.regs[a] as f64;
+            }
+            // `as` already truncates towards zero, saturates and maps NaN to 0
+            OpCode::FloatToInt => {
+                self.regs[b] = self.fregs[a] as i32;
+            }
+            OpCode::Mov => {
+                self.set_value(b, self.value(a));
+            }
+            OpCode::Inc => {
+                if self.arith(start, a, add(self.regs[a], 1)) {
+                    return true;
+                }
+            }
+            OpCode::Dec => {
+                if self.arith(start, a, sub(self.regs[a], 1)) {
+                    return true;
+                }
+            }
+            OpCode::MovRem => {
+                self.regs[a] = self.rem as i32;
+            }
+            OpCode::MovCmp => {
+                let val = if self.config.typed {
+                    Value::Bool(self.cmp)
+                } else {
+                    Value::Int(self.cmp as i32)
+                };
+                self.set_value(a, val);
+            }
+            OpCode::Jump => {
+                return self.jump(start, self.regs[a] as i64);
+            }
+            // the target is worked out in `i64`, so that no offset can
+            // overflow or wrap around before it is range checked
+            OpCode::JumpR | OpCode::JumpF | OpCode::JumpB => {
+                if let Some(dest) = instr.relative_target(self.regs[a]) {
+                    return self.jump(start, dest);
+                }
+            }
+            // comparisons update the special comparison register to hold the
+            // result; the padding byte following the operands was already
+            // skipped over when decoding
+            OpCode::Eq => {
+                self.cmp = self.equal(self.value(a), self.value(b));
+            }
+            OpCode::NotEq => {
+                self.cmp = !self.equal(self.value(a), self.value(b));
+            }
+            OpCode::Greater => {
+                self.cmp = self.regs[a] > self.regs[b];
+            }
+            OpCode::Less => {
+                self.cmp = self.regs[a] < self.regs[b];
+            }
+            OpCode::GreaterEq => {
+                self.cmp = self.regs[a] >= self.regs[b];
+            }
+            OpCode::LessEq => {
+                self.cmp = self.regs[a] <= self.regs[b];
+            }
+            OpCode::JumpEq => {
+                let dest = self.regs[a];
+                self.profile_branch(start, self.cmp);
+                if self.cmp {
+                    return self.jump(start, dest as i64);
+                }
+            }
+            OpCode::JumpNeq => {
+                let dest = self.regs[a];
+                self.profile_branch(start, !self.cmp);
+                if !self.cmp {
+                    return self.jump(start, dest as i64);
+                }
+            }
+            OpCode::LoadWord => {
+                let addr = self.regs[a] as u32;
+                match self.read_word(addr) {
+                    Some(val) => self.regs[b] = val,
+                    None => {
+                        self.raise(Trap::MemoryFault { pc: start, addr });
+                        return true;
+                    }
+                }
+            }
+            OpCode::StoreWord => {
+                let addr = self.regs[b] as u32;
+                if !self.write_word(addr, self.regs[a]) {
+                    self.raise(Trap::MemoryFault { pc: start, addr });
+                    return true;
+                }
+            }
+            OpCode::SetVector => {
+                let (line, handler) = (instr.imm, self.regs[a] as i64);
+                if line as usize >= VECTORS {
+                    self.raise(Trap::BadVector { pc: start, line });
+                    return true;
+                }
+                if handler < 0 || handler > self.code.len() as i64 {
+                    self.raise(Trap::JumpOutOfRange {
+                        pc: start,
+                        dest: handler,
+                    });
+                    return true;
+                }
+                self.irq.vectors[line as usize] = Some(handler as usize);
+            }
+            OpCode::Yield => {
+                self.pause = Some(Slice::Yielded);
+                return true;
+            }
+            OpCode::Spawn => {
+                let entry = self.regs[a] as i64;
+                if entry < 0 || entry > self.code.len() as i64 {
+                    self.raise(Trap::JumpOutOfRange {
+                        pc: start,
+                        dest: entry,
+                    });
+                    return true;
+                }
+                self.pause = Some(Slice::Spawn {
+                    entry: entry as usize,
+                    dst: b,
+                });
+                return true;
+            }
+            OpCode::Wait => {
+                self.pause = Some(Slice::Wait {
+                    pid: self.regs[a] as u32,
+                    dst: a,
+                });
+                return true;
+            }
+            OpCode::Send => {
+                self.outbox.push((self.regs[a] as u32, self.regs[b]));
+            }
+            OpCode::Recv => match self.mailbox.pop_front() {
+                Some(val) => self.regs[a] = val,
+                None => {
+                    // try again once there's something to receive
+                    self.pc = start;
+                    self.pause = Some(Slice::Blocked);
+                    return true;
+                }
+            },
+            OpCode::Push => {
+                if self.stack.len() >= self.config.stack_size {
+                    self.raise(Trap::StackOverflow { pc: start });
+                    return true;
+                }
+                self.stack.push(self.value(a));
+            }
+            OpCode::Pop => match self.stack.pop() {
+                Some(val) => self.set_value(a, val),
+                None => {
+                    self.raise(Trap::StackUnderflow { pc: start });
+                    return true;
+                }
+            },
+            OpCode::Peek | OpCode::Poke => {
+                let depth = instr.imm as usize;
+                if depth >= self.stack.len() {
+                    self.raise(Trap::StackUnderflow { pc: start });
                     return true;
                 }
-                OpCode::Bad => {
-                    println!("encountered instruction: UNKNOWN");
+                let at = self.stack.len() - 1 - depth;
+                if instr.op == OpCode::Peek {
+                    self.set_value(a, self.stack[at]);
+                } else {
+                    self.stack[at] = self.value(a);
+                }
+            }
+            OpCode::Call => {
+                if self.stack.len() >= self.config.stack_size {
+                    self.raise(Trap::StackOverflow { pc: start });
+                    return true;
+                }
+                self.stack.push(Value::Int(self.pc as i32));
+                return self.jump(start, self.regs[a] as i64);
+            }
+            OpCode::Return => match self.stack.pop() {
+                Some(val) => return self.jump(start, val.raw() as i64),
+                None => {
+                    self.raise(Trap::StackUnderflow { pc: start });
                     return true;
                 }
-                OpCode::Load => {
-                    // LOAD $REG #VAL
-                    // next byte should contain the register we're loading into
-                    let reg = self.next_8_bits() as usize;
-                    // since LOAD takes 2 operands, it has a layout of
-                    // 8 bits + 8 bits + 16 bits
-                    // ^^^^^^   ^^^^^^   ^^^^^^^
-                    // opcode  register  value
-                    let val = self.next_16_bits() as u32;
-                    // since our registers hold i32 values
-                    self.regs[reg] = val as i32;
-                    // the next 8 bits in line should be an opcode !!
-                }
-                OpCode::Add => {
-                    // ADD (val in) R1 with (val in) R2 and store in R3
-                    // get operand (reg) address and read value
-                    let r1 = self.regs[self.next_8_bits() as usize];
-                    // get next operand (reg) address and read value
-                    let r2 = self.regs[self.next_8_bits() as usize];
-                    // get last operand (reg) address and store sum
-                    let r3 = self.next_8_bits() as usize;
-                    self.regs[r3] = r1 + r2;
-                }
-                OpCode::Sub => {
-                    let r1 = self.regs[self.next_8_bits() as usize];
-                    let r2 = self.regs[self.next_8_bits() as usize];
-                    let r3 = self.next_8_bits() as usize;
-                    self.regs[r3] = r1 - r2;
-                }
-                OpCode::Mul => {
-                    let r1 = self.regs[self.next_8_bits() as usize];
-                    let r2 = self.regs[self.next_8_bits() as usize];
-                    let r3 = self.next_8_bits() as usize;
-                    self.regs[r3] = r1 * r2;
-                }
-                // since division is not algebraically closed over integers we
-                // could store floats elsewhere, but instead we'll store
-                // *remainders* and keep things integer based.
-                //
-                // recall that for integers `a, b, q, r`, we have `a / b = q +
-                // r` where q is the *quotient* and r is the *remainder*
-                //
-                // so what do? store quotient in register and store remainder
-                // separately in the VM's `rem` field
-                OpCode::Div => {
-                    let r1 = self.regs[self.next_8_bits() as usize];
-                    let r2 = self.regs[self.next_8_bits() as usize];
-                    let r3 = self.next_8_bits() as usize;
-                    // integer division
-                    self.regs[r3] = r1 / r2;
-                    self.rem = (r1 % r2) as u32;
-                }
-                OpCode::Jump => {
-                    let dest = self.regs[self.next_8_bits() as usize];
-                    self.pc = dest as usize;
-                }
-                OpCode::JumpF => {
-                    let dest = self.regs[self.next_8_bits() as usize];
-                    self.pc += dest as usize;
-                }
-                OpCode::JumpB => {
-                    let dest = self.regs[self.next_8_bits() as usize];
-                    self.pc -= dest as usize;
-                }
-                OpCode::Eq => {
-                    let r1 = self.regs[self.next_8_bits() as usize];
-                    let r2 = self.regs[self.next_8_bits() as usize];
-                    // update the special comparison register to hold the result
-                    self.cmp = r1 == r2;
-                    // then proceed with the next 8 bits?
-                    self.next_8_bits();
-                }
-                OpCode::NotEq => {
-                    let r1 = self.regs[self.next_8_bits() as usize];
-                    let r2 = self.regs[self.next_8_bits() as usize];
-                    // update the special comparison register to hold the result
-                    self.cmp = r1 != r2;
-                    // then proceed with the next 8 bits?
-                    self.next_8_bits();
-                }
-                OpCode::Greater => {
-                    let r1 = self.regs[self.next_8_bits() as usize];
-                    let r2 = self.regs[self.next_8_bits() as usize];
-                    // update the special comparison register to hold the result
-                    self.cmp = r1 > r2;
-                    // then proceed with the next 8 bits?
-                    self.next_8_bits();
-                }
-                OpCode::Less => {
-                    let r1 = self.regs[self.next_8_bits() as usize];
-                    let r2 = self.regs[self.next_8_bits() as usize];
-                    // update the special comparison register to hold the result
-                    self.cmp = r1 < r2;
-                    // then proceed with the next 8 bits?
-                    self.next_8_bits();
-                }
-                OpCode::GreaterEq => {
-                    let [r1, r2] = self.fetch_reg_chunk();
-                    // let r1 = self.regs[self.next_8_bits() as usize];
-                    // let r2 = self.regs[self.next_8_bits() as usize];
-                    // update the special comparison register to hold the result
-                    self.cmp = r1 >= r2;
-                    // then proceed with the next 8 bits?
-                    self.next_8_bits();
-                }
-                OpCode::LessEq => {
-                    let r1 = self.regs[self.next_8_bits() as usize];
-                    let r2 = self.regs[self.next_8_bits() as usize];
-                    // update the special comparison register to hold the result
-                    self.cmp = r1 <= r2;
-                    // then proceed with the next 8 bits?
-                    self.next_8_bits();
-                }
-                OpCode::JumpEq => {
-                    let reg = self.next_8_bits() as usize;
-                    let dest = self.regs[reg];
-                    if self.cmp {
-                        self.pc = dest as usize
+            },
+            OpCode::New => {
+                let kind = match ObjKind::from_tag(instr.imm) {
+                    Some(kind) => kind,
+                    None => {
+                        self.raise(Trap::BadObjectKind {
+                            pc: start,
+                            kind: instr.imm,
+                        });
+                        return true;
+                    }
+                };
+                let len = self.regs[a];
+                // whatever the destination held is about to be overwritten,
+                // so it shouldn't keep anything alive through a collection
+                self.tags[b] = Type::Int;
+                let handle = if len >= 0 {
+                    self.alloc(kind, len as usize)
+                } else {
+                    None
+                };
+                match handle {
+                    Some(handle) => self.set_value(b, Value::Ref(handle)),
+                    None => {
+                        self.raise(Trap::OutOfMemory { pc: start, len });
+                        return true;
                     }
                 }
-                OpCode::JumpNeq => {
-                    let reg = self.next_8_bits() as usize;
-                    let dest = self.regs[reg];
-                    if !self.cmp {
-                        self.pc = dest as usize
+            }
+            OpCode::GetField => match self.field(start, a, b) {
+                Some((handle, i)) => {
+                    let val = self.heap.get(handle).unwrap().fields[i];
+                    self.set_value(c, val);
+                }
+                None => return true,
+            },
+            OpCode::SetField => {
+                let val = self.value(c);
+                match self.field(start, a, b) {
+                    Some((handle, i)) => {
+                        let obj = self.heap.get_mut(handle).unwrap();
+                        obj.fields[i] = match obj.kind {
+                            ObjKind::String => Value::Int(val.raw() as u8 as i32),
+                            ObjKind::Array | ObjKind::Record => val,
+                        };
                     }
+                    None => return true,
                 }
-            };
-        }
+            }
+            OpCode::Len => match self.object(start, a) {
+                Some(handle) => {
+                    self.regs[b] = self.heap.get(handle).unwrap().fields.len() as i32;
+                }
+                None => return true,
+            },
+            OpCode::Nil => {
+                self.set_value(a, Value::Nil);
+            }
+            OpCode::TypeOf => {
+                self.regs[b] = self.tags[a].tag() as i32;
+            }
+            OpCode::EnableInt => {
+                self.irq.enabled = true;
+            }
+            OpCode::DisableInt => {
+                self.irq.enabled = false;
+            }
+            OpCode::IntReturn => match self.irq.saved.take() {
+                Some(saved) => {
+                    self.cmp = saved.cmp;
+                    self.overflow = saved.overflow;
+                    self.carry = saved.carry;
+                    self.irq.enabled = true;
+                    return self.jump(start, saved.pc as i64);
+                }
+                None => {
+                    self.raise(Trap::StrayIret { pc: start });
+                    return true;
+                }
+            },
+            // fused compare-and-branch; the offset is relative to the end of
+            // the instruction, like `JMPR`
+            OpCode::BranchEq
+            | OpCode::BranchNeq
+            | OpCode::BranchLess
+            | OpCode::BranchGreaterEq
+            | OpCode::BranchGreater
+            | OpCode::BranchLessEq => {
+                let (x, y) = (self.regs[a], self.regs[b]);
+                let taken = match instr.op {
+                    OpCode::BranchEq => self.equal(self.value(a), self.value(b)),
+                    OpCode::BranchNeq => !self.equal(self.value(a), self.value(b)),
+                    OpCode::BranchLess => x < y,
+                    OpCode::BranchGreaterEq => x >= y,
+                    OpCode::BranchGreater => x > y,
+                    _ => x <= y,
+                };
+                self.profile_branch(start, taken);
+                if let (true, Some(dest)) = (taken, instr.relative_target(0)) {
+                    return self.jump(start, dest);
+                }
+            }
+        };
         self.pc >= self.code.len()
     }
 
-    fn decode_opcode(&mut self) -> OpCode {
-        let opcode = OpCode::from(self.code[self.pc]);
-        self.pc += 1;
-        opcode
+    /// Stores the result of an arithmetic operation in register `dst` and
+    /// updates the overflow and carry flags. If the operation overflowed and
+    /// the VM is configured to trap on overflow, the machine is stopped
+    /// instead, leaving `dst` untouched. Returns whether the machine trapped.
+    fn arith(
+        &mut self,
+        start: usize,
+        dst: usize,
+        (val, overflow, carry): (i32, bool, bool),
+    ) -> bool {
+        self.overflow = overflow;
+        self.carry = carry;
+        if overflow && self.config.overflow == Overflow::Trap {
+            self.raise(Trap::Overflow { pc: start });
+            return true;
+        }
+        self.regs[dst] = val;
+        false
+    }
+
+    /// Whether two values are equal. Values of different types never are
+    /// when the VM is typed; otherwise only their raw bits are compared.
+    fn equal(&self, x: Value, y: Value) -> bool {
+        x.raw() == y.raw() && (!self.config.typed || x.ty() == y.ty())
+    }
+
+    /// The type of the result of a bitwise operation on registers `a` and `b`
+    fn logical(&self, a: usize, b: usize) -> Type {
+        if self.tags[a] == Type::Bool && self.tags[b] == Type::Bool {
+            Type::Bool
+        } else {
+            Type::Int
+        }
+    }
+
+    /// Checks the types of an instruction's operands, returning the first
+    /// register holding a value of the wrong type, along with that type
+    fn check_types(&self, instr: &DecodedInstr) -> Option<(u8, Type)> {
+        let [a, b, _] = instr.regs;
+        let expect = |reg: u8, ty: Type| match self.tags[reg as usize] {
+            found if found == ty => None,
+            found => Some((reg, found)),
+        };
+        match instr.op {
+            OpCode::Add
+            | OpCode::Sub
+            | OpCode::Mul
+            | OpCode::Div
+            | OpCode::Shl
+            | OpCode::Shr
+            | OpCode::Sar
+            | OpCode::Greater
+            | OpCode::Less
+            | OpCode::GreaterEq
+            | OpCode::LessEq
+            | OpCode::BranchLess
+            | OpCode::BranchGreaterEq
+            | OpCode::BranchGreater
+            | OpCode::BranchLessEq
+            | OpCode::StoreWord
+            | OpCode::Send => expect(a, Type::Int).or_else(|| expect(b, Type::Int)),
+            OpCode::AddI
+            | OpCode::SubI
+            | OpCode::MulI
+            | OpCode::Inc
+            | OpCode::Dec
+            | OpCode::IntToFloat
+            | OpCode::Jump
+            | OpCode::JumpR
+            | OpCode::JumpF
+            | OpCode::JumpB
+            | OpCode::JumpEq
+            | OpCode::JumpNeq
+            | OpCode::LoadWord
+            | OpCode::SetVector
+            | OpCode::Spawn
+            | OpCode::Wait
+            | OpCode::New
+            | OpCode::Call => expect(a, Type::Int),
+            OpCode::GetField | OpCode::SetField => {
+                expect(a, Type::Ref).or_else(|| expect(b, Type::Int))
+            }
+            OpCode::Len => expect(a, Type::Ref),
+            OpCode::And | OpCode::Or | OpCode::Xor => match self.tags[a as usize] {
+                Type::Bool => expect(b, Type::Bool),
+                _ => expect(a, Type::Int).or_else(|| expect(b, Type::Int)),
+            },
+            OpCode::Not => match self.tags[a as usize] {
+                Type::Bool => None,
+                _ => expect(a, Type::Int),
+            },
+            _ => None,
+        }
+    }
+
+    /// Allocates an object, collecting garbage first if the heap is due for
+    /// a collection (or would otherwise be too full to fit it)
+    fn alloc(&mut self, kind: ObjKind, len: usize) -> Option<u32> {
+        if self.heap.wants_collection(len) {
+            self.collect();
+        }
+        self.heap.alloc(kind, len)
+    }
+
+    /// The handle of the object referenced by register `reg`, trapping if it
+    /// doesn't hold a reference
+    fn object(&mut self, start: usize, reg: usize) -> Option<u32> {
+        let handle = self.regs[reg] as u32;
+        if !self.is_ref(reg) || self.heap.get(handle).is_none() {
+            self.raise(Trap::NotAReference {
+                pc: start,
+                reg: reg as u8,
+            });
+            return None;
+        }
+        Some(handle)
+    }
+
+    /// The object referenced by register `obj` and the index of the field
+    /// numbered by register `field`, trapping if either is invalid
+    fn field(&mut self, start: usize, obj: usize, field: usize) -> Option<(u32, usize)> {
+        let handle = self.object(start, obj)?;
+        let i = self.regs[field];
+        if i < 0 || i as usize >= self.heap.get(handle).unwrap().fields.len() {
+            self.raise(Trap::FieldOutOfRange {
+                pc: start,
+                field: i,
+            });
+            return None;
+        }
+        Some((handle, i as usize))
+    }
+
+    /// Moves the program counter to `dest`, trapping if it lies outside of
+    /// the bytecode. Landing exactly on the end of the code is allowed, and
+    /// simply ends the program.
+    fn jump(&mut self, start: usize, dest: i64) -> bool {
+        if dest < 0 || dest > self.code.len() as i64 {
+            self.raise(Trap::JumpOutOfRange { pc: start, dest });
+            return true;
+        }
+        self.pc = dest as usize;
+        self.is_done()
     }
 
-    fn next_8_bits(&mut self) -> u8 {
-        let byte = self.code[self.pc];
-        self.pc += 1;
-        byte
+    /// Stops the machine, writing a core file if one was configured.
+    fn raise(&mut self, trap: Trap) {
+        self.trap = Some(trap);
+        if let Some(path) = &self.config.core_dump {
+            if let Err(e) = self.core_dump(trap).save(path) {
+                eprintln!("unable to write core file `{}`: {}", path.display(), e);
+            }
+        }
     }
 
-    /// Returns an array of values held by the registers addressed in the next
-    /// `N` chunks of bytecode.
-    fn fetch_reg_chunk<const N: usize>(&mut self) -> [i32; N] {
-        let mut chunk = [0; N];
-        for i in 0..N {
-            chunk[i] = self.regs[self.next_8_bits() as usize];
+    fn profile_branch(&mut self, pc: usize, taken: bool) {
+        if let Some(profile) = &mut self.profile {
+            profile.branch(pc, taken);
         }
-        chunk
     }
 
-    fn next_16_bits(&mut self) -> u16 {
-        let dword = ((self.code[self.pc] as u16) << 8) | self.code[self.pc + 1] as u16;
-        // increment twice, since the pc increments *bytes*
-        self.pc += 2;
-        dword
+    /// Pushes the instruction starting at `pc` onto the trace, evicting the
+    /// oldest entry if the trace is full.
+    fn record(&mut self, pc: usize) {
+        if self.config.trace_len == 0 {
+            return;
+        }
+        if self.trace.len() == self.config.trace_len {
+            self.trace.pop_front();
+        }
+        self.trace.push_back(TraceEntry {
+            pc,
+            byte: self.code[pc],
+        });
     }
 }
 
+/// The wrapped result of `x + y`, whether it overflowed, and whether it carried
+fn add(x: i32, y: i32) -> (i32, bool, bool) {
+    let (val, overflow) = x.overflowing_add(y);
+    (val, overflow, (x as u32).overflowing_add(y as u32).1)
+}
+
+/// The wrapped result of `x - y`, whether it overflowed, and whether it
+/// borrowed
+fn sub(x: i32, y: i32) -> (i32, bool, bool) {
+    let (val, overflow) = x.overflowing_sub(y);
+    (val, overflow, (x as u32) < (y as u32))
+}
+
+/// The wrapped result of `x * y` and whether it overflowed. For
+/// multiplication, the carry flag is set whenever the full (unsigned) product
+/// didn't fit in 32 bits.
+fn mul(x: i32, y: i32) -> (i32, bool, bool) {
+    let (val, overflow) = x.overflowing_mul(y);
+    (val, overflow, (x as u32).overflowing_mul(y as u32).1)
+}
+
+/// The wrapped quotient of `x / y` and whether it overflowed; division never
+/// carries. `y` must not be zero.
+fn div(x: i32, y: i32) -> (i32, bool, bool) {
+    let (val, overflow) = x.overflowing_div(y);
+    (val, overflow, false)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -272,7 +1477,7 @@ mod tests {
     fn test_opcode_halt() {
         let mut vm = Vm::new();
         let code = vec![0, 0, 0, 0];
-        vm.code = code;
+        vm.code = code.into();
         vm.run();
         assert_eq!(vm.pc, 1)
     }
@@ -281,43 +1486,887 @@ mod tests {
     fn test_opcode_bad() {
         let mut vm = Vm::new();
         let code = vec![200, 0, 0, 0];
-        vm.code = code;
+        vm.code = code.into();
         vm.run();
-        assert_eq!(vm.pc, 1)
+        assert_eq!(vm.pc, 1);
+        assert_eq!(vm.trap, Some(Trap::IllegalOpcode { pc: 0, byte: 200 }))
     }
 
     #[test]
-    fn test_opcode_load() {
+    fn test_div_by_zero_traps() {
         let mut vm = Vm::new();
-        // represent 500 using LE u8
-        vm.code = vec![0, 0, 1, 244];
-        vm.exec_instruction();
-        assert_eq!(vm.regs[0], 500)
+        vm.regs[0] = 10;
+        vm.code = vec![OpCode::Div as u8, 0, 1, 2, OpCode::Halt as u8].into();
+        vm.run();
+        assert_eq!(vm.trap, Some(Trap::DivideByZero { pc: 0 }));
+        assert_eq!(vm.regs[2], 0)
     }
 
     #[test]
-    fn test_opcode_add() {
+    fn test_jump_out_of_range_traps() {
         let mut vm = Vm::new();
-        // represent 500 using BE u8, so 500 = [1, 244]
-        vm.code = vec![
-            OpCode::Load as u8,
-            0,
-            1,
-            244, // load 500 into $0
-            OpCode::Load as u8,
-            1,
-            1,
+        vm.regs[0] = 100;
+        vm.code = vec![OpCode::Jump as u8, 0, 0, 0].into();
+        vm.run();
+        assert_eq!(vm.trap, Some(Trap::JumpOutOfRange { pc: 0, dest: 100 }));
+        // backward jumps past the start of the code trap instead of wrapping
+        let mut vm = Vm::new();
+        vm.regs[0] = 8;
+        vm.code = vec![OpCode::JumpB as u8, 0, 0, 0].into();
+        vm.run();
+        assert_eq!(vm.trap, Some(Trap::JumpOutOfRange { pc: 0, dest: -6 }));
+        // as do offsets that would overflow if added up in 32 bits
+        let mut vm = Vm::new();
+        vm.regs[0] = i32::MIN;
+        vm.code = vec![OpCode::JumpB as u8, 0].into();
+        vm.run();
+        assert_eq!(
+            vm.trap,
+            Some(Trap::JumpOutOfRange {
+                pc: 0,
+                dest: 2 + (1 << 31)
+            })
+        )
+    }
+
+    #[test]
+    fn test_opcode_jmpr() {
+        let mut vm = Vm::new();
+        vm.regs[0] = -4;
+        vm.regs[1] = 2;
+        vm.regs[2] = 1;
+        vm.code = vec![
+            OpCode::JumpR as u8,
+            1, // jmpr $1, over the next `jmpr`
+            OpCode::JumpR as u8,
+            0, // jmpr $0, back to the start
+            OpCode::Inc as u8,
+            2, // inc $2
+            OpCode::BranchEq as u8,
+            2,
+            1,
+            255,
+            247, // beq $2 $1 #-9, to the `jmpr $0`
+        ]
+        .into();
+        for _ in 0..3 {
+            vm.tick();
+        }
+        assert_eq!(vm.pc, 2);
+        vm.tick();
+        assert_eq!(vm.pc, 0);
+        // `jmpf` is the same as `jmpr`, and takes negative offsets too
+        let mut vm = Vm::new();
+        vm.regs[0] = -4;
+        vm.code = vec![OpCode::Halt as u8, 0, OpCode::JumpF as u8, 0].into();
+        vm.pc = 2;
+        vm.tick();
+        assert_eq!(vm.pc, 0)
+    }
+
+    #[test]
+    fn test_trace_keeps_last_entries() {
+        let mut vm = Vm::with_config(VmConfig {
+            trace_len: 2,
+            ..Default::default()
+        });
+        vm.code = vec![
+            OpCode::Load as u8,
+            0,
+            0,
+            1,
+            OpCode::Load as u8,
+            1,
+            0,
+            2,
+            OpCode::Add as u8,
+            0,
+            1,
+            2,
+        ]
+        .into();
+        vm.run();
+        let pcs = vm.trace().map(|e| e.pc).collect::<Vec<_>>();
+        assert_eq!(pcs, vec![4, 8])
+    }
+
+    #[test]
+    fn test_misaligned_jump_traps() {
+        let mut vm = Vm::new();
+        vm.regs[0] = 1;
+        vm.code = vec![OpCode::Jump as u8, 0, OpCode::Halt as u8].into();
+        vm.run();
+        assert_eq!(vm.trap, Some(Trap::MisalignedJump { pc: 0, dest: 1 }))
+    }
+
+    #[test]
+    fn test_bad_register_traps() {
+        let mut vm = Vm::new();
+        vm.code = vec![OpCode::Add as u8, 0, 1, 40].into();
+        vm.run();
+        assert_eq!(vm.trap, Some(Trap::BadRegister { pc: 0, reg: 40 }));
+        let mut vm = Vm::new();
+        vm.code = vec![OpCode::Add as u8, 0, 1, 40].into();
+        vm.run_bytes();
+        assert_eq!(vm.trap, Some(Trap::BadRegister { pc: 0, reg: 40 }))
+    }
+
+    /// Counts `$0` down from `n` to zero, adding `$0` into `$4` each time
+    /// around the loop.
+    fn sum_loop(n: u16) -> Vec<u8> {
+        let [hi, lo] = n.to_be_bytes();
+        vec![
+            OpCode::Load as u8,
+            0,
+            hi,
+            lo, // load n into $0
+            OpCode::Load as u8,
+            1,
+            0,
+            1, // load 1 into $1
+            OpCode::Load as u8,
+            3,
+            0,
+            16, // load the loop start (16) into $3
+            OpCode::Load as u8,
+            4,
+            0,
+            0, // load 0 into $4
+            OpCode::Add as u8,
+            4,
+            0,
+            4, // add $4 $0 $4
+            OpCode::Sub as u8,
+            0,
+            1,
+            0, // sub $0 $1 $0
+            OpCode::Eq as u8,
+            0,
+            2,
+            0, // eq $0 $2
+            OpCode::JumpNeq as u8,
+            3, // jmpne $3
+            OpCode::Halt as u8,
+        ]
+    }
+
+    #[test]
+    fn test_predecoded_matches_bytes() {
+        let mut decoded = Vm::new();
+        decoded.code = sum_loop(100).into();
+        decoded.run();
+        let mut bytes = Vm::new();
+        bytes.code = sum_loop(100).into();
+        bytes.run_bytes();
+        assert_eq!(decoded.regs[4], 5050);
+        assert_eq!(decoded.regs, bytes.regs);
+        assert_eq!(decoded.pc, bytes.pc);
+        assert_eq!(decoded.trace, bytes.trace);
+    }
+
+    #[test]
+    fn test_opcode_load() {
+        let mut vm = Vm::new();
+        // represent 500 using LE u8
+        vm.code = vec![0, 0, 1, 244].into();
+        vm.exec_instruction();
+        assert_eq!(vm.regs[0], 500)
+    }
+
+    #[test]
+    fn test_opcode_add() {
+        let mut vm = Vm::new();
+        // represent 500 using BE u8, so 500 = [1, 244]
+        vm.code = vec![
+            OpCode::Load as u8,
+            0,
+            1,
+            244, // load 500 into $0
+            OpCode::Load as u8,
+            1,
+            1,
             244, // load 500 into $1
             OpCode::Add as u8,
             0,
             1,
             2, // add $0 $1 $2
-        ];
+        ]
+        .into();
         vm.run();
         println!("{:?}", &vm);
         assert_eq!(vm.regs[2], 1000)
     }
 
+    #[test]
+    fn test_opcode_immediates() {
+        let mut vm = Vm::new();
+        vm.code = vec![
+            OpCode::Load as u8,
+            0,
+            0,
+            10, // load 10 into $0
+            OpCode::AddI as u8,
+            0,
+            0,
+            5, // addi $0 #5
+            OpCode::SubI as u8,
+            0,
+            255,
+            254, // subi $0 #-2
+            OpCode::MulI as u8,
+            0,
+            255,
+            253, // muli $0 #-3
+            OpCode::CmpI as u8,
+            0,
+            255,
+            205, // cmpi $0 #-51
+        ]
+        .into();
+        vm.run();
+        assert_eq!(vm.regs[0], -51);
+        assert!(vm.cmp)
+    }
+
+    #[test]
+    fn test_opcode_branches() {
+        let mut vm = Vm::new();
+        vm.regs[1] = 5;
+        vm.code = vec![
+            OpCode::Inc as u8,
+            0, // inc $0
+            OpCode::AddI as u8,
+            2,
+            0,
+            3, // addi $2 #3
+            OpCode::BranchLess as u8,
+            0,
+            1,
+            255,
+            245, // blt $0 $1 #-11, back to the `inc`
+            OpCode::BranchEq as u8,
+            0,
+            1,
+            0,
+            1, // beq $0 $1 #1, over the `halt`
+            OpCode::Halt as u8,
+            OpCode::Load as u8,
+            3,
+            0,
+            1, // load 1 into $3
+        ]
+        .into();
+        vm.run();
+        assert_eq!(vm.regs[0], 5);
+        assert_eq!(vm.regs[2], 15);
+        assert_eq!(vm.regs[3], 1);
+        assert!(!vm.cmp);
+        assert!(vm.trap.is_none())
+    }
+
+    #[test]
+    fn test_branch_out_of_range() {
+        let mut vm = Vm::new();
+        vm.code = vec![OpCode::BranchEq as u8, 0, 0, 255, 250].into();
+        vm.run();
+        assert_eq!(vm.trap, Some(Trap::JumpOutOfRange { pc: 0, dest: -1 }))
+    }
+
+    #[test]
+    fn test_run_slice() {
+        let code = vec![
+            OpCode::Inc as u8,
+            0,
+            OpCode::Yield as u8,
+            OpCode::Inc as u8,
+            0,
+            OpCode::Inc as u8,
+            0,
+        ];
+        let mut vm = Vm::new();
+        vm.set_code(code.clone());
+        assert_eq!(vm.run_slice(5), Slice::Yielded);
+        // the code is only decoded on the first slice
+        let decoded = vm.decoded.clone().unwrap();
+        assert_eq!(vm.run_slice(1), Slice::OutOfFuel);
+        assert!(Arc::ptr_eq(&decoded, vm.decoded.as_ref().unwrap()));
+        assert_eq!(vm.regs[0], 2);
+        assert_eq!(vm.run_slice(5), Slice::Done);
+        assert_eq!(vm.run_slice(5), Slice::Done);
+        // `run` carries on through yields
+        let mut vm = Vm::new();
+        vm.code = code.into();
+        vm.run();
+        assert_eq!(vm.regs[0], 3)
+    }
+
+    #[test]
+    fn test_send_recv() {
+        let mut vm = Vm::new();
+        vm.regs[0] = 7;
+        vm.regs[1] = 42;
+        vm.code = vec![
+            OpCode::Send as u8,
+            0,
+            1,
+            0, // send $0 $1
+            OpCode::Recv as u8,
+            2, // recv $2
+            OpCode::Recv as u8,
+            3, // recv $3
+        ]
+        .into();
+        vm.deliver(5);
+        assert_eq!(vm.run_slice(10), Slice::Blocked);
+        assert_eq!(vm.regs[2], 5);
+        assert_eq!(vm.pc, 6);
+        assert_eq!(vm.take_outbox(), vec![(7, 42)]);
+        vm.deliver(-1);
+        assert_eq!(vm.run_slice(10), Slice::Done);
+        assert_eq!(vm.regs[3], -1)
+    }
+
+    #[test]
+    fn test_blocked_recv_keeps_ref() {
+        let mut vm = Vm::new();
+        vm.regs[0] = 2;
+        vm.code = vec![
+            OpCode::New as u8,
+            0,
+            1,
+            0,
+            0, // new $0 $1 #0
+            OpCode::Recv as u8,
+            1, // recv $1
+        ]
+        .into();
+        assert_eq!(vm.run_slice(10), Slice::Blocked);
+        // nothing was received, so $1 still holds the object
+        assert!(vm.is_ref(1));
+        assert_eq!(vm.collect(), 0);
+        vm.deliver(3);
+        assert_eq!(vm.run_slice(10), Slice::Done);
+        assert!(!vm.is_ref(1));
+        assert_eq!(vm.regs[1], 3);
+    }
+
+    #[test]
+    fn test_spawn_unscheduled() {
+        let mut vm = Vm::new();
+        vm.regs[0] = 4;
+        vm.code = vec![
+            OpCode::Spawn as u8,
+            0,
+            1,
+            0, // spawn $0 $1
+            OpCode::Wait as u8,
+            0, // wait $0
+        ]
+        .into();
+        assert_eq!(vm.run_slice(10), Slice::Spawn { entry: 4, dst: 1 });
+        assert_eq!(vm.run_slice(10), Slice::Wait { pid: 4, dst: 0 });
+        let mut vm = vm.fork(0);
+        vm.regs[0] = 4;
+        vm.set_value(1, Value::Nil);
+        vm.run();
+        assert_eq!(vm.value(1), Value::Int(-1));
+        assert_eq!(vm.regs[0], i32::MIN)
+    }
+
+    #[test]
+    fn test_memory_words() {
+        let mut vm = Vm::new();
+        vm.regs[0] = -2;
+        vm.regs[1] = 0x100;
+        vm.code = vec![
+            OpCode::StoreWord as u8,
+            0,
+            1,
+            0, // stw $0 $1
+            OpCode::LoadWord as u8,
+            1,
+            2,
+            0, // ldw $1 $2
+        ]
+        .into();
+        vm.run();
+        assert_eq!(vm.regs[2], -2);
+        assert_eq!(&vm.memory()[0x100..0x104], &[0xfe, 0xff, 0xff, 0xff]);
+        // the last word of memory is fine, but it can't run off the end
+        let mut vm = Vm::new();
+        vm.regs[1] = 64 * 1024 - 4;
+        vm.regs[2] = 64 * 1024 - 3;
+        vm.code = vec![
+            OpCode::LoadWord as u8,
+            1,
+            0,
+            0,
+            OpCode::LoadWord as u8,
+            2,
+            0,
+            0,
+        ]
+        .into();
+        vm.run();
+        assert_eq!(
+            vm.trap,
+            Some(Trap::MemoryFault {
+                pc: 4,
+                addr: 64 * 1024 - 3
+            })
+        )
+    }
+
+    #[test]
+    fn test_memory_mapped_devices() {
+        use crate::device::{Rng, RNG_BASE};
+        let mut vm = Vm::builder().device(RNG_BASE, Rng::new(3)).build();
+        vm.regs[0] = RNG_BASE as i32;
+        vm.regs[1] = 3;
+        vm.code = vec![
+            OpCode::LoadWord as u8,
+            0,
+            2,
+            0, // ldw $0 $2
+            OpCode::StoreWord as u8,
+            1,
+            0,
+            0, // stw $1 $0, reseeding the rng
+            OpCode::LoadWord as u8,
+            0,
+            3,
+            0, // ldw $0 $3
+        ]
+        .into();
+        vm.run();
+        assert_eq!(vm.regs[2], Rng::new(3).read(0));
+        assert_eq!(vm.regs[2], vm.regs[3]);
+        // device registers shadow RAM
+        assert_eq!(&vm.memory()[RNG_BASE as usize..][..4], &[0; 4])
+    }
+
+    /// counts up in `$0` forever, while the timer handler counts its
+    /// interrupts in `$1`
+    fn timer_program() -> Vec<u8> {
+        vec![
+            OpCode::Load as u8,
+            2,
+            0,
+            14, // load the handler address into $2
+            OpCode::SetVector as u8,
+            2,
+            0,
+            0, // vec $2 #0
+            OpCode::EnableInt as u8,
+            OpCode::Inc as u8,
+            0, // inc $0
+            OpCode::JumpB as u8,
+            3, // jmpb $3, back to the `inc`
+            OpCode::Halt as u8,
+            OpCode::Inc as u8,
+            1, // inc $1, the handler
+            OpCode::Eq as u8,
+            1,
+            4,
+            0, // eq $1 $4
+            OpCode::IntReturn as u8,
+        ]
+    }
+
+    #[test]
+    fn test_timer_interrupts() {
+        let mut vm = Vm::with_config(VmConfig {
+            timer: Some(10),
+            ..Default::default()
+        });
+        vm.regs[3] = 4;
+        vm.regs[4] = 5;
+        vm.code = timer_program().into();
+        for _ in 0..100 {
+            vm.tick();
+        }
+        assert!(vm.trap.is_none());
+        // the timer fires after every tenth instruction, counting those run
+        // by the handler; the last interrupt was only just taken
+        assert_eq!(vm.regs[1], 9);
+        assert_eq!(vm.pc, 14);
+        assert!(vm.regs[0] > 30);
+        // the handler's comparison (`$1 == $4` on the fifth interrupt) was
+        // undone by `IRET`
+        assert!(!vm.cmp);
+        assert!(vm.interrupts().saved.is_some())
+    }
+
+    #[test]
+    fn test_interrupts_disabled() {
+        let mut vm = Vm::with_config(VmConfig {
+            timer: Some(1),
+            ..Default::default()
+        });
+        vm.code = vec![
+            OpCode::DisableInt as u8,
+            OpCode::Load as u8,
+            0,
+            0,
+            7,
+            OpCode::Halt as u8,
+        ]
+        .into();
+        vm.set_vector(0, 5);
+        vm.run();
+        assert_eq!(vm.regs[0], 7);
+        assert!(vm.interrupts().is_pending(0));
+        // the host can raise interrupts too
+        let mut vm = Vm::new();
+        vm.code = vec![
+            OpCode::EnableInt as u8,
+            OpCode::Halt as u8,
+            OpCode::DisableInt as u8,
+        ]
+        .into();
+        assert!(vm.set_vector(2, 2));
+        assert!(vm.interrupt(2));
+        // lines that don't exist are refused rather than panicking
+        assert!(!vm.set_vector(VECTORS, 2));
+        assert!(!vm.interrupt(64));
+        vm.tick();
+        assert_eq!(vm.pc, 2)
+    }
+
+    #[test]
+    fn test_interrupt_traps() {
+        let mut vm = Vm::new();
+        vm.code = vec![OpCode::IntReturn as u8].into();
+        vm.run();
+        assert_eq!(vm.trap, Some(Trap::StrayIret { pc: 0 }));
+        let mut vm = Vm::new();
+        vm.code = vec![OpCode::SetVector as u8, 0, 0, 8].into();
+        vm.run();
+        assert_eq!(vm.trap, Some(Trap::BadVector { pc: 0, line: 8 }))
+    }
+
+    #[test]
+    fn test_opcode_moves() {
+        let mut vm = Vm::new();
+        vm.regs[0] = 17;
+        vm.regs[1] = 5;
+        vm.code = vec![
+            OpCode::Div as u8,
+            0,
+            1,
+            2, // div $0 $1 $2
+            OpCode::MovRem as u8,
+            3, // movrem $3
+            OpCode::Mov as u8,
+            2,
+            4,
+            0, // mov $2 $4
+            OpCode::Inc as u8,
+            4, // inc $4
+            OpCode::Dec as u8,
+            3, // dec $3
+            OpCode::Eq as u8,
+            3,
+            4,
+            0, // eq $3 $4
+            OpCode::MovCmp as u8,
+            5, // movcmp $5
+        ]
+        .into();
+        vm.run();
+        assert_eq!(vm.regs[2], 3);
+        assert_eq!(vm.regs[3], 1);
+        assert_eq!(vm.regs[4], 4);
+        assert_eq!(vm.regs[5], 0);
+        assert!(vm.trap.is_none())
+    }
+
+    #[test]
+    fn test_inc_overflow() {
+        let mut vm = Vm::with_config(VmConfig {
+            overflow: Overflow::Trap,
+            ..Default::default()
+        });
+        vm.regs[0] = i32::MIN;
+        vm.regs[1] = i32::MAX;
+        vm.code = vec![OpCode::Dec as u8, 1, OpCode::Dec as u8, 0].into();
+        vm.run();
+        assert_eq!(vm.regs[1], i32::MAX - 1);
+        assert_eq!(vm.regs[0], i32::MIN);
+        assert_eq!(vm.trap, Some(Trap::Overflow { pc: 2 }))
+    }
+
+    #[test]
+    fn test_overflow_wraps_by_default() {
+        let mut vm = Vm::new();
+        vm.regs[0] = i32::MAX;
+        vm.regs[1] = 1;
+        vm.code = vec![OpCode::Add as u8, 0, 1, 2].into();
+        vm.run();
+        assert_eq!(vm.regs[2], i32::MIN);
+        assert!(vm.overflow);
+        assert!(!vm.carry);
+        assert!(vm.trap.is_none())
+    }
+
+    #[test]
+    fn test_carry_flag() {
+        let mut vm = Vm::new();
+        vm.regs[0] = -1;
+        vm.regs[1] = 1;
+        vm.code = vec![
+            OpCode::Add as u8,
+            0,
+            1,
+            2, // add $0 $1 $2
+        ]
+        .into();
+        vm.run();
+        // 0xffffffff + 1 carries, but -1 + 1 doesn't overflow
+        assert_eq!(vm.regs[2], 0);
+        assert!(vm.carry);
+        assert!(!vm.overflow);
+
+        let mut vm = Vm::new();
+        vm.regs[1] = 1;
+        vm.code = vec![
+            OpCode::Sub as u8,
+            0,
+            1,
+            2, // sub $0 $1 $2
+        ]
+        .into();
+        vm.run();
+        // 0 - 1 borrows
+        assert_eq!(vm.regs[2], -1);
+        assert!(vm.carry);
+        assert!(!vm.overflow)
+    }
+
+    #[test]
+    fn test_overflow_traps_when_checked() {
+        let mut vm = Vm::with_config(VmConfig {
+            overflow: Overflow::Trap,
+            ..Default::default()
+        });
+        vm.regs[0] = i32::MAX;
+        vm.code = vec![
+            OpCode::AddI as u8,
+            1,
+            0,
+            7, // addi $1 #7, which is fine
+            OpCode::MulI as u8,
+            0,
+            0,
+            2, // muli $0 #2, which overflows
+        ]
+        .into();
+        vm.run();
+        assert_eq!(vm.regs[1], 7);
+        assert_eq!(vm.regs[0], i32::MAX);
+        assert_eq!(vm.trap, Some(Trap::Overflow { pc: 4 }))
+    }
+
+    #[test]
+    fn test_div_min_by_minus_one() {
+        let code = vec![OpCode::Div as u8, 0, 1, 2];
+        let mut vm = Vm::new();
+        vm.regs[0] = i32::MIN;
+        vm.regs[1] = -1;
+        vm.code = code.clone().into();
+        vm.run();
+        assert_eq!(vm.regs[2], i32::MIN);
+        assert_eq!(vm.rem, 0);
+        assert!(vm.overflow);
+
+        let mut vm = Vm::with_config(VmConfig {
+            overflow: Overflow::Trap,
+            ..Default::default()
+        });
+        vm.regs[0] = i32::MIN;
+        vm.regs[1] = -1;
+        vm.code = code.into();
+        vm.run();
+        assert_eq!(vm.trap, Some(Trap::Overflow { pc: 0 }))
+    }
+
+    #[test]
+    fn test_opcode_floats() {
+        let mut vm = Vm::new();
+        vm.regs[0] = 7;
+        vm.code = vec![
+            OpCode::FLoad as u8,
+            0,
+            0,
+            1, // fload %f0 #1
+            OpCode::IntToFloat as u8,
+            0,
+            1,
+            0, // itof $0 %f1
+            OpCode::FDiv as u8,
+            0,
+            1,
+            2, // fdiv %f0 %f1 %f2
+            OpCode::FMul as u8,
+            2,
+            1,
+            3, // fmul %f2 %f1 %f3
+            OpCode::FSub as u8,
+            1,
+            0,
+            4, // fsub %f1 %f0 %f4
+            OpCode::FloatToInt as u8,
+            4,
+            1,
+            0, // ftoi %f4 $1
+            OpCode::FLess as u8,
+            2,
+            0,
+            0, // flt %f2 %f0
+        ]
+        .into();
+        vm.run();
+        assert_eq!(vm.fregs[2], 1.0 / 7.0);
+        assert_eq!(vm.fregs[3], 1.0 / 7.0 * 7.0);
+        assert_eq!(vm.regs[1], 6);
+        assert!(vm.cmp)
+    }
+
+    #[test]
+    fn test_float_edge_cases() {
+        let mut vm = Vm::new();
+        vm.fregs[0] = 1.0;
+        vm.fregs[2] = -1e20;
+        vm.code = vec![
+            OpCode::FDiv as u8,
+            0,
+            1,
+            3, // fdiv %f0 %f1 %f3, i.e., 1 / 0
+            OpCode::FloatToInt as u8,
+            2,
+            0,
+            0, // ftoi %f2 $0
+            OpCode::FSub as u8,
+            3,
+            3,
+            4, // fsub %f3 %f3 %f4, i.e., inf - inf
+            OpCode::FloatToInt as u8,
+            4,
+            1,
+            0, // ftoi %f4 $1
+            OpCode::FCmp as u8,
+            4,
+            4,
+            0, // fcmp %f4 %f4
+        ]
+        .into();
+        vm.run();
+        assert!(vm.trap.is_none());
+        assert_eq!(vm.fregs[3], f64::INFINITY);
+        assert!(vm.fregs[4].is_nan());
+        assert_eq!(vm.regs[0], i32::MIN);
+        assert_eq!(vm.regs[1], 0);
+        assert!(!vm.cmp)
+    }
+
+    #[test]
+    fn test_opcode_bitwise() {
+        let mut vm = Vm::new();
+        vm.code = vec![
+            OpCode::Load as u8,
+            0,
+            0b1100,
+            0b1010, // load 0x0c0a into $0
+            OpCode::Load as u8,
+            1,
+            0b1010,
+            0b0110, // load 0x0a06 into $1
+            OpCode::And as u8,
+            0,
+            1,
+            2, // and $0 $1 $2
+            OpCode::Or as u8,
+            0,
+            1,
+            3, // or $0 $1 $3
+            OpCode::Xor as u8,
+            0,
+            1,
+            4, // xor $0 $1 $4
+            OpCode::Not as u8,
+            0,
+            5,
+            0, // not $0 $5
+        ]
+        .into();
+        vm.run();
+        assert_eq!(vm.regs[2], 0x0802);
+        assert_eq!(vm.regs[3], 0x0e0e);
+        assert_eq!(vm.regs[4], 0x060c);
+        assert_eq!(vm.regs[5], !0x0c0a)
+    }
+
+    #[test]
+    fn test_opcode_shifts() {
+        let mut vm = Vm::new();
+        vm.regs[0] = -16;
+        vm.code = vec![
+            OpCode::Load as u8,
+            1,
+            0,
+            2, // load 2 into $1
+            OpCode::Shl as u8,
+            0,
+            1,
+            2, // shl $0 $1 $2
+            OpCode::Shr as u8,
+            0,
+            1,
+            3, // shr $0 $1 $3
+            OpCode::Sar as u8,
+            0,
+            1,
+            4, // sar $0 $1 $4
+        ]
+        .into();
+        vm.run();
+        assert_eq!(vm.regs[2], -64);
+        assert_eq!(vm.regs[3], (-16i32 as u32 >> 2) as i32);
+        assert_eq!(vm.regs[4], -4)
+    }
+
+    #[test]
+    fn test_opcode_shifts_past_width() {
+        for amount in [32, 33, 1000, -1] {
+            let mut vm = Vm::new();
+            vm.regs[0] = -16;
+            vm.regs[1] = amount;
+            vm.regs[5] = 16;
+            vm.code = vec![
+                OpCode::Shl as u8,
+                0,
+                1,
+                2, // shl $0 $1 $2
+                OpCode::Shr as u8,
+                0,
+                1,
+                3, // shr $0 $1 $3
+                OpCode::Sar as u8,
+                0,
+                1,
+                4, // sar $0 $1 $4
+                OpCode::Sar as u8,
+                5,
+                1,
+                6, // sar $5 $1 $6
+            ]
+            .into();
+            vm.run();
+            assert_eq!(vm.regs[2..=4], [0, 0, -1], "shifting by {}", amount);
+            assert_eq!(vm.regs[6], 0, "shifting by {}", amount)
+        }
+    }
+
     #[test]
     fn test_opcode_mul() {
         let mut vm = Vm::new();
@@ -334,7 +2383,8 @@ mod tests {
             0,
             1,
             2,
-        ];
+        ]
+        .into();
         vm.run();
         println!("{}", vm.regs[2]);
     }
@@ -345,7 +2395,7 @@ mod tests {
         // manually store `1` in r0, so that when we jump to it the program
         // counter is set to this value
         vm.regs[0] = 1;
-        vm.code = vec![OpCode::Jump as u8, 0, 0, 0];
+        vm.code = vec![OpCode::Jump as u8, 0, 0, 0].into();
         vm.tick();
         assert_eq!(vm.pc, 1)
     }
@@ -355,7 +2405,7 @@ mod tests {
         let mut vm = Vm::new();
         vm.regs[0] = 2;
         // uwu i think this would cause an infinite loop
-        vm.code = vec![OpCode::JumpF as u8, 0, 0, 0, OpCode::Jump as u8, 0, 0, 0];
+        vm.code = vec![OpCode::JumpF as u8, 0, 0, 0, OpCode::Jump as u8, 0, 0, 0].into();
         vm.tick();
         assert_eq!(vm.pc, 4)
     }
@@ -366,7 +2416,7 @@ mod tests {
         // let's set the values of 2 registers equal
         vm.regs[0] = 10;
         vm.regs[1] = 10;
-        vm.code = vec![OpCode::Eq as u8, 0, 1, 0, OpCode::Eq as u8, 0, 1, 0];
+        vm.code = vec![OpCode::Eq as u8, 0, 1, 0, OpCode::Eq as u8, 0, 1, 0].into();
         vm.tick();
         // 10 == 10
         assert!(vm.cmp);
@@ -382,9 +2432,287 @@ mod tests {
         let mut vm = Vm::new();
         vm.regs[0] = 7;
         vm.cmp = true;
-        vm.code = vec![OpCode::JumpEq as u8, 0, 0, 0, 17, 0, 0, 0, 17, 0, 0, 0];
+        vm.code = vec![OpCode::JumpEq as u8, 0, 0, 0, 17, 0, 0, 0, 17, 0, 0, 0].into();
         vm.tick();
         assert_eq!(vm.pc, 7);
         println!("{:?}", &vm)
     }
+
+    #[test]
+    fn test_heap_objects() {
+        let mut vm = Vm::new();
+        vm.regs[0] = 3;
+        vm.regs[2] = 1;
+        vm.regs[3] = 99;
+        vm.code = vec![
+            OpCode::New as u8,
+            0,
+            1,
+            0,
+            0, // new $0 $1 #0
+            OpCode::SetField as u8,
+            1,
+            2,
+            3, // setf $1 $2 $3
+            OpCode::GetField as u8,
+            1,
+            2,
+            4, // getf $1 $2 $4
+            OpCode::Len as u8,
+            1,
+            5,
+            0, // len $1 $5
+            OpCode::Mov as u8,
+            1,
+            6,
+            0, // mov $1 $6
+            OpCode::Inc as u8,
+            1, // inc $1
+        ]
+        .into();
+        vm.run();
+        assert_eq!(vm.trap(), None);
+        assert_eq!((vm.regs[4], vm.regs[5]), (99, 3));
+        assert!(!vm.is_ref(4) && !vm.is_ref(5));
+        // `MOV` copies the reference, while arithmetic leaves an integer
+        assert!(vm.is_ref(6));
+        assert!(!vm.is_ref(1));
+        assert_eq!(vm.collect(), 0);
+        vm.tags = [Type::Int; 32];
+        assert_eq!(vm.collect(), 1);
+        let stats = vm.heap_stats();
+        assert_eq!((stats.objects, stats.freed, stats.collections), (0, 1, 2));
+    }
+
+    #[test]
+    fn test_gc_roots() {
+        let mut vm = Vm::new();
+        vm.regs[0] = 2;
+        vm.code = vec![
+            OpCode::New as u8,
+            0,
+            1,
+            0,
+            0, // new $0 $1 #0
+            OpCode::Push as u8,
+            1, // push $1
+            OpCode::New as u8,
+            0,
+            1,
+            0,
+            2, // new $0 $1 #2
+            OpCode::New as u8,
+            0,
+            2,
+            0,
+            2, // new $0 $2 #2
+            OpCode::SetField as u8,
+            2,
+            3,
+            1, // setf $2 $3 $1
+            OpCode::New as u8,
+            0,
+            1,
+            0,
+            1, // new $0 $1 #1
+        ]
+        .into();
+        vm.run();
+        assert_eq!(vm.heap_stats().objects, 4);
+        // the first object is only on the stack, the second only in a field
+        // of the third, and the last is held by $1
+        assert_eq!(vm.collect(), 0);
+        vm.tags[2] = Type::Int;
+        assert_eq!(vm.collect(), 2);
+        assert_eq!(vm.stack(), &[Value::Ref(0)]);
+        vm.set_code(vec![OpCode::Pop as u8, 7, OpCode::Pop as u8, 7]);
+        vm.pc = 0;
+        vm.run();
+        assert!(vm.is_ref(7));
+        assert_eq!(vm.regs[7], 0);
+        assert_eq!(vm.trap(), Some(Trap::StackUnderflow { pc: 2 }));
+    }
+
+    #[test]
+    fn test_gc_on_allocation() {
+        let mut vm = Vm::with_config(VmConfig {
+            heap_size: 8,
+            ..Default::default()
+        });
+        vm.regs[0] = 5;
+        // allocating 5 fields at a time only fits once the previous object
+        // has been collected
+        let new = [OpCode::New as u8, 0, 1, 0, 1];
+        vm.code = new.repeat(10).into();
+        vm.run();
+        assert_eq!(vm.trap(), None);
+        let stats = vm.heap_stats();
+        assert_eq!(
+            (stats.objects, stats.allocated, stats.collections),
+            (1, 10, 9)
+        );
+        // unless something else is holding on to it
+        vm.set_code([&[OpCode::Mov as u8, 1, 2, 0][..], &new].concat());
+        vm.pc = 0;
+        vm.run();
+        assert_eq!(vm.trap(), Some(Trap::OutOfMemory { pc: 4, len: 5 }));
+    }
+
+    #[test]
+    fn test_heap_traps() {
+        let run = |regs: &[(usize, i32)], code: Vec<u8>| {
+            let mut vm = Vm::with_config(VmConfig {
+                stack_size: 1,
+                ..Default::default()
+            });
+            for (r, val) in regs {
+                vm.regs[*r] = *val;
+            }
+            vm.code = code.into();
+            vm.run();
+            vm.trap()
+        };
+        let new = vec![OpCode::New as u8, 0, 1, 0, 0];
+        let getf = |field| [&new[..], &[OpCode::GetField as u8, 1, field, 3]].concat();
+        assert_eq!(
+            run(&[(0, 2), (2, 2)], getf(2)),
+            Some(Trap::FieldOutOfRange { pc: 5, field: 2 })
+        );
+        assert_eq!(
+            run(&[(0, 2), (2, -1)], getf(2)),
+            Some(Trap::FieldOutOfRange { pc: 5, field: -1 })
+        );
+        // $0 holds an integer
+        assert_eq!(
+            run(&[], vec![OpCode::Len as u8, 0, 1, 0]),
+            Some(Trap::NotAReference { pc: 0, reg: 0 })
+        );
+        assert_eq!(
+            run(&[(0, -1)], new.clone()),
+            Some(Trap::OutOfMemory { pc: 0, len: -1 })
+        );
+        assert_eq!(
+            run(&[], vec![OpCode::New as u8, 0, 1, 0, 3]),
+            Some(Trap::BadObjectKind { pc: 0, kind: 3 })
+        );
+        assert_eq!(
+            run(&[], vec![OpCode::Push as u8, 0, OpCode::Push as u8, 0]),
+            Some(Trap::StackOverflow { pc: 2 })
+        );
+    }
+
+    #[test]
+    fn test_string_fields_hold_bytes() {
+        let mut vm = Vm::new();
+        vm.regs[0] = 1;
+        vm.regs[3] = 0x1234;
+        vm.code = vec![
+            OpCode::New as u8,
+            0,
+            1,
+            0,
+            1, // new $0 $1 #1
+            OpCode::SetField as u8,
+            1,
+            2,
+            3, // setf $1 $2 $3
+            OpCode::GetField as u8,
+            1,
+            2,
+            4, // getf $1 $2 $4
+        ]
+        .into();
+        vm.run();
+        assert_eq!(vm.regs[4], 0x34);
+    }
+
+    #[test]
+    fn test_typed_values() {
+        let mut vm = Vm::with_config(VmConfig {
+            typed: true,
+            ..Default::default()
+        });
+        vm.code = vec![
+            OpCode::Load as u8,
+            0,
+            0,
+            1, // load $0 #1
+            OpCode::Eq as u8,
+            0,
+            0,
+            0, // eq $0 $0
+            OpCode::MovCmp as u8,
+            1, // movcmp $1
+            OpCode::Not as u8,
+            1,
+            2,
+            0, // not $1 $2
+            OpCode::Eq as u8,
+            0,
+            1,
+            0, // eq $0 $1
+            OpCode::TypeOf as u8,
+            1,
+            3,
+            0, // typeof $1 $3
+            OpCode::TypeOf as u8,
+            4,
+            5,
+            0, // typeof $4 $5
+            OpCode::Add as u8,
+            0,
+            1,
+            6, // add $0 $1 $6
+        ]
+        .into();
+        vm.run();
+        assert_eq!(vm.value(1), Value::Bool(true));
+        assert_eq!(vm.value(2), Value::Bool(false));
+        // `1` and `true` have the same bits, but not the same type
+        assert!(!vm.cmp());
+        assert_eq!((vm.regs[3], vm.regs[5]), (2, 0));
+        assert_eq!(vm.value(4), Value::Nil);
+        assert_eq!(
+            vm.trap(),
+            Some(Trap::TypeError {
+                pc: 26,
+                reg: 1,
+                found: Type::Bool
+            })
+        );
+        assert_eq!(vm.value(6), Value::Nil);
+    }
+
+    #[test]
+    fn test_untyped_ignores_types() {
+        let code = vec![
+            OpCode::Nil as u8,
+            0, // nil $0
+            OpCode::Inc as u8,
+            0, // inc $0
+            OpCode::GetField as u8,
+            0,
+            1,
+            2, // getf $0 $1 $2
+        ];
+        let mut vm = Vm::new();
+        vm.code = code.clone().into();
+        vm.run();
+        assert_eq!(vm.value(0), Value::Int(1));
+        assert_eq!(vm.trap(), Some(Trap::NotAReference { pc: 4, reg: 0 }));
+        let mut vm = Vm::with_config(VmConfig {
+            typed: true,
+            ..Default::default()
+        });
+        vm.code = code.into();
+        vm.run();
+        assert_eq!(
+            vm.trap(),
+            Some(Trap::TypeError {
+                pc: 2,
+                reg: 0,
+                found: Type::Nil
+            })
+        );
+    }
 }